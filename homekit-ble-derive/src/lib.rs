@@ -0,0 +1,247 @@
+//! Derive macros for encoding structs as TLV8 data
+//!
+//! The generated code implements the `TlvEncode` and `TlvDecode` traits
+//! from `homekit_ble::tlv`, see the documentation there for the supported
+//! attributes.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Error, Expr, Field, Fields, GenericParam,
+    Generics, Ident, Lifetime, LifetimeParam, Type,
+};
+
+#[proc_macro_derive(TlvEncode, attributes(tlv))]
+pub fn derive_tlv_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand_encode(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(TlvDecode, attributes(tlv))]
+pub fn derive_tlv_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand_decode(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// How a field is encoded
+enum FieldKind {
+    /// A single item, which has to be present
+    Required(Expr),
+
+    /// A single item, which can be missing
+    Optional(Expr),
+
+    /// A separated list of groups
+    List,
+}
+
+struct TlvField<'f> {
+    ident: &'f Ident,
+
+    kind: FieldKind,
+}
+
+fn parse_fields(input: &DeriveInput) -> Result<Vec<TlvField<'_>>, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "TLV encoding is only supported for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "TLV encoding is only supported for structs",
+            ))
+        }
+    };
+
+    fields.iter().map(parse_field).collect()
+}
+
+fn parse_field(field: &Field) -> Result<TlvField<'_>, Error> {
+    let mut tlv_type = None;
+    let mut list = false;
+
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("tlv"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("type") {
+                tlv_type = Some(meta.value()?.parse::<Expr>()?);
+                Ok(())
+            } else if meta.path.is_ident("list") {
+                list = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `type = ...` or `list`"))
+            }
+        })?;
+    }
+
+    // Unwrap is safe, only named fields are supported
+    let ident = field.ident.as_ref().unwrap();
+
+    let kind = match (tlv_type, list) {
+        (None, true) => FieldKind::List,
+        (Some(tlv_type), false) if is_option(&field.ty) => FieldKind::Optional(tlv_type),
+        (Some(tlv_type), false) => FieldKind::Required(tlv_type),
+        (Some(_), true) => {
+            return Err(Error::new_spanned(
+                ident,
+                "a list field can't have a TLV type",
+            ))
+        }
+        (None, false) => {
+            return Err(Error::new_spanned(
+                ident,
+                "missing TLV type, use `#[tlv(type = ...)]`",
+            ))
+        }
+    };
+
+    Ok(TlvField { ident, kind })
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
+
+fn expand_encode(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = parse_fields(input)?;
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let encode_fields = fields.iter().map(|field| {
+        let ident = field.ident;
+
+        match &field.kind {
+            FieldKind::Required(tlv_type) => quote! {
+                writer.push_tlv(::homekit_ble::tlv::Tlv::new(
+                    #tlv_type,
+                    ::homekit_ble::tlv::ToTlvValue::to_tlv_value(&self.#ident),
+                ))?;
+            },
+            FieldKind::Optional(tlv_type) => quote! {
+                if let ::core::option::Option::Some(value) = &self.#ident {
+                    writer.push_tlv(::homekit_ble::tlv::Tlv::new(
+                        #tlv_type,
+                        ::homekit_ble::tlv::ToTlvValue::to_tlv_value(value),
+                    ))?;
+                }
+            },
+            FieldKind::List => quote! {
+                self.#ident.encode(writer)?;
+            },
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::homekit_ble::tlv::TlvEncode for #name #type_generics #where_clause {
+            fn encode<W: ::homekit_ble::tlv::TlvWrite>(
+                &self,
+                writer: &mut W,
+            ) -> ::core::result::Result<(), ::homekit_ble::Error> {
+                #(#encode_fields)*
+
+                ::core::result::Result::Ok(())
+            }
+        }
+    })
+}
+
+fn expand_decode(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = parse_fields(input)?;
+
+    let name = &input.ident;
+    let (_, type_generics, where_clause) = input.generics.split_for_impl();
+
+    // Borrowed fields are decoded with the lifetime of the struct,
+    // structs without a lifetime get a new one for the decoder.
+    let (lifetime, generics) = match input.generics.lifetimes().next() {
+        Some(param) => (param.lifetime.clone(), input.generics.clone()),
+        None => with_lifetime(&input.generics),
+    };
+
+    let (impl_generics, _, _) = generics.split_for_impl();
+
+    // Items of the other fields end up in the first group of a list
+    let field_types: Vec<&Expr> = fields
+        .iter()
+        .filter_map(|field| match &field.kind {
+            FieldKind::Required(tlv_type) | FieldKind::Optional(tlv_type) => Some(tlv_type),
+            FieldKind::List => None,
+        })
+        .collect();
+
+    let decode_fields = fields.iter().map(|field| {
+        let ident = field.ident;
+
+        match &field.kind {
+            FieldKind::Required(tlv_type) => quote! {
+                #ident: ::homekit_ble::tlv::FromTlvItem::from_tlv_item(reader.get(#tlv_type)?)?,
+            },
+            FieldKind::Optional(tlv_type) => quote! {
+                #ident: match reader.find(#tlv_type)? {
+                    ::core::option::Option::Some(item) => ::core::option::Option::Some(
+                        ::homekit_ble::tlv::FromTlvItem::from_tlv_item(item)?,
+                    ),
+                    ::core::option::Option::None => ::core::option::Option::None,
+                },
+            },
+            FieldKind::List => quote! {
+                #ident: ::homekit_ble::tlv::TlvList::Encoded {
+                    reader,
+                    is_field: |tlv_type: u8| {
+                        false #(|| tlv_type == ::core::convert::Into::<u8>::into(#field_types))*
+                    },
+                },
+            },
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::homekit_ble::tlv::TlvDecode<#lifetime> for #name #type_generics #where_clause {
+            fn decode(
+                reader: ::homekit_ble::tlv::TlvReader<#lifetime>,
+            ) -> ::core::result::Result<Self, ::homekit_ble::Error> {
+                ::core::result::Result::Ok(#name {
+                    #(#decode_fields)*
+                })
+            }
+        }
+    })
+}
+
+fn with_lifetime(generics: &Generics) -> (Lifetime, Generics) {
+    let lifetime: Lifetime = parse_quote!('__tlv);
+
+    let mut generics = generics.clone();
+    generics.params.insert(
+        0,
+        GenericParam::Lifetime(LifetimeParam::new(lifetime.clone())),
+    );
+
+    (lifetime, generics)
+}
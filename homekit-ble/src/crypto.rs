@@ -0,0 +1,118 @@
+//! Cryptographic primitives shared by pairing and secure sessions
+//!
+//! HAP derives all keys using HKDF-SHA-512, and encrypts data with
+//! ChaCha20-Poly1305. The 96-bit nonce consists of four zero bytes,
+//! followed by a 64-bit value.
+
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha512;
+
+use crate::Error;
+
+/// Length of the keys used for encryption
+pub const KEY_LEN: usize = 32;
+
+/// Length of the authentication tag appended to encrypted data
+pub const TAG_LEN: usize = 16;
+
+/// Derive a 32 byte key using HKDF-SHA-512.
+pub(crate) fn derive_key(input_key: &[u8], salt: &[u8], info: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+
+    // Unwrap is safe, the length is valid for SHA-512
+    Hkdf::<Sha512>::new(Some(salt), input_key)
+        .expand(info, &mut key)
+        .unwrap();
+
+    key
+}
+
+fn nonce(value: &[u8; 8]) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(value);
+
+    nonce
+}
+
+/// Encrypt the first `len` bytes of the buffer in place, and append the tag.
+///
+/// Returns the length of the encrypted data including the tag.
+pub(crate) fn encrypt(
+    key: &[u8; KEY_LEN],
+    nonce_value: &[u8; 8],
+    aad: &[u8],
+    buffer: &mut [u8],
+    len: usize,
+) -> Result<usize, Error> {
+    if buffer.len() < len + TAG_LEN {
+        return Err(Error::InsufficientBuffer);
+    }
+
+    let (data, rest) = buffer.split_at_mut(len);
+
+    let tag = ChaCha20Poly1305::new(key.into())
+        .encrypt_in_place_detached(&nonce(nonce_value), aad, data)
+        .map_err(|_| Error::InsufficientBuffer)?;
+
+    rest[..TAG_LEN].copy_from_slice(&tag);
+
+    Ok(len + TAG_LEN)
+}
+
+/// Decrypt data in place, which is followed by the tag.
+///
+/// Returns the decrypted data, or `Error::Authentication` if the tag is invalid.
+pub(crate) fn decrypt<'b>(
+    key: &[u8; KEY_LEN],
+    nonce_value: &[u8; 8],
+    aad: &[u8],
+    buffer: &'b mut [u8],
+) -> Result<&'b [u8], Error> {
+    if buffer.len() < TAG_LEN {
+        return Err(Error::Authentication);
+    }
+
+    let (data, tag) = buffer.split_at_mut(buffer.len() - TAG_LEN);
+
+    ChaCha20Poly1305::new(key.into())
+        .decrypt_in_place_detached(&nonce(nonce_value), aad, data, Tag::from_slice(tag))
+        .map_err(|_| Error::Authentication)?;
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let key = derive_key(b"input", b"salt", b"info");
+
+        let mut buffer = [0u8; 32];
+        buffer[..5].copy_from_slice(b"hello");
+
+        let len = encrypt(&key, b"PS-Msg05", &[], &mut buffer, 5).unwrap();
+        assert_eq!(len, 5 + TAG_LEN);
+        assert_ne!(&buffer[..5], b"hello");
+
+        assert_eq!(
+            decrypt(&key, b"PS-Msg05", &[], &mut buffer[..len]).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn decrypt_with_wrong_nonce() {
+        let key = derive_key(b"input", b"salt", b"info");
+
+        let mut buffer = [0u8; 32];
+        let len = encrypt(&key, b"PS-Msg05", &[], &mut buffer, 5).unwrap();
+
+        assert!(matches!(
+            decrypt(&key, b"PS-Msg06", &[], &mut buffer[..len]),
+            Err(Error::Authentication)
+        ));
+    }
+}
@@ -0,0 +1,610 @@
+//! Support for fragmented HAP PDUs
+//!
+//! PDUs which do not fit into a single GATT write are split into
+//! a first fragment, followed by any number of continuation fragments
+//! (see section 7.3.3.5 of the HAP specification). A continuation fragment
+//! only consists of the control field, the TID and the next part of the body.
+
+use crate::{
+    parse_control_field, Error, Fragmented, HapRequest, IidSize, PduType,
+    CONTROL_FIELD_CONTINUATION,
+};
+
+/// Length of the request header in the first fragment, excluding the instance ID.
+/// It consists of Control Field, Opcode and TID.
+const REQUEST_HEADER_LEN: usize = 3;
+
+/// Length of the response header in the first fragment.
+/// It consists of Control Field, TID and Status.
+const RESPONSE_HEADER_LEN: usize = 3;
+
+/// Length of a continuation fragment header, consisting of Control Field and TID.
+const CONTINUATION_HEADER_LEN: usize = 2;
+
+/// Length of the body length field
+const BODY_LEN_LEN: usize = 2;
+
+/// Smallest fragment length which can be used for any response,
+/// the first fragment has to contain the header and one byte of the body.
+pub const MIN_RESPONSE_FRAGMENT_LEN: usize = RESPONSE_HEADER_LEN + BODY_LEN_LEN + 1;
+
+/// Maximum length of the header of a first fragment, which is
+/// a request with 64-bit instance ID and a body.
+const MAX_HEADER_LEN: usize = REQUEST_HEADER_LEN + 8 + BODY_LEN_LEN;
+
+/// Reassembles fragmented request PDUs.
+///
+/// Fragments are copied into the buffer supplied when creating the
+/// reassembler, so the buffer has to be large enough to hold the
+/// complete, unfragmented PDU.
+pub struct RequestReassembler<'b> {
+    buffer: &'b mut [u8],
+
+    pending: Option<PendingRequest>,
+}
+
+/// State of a request for which not all fragments have been received yet.
+#[derive(Debug)]
+struct PendingRequest {
+    tid: u8,
+
+    iid_size: IidSize,
+
+    /// Number of bytes of the PDU stored in the buffer
+    received: usize,
+
+    /// Total length of the PDU, including the header
+    total_len: usize,
+}
+
+impl<'b> RequestReassembler<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> Self {
+        RequestReassembler {
+            buffer,
+            pending: None,
+        }
+    }
+
+    /// Check if a request is partially received.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Discard a partially received request.
+    pub fn reset(&mut self) {
+        self.pending = None;
+    }
+
+    /// Add a fragment to the reassembler.
+    ///
+    /// Returns the complete request once the declared body length has been
+    /// received, or `None` if more fragments are required. A first fragment
+    /// always starts a new request, discarding any partially received one.
+    pub fn push(&mut self, fragment: &[u8]) -> Result<Option<HapRequest<'_>>, Error> {
+        let control_field = *fragment.first().ok_or(Error::BadLength)?;
+
+        let (fragmented, iid_size, pdu_type) = parse_control_field(control_field)?;
+
+        if pdu_type != PduType::Request {
+            self.pending = None;
+            return Err(Error::UnsupportedPduType((control_field & 0b1110) >> 1));
+        }
+
+        let result = match fragmented {
+            Fragmented::First => self.push_first(fragment, iid_size),
+            Fragmented::Continuation => self.push_continuation(fragment),
+        };
+
+        match result {
+            Ok(true) => {
+                // Unwrap is safe, a complete request always has pending state
+                let pending = self.pending.take().unwrap();
+
+                HapRequest::parse_after_control(
+                    &self.buffer[1..pending.total_len],
+                    pending.iid_size,
+                )
+                .map(Some)
+            }
+            Ok(false) => Ok(None),
+            Err(e) => {
+                self.pending = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Handle the first fragment of a request.
+    ///
+    /// Returns `true` if the request is complete.
+    fn push_first(&mut self, fragment: &[u8], iid_size: IidSize) -> Result<bool, Error> {
+        self.pending = None;
+
+        let header_len = REQUEST_HEADER_LEN + iid_size.num_bytes();
+
+        if fragment.len() < header_len {
+            return Err(Error::BadLength);
+        }
+
+        // The body length is optional, a request without it is never fragmented.
+        let total_len = if fragment.len() >= header_len + BODY_LEN_LEN {
+            let body_len =
+                u16::from_le_bytes([fragment[header_len], fragment[header_len + 1]]) as usize;
+
+            header_len + BODY_LEN_LEN + body_len
+        } else {
+            fragment.len()
+        };
+
+        if total_len > self.buffer.len() {
+            return Err(Error::InsufficientBuffer);
+        }
+
+        // Additional bytes after the body are ignored, as in `HapRequest::parse`
+        let fragment = &fragment[..fragment.len().min(total_len)];
+
+        self.buffer[..fragment.len()].copy_from_slice(fragment);
+
+        self.pending = Some(PendingRequest {
+            tid: fragment[2],
+            iid_size,
+            received: fragment.len(),
+            total_len,
+        });
+
+        Ok(fragment.len() == total_len)
+    }
+
+    /// Handle a continuation fragment of a request.
+    ///
+    /// Returns `true` if the request is complete.
+    fn push_continuation(&mut self, fragment: &[u8]) -> Result<bool, Error> {
+        let pending = self.pending.as_mut().ok_or(Error::UnexpectedContinuation)?;
+
+        if fragment.len() < CONTINUATION_HEADER_LEN {
+            return Err(Error::BadLength);
+        }
+
+        let tid = fragment[1];
+
+        if tid != pending.tid {
+            return Err(Error::TidMismatch {
+                expected: pending.tid,
+                received: tid,
+            });
+        }
+
+        let body = &fragment[CONTINUATION_HEADER_LEN..];
+
+        if pending.received + body.len() > pending.total_len {
+            return Err(Error::BadLength);
+        }
+
+        self.buffer[pending.received..pending.received + body.len()].copy_from_slice(body);
+
+        pending.received += body.len();
+
+        Ok(pending.received == pending.total_len)
+    }
+}
+
+/// A single fragment of a PDU.
+///
+/// The fragment borrows the body of the PDU, and only
+/// stores the header itself.
+#[derive(Debug)]
+pub struct Fragment<'a> {
+    header: [u8; MAX_HEADER_LEN],
+
+    header_len: usize,
+
+    body: &'a [u8],
+}
+
+impl Fragment<'_> {
+    /// Length of the fragment in bytes
+    pub fn size(&self) -> usize {
+        self.header_len + self.body.len()
+    }
+
+    /// Check if this is the first fragment of a PDU
+    pub fn is_first(&self) -> bool {
+        self.header[0] & CONTROL_FIELD_CONTINUATION == 0
+    }
+
+    /// Write the fragment into a buffer, returning the number of bytes written.
+    pub fn write_into(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        if self.size() > buffer.len() {
+            return Err(Error::InsufficientBuffer);
+        }
+
+        buffer[..self.header_len].copy_from_slice(&self.header[..self.header_len]);
+        buffer[self.header_len..self.size()].copy_from_slice(self.body);
+
+        Ok(self.size())
+    }
+}
+
+/// Iterator over the fragments of a request or response PDU.
+///
+/// The first fragment contains the complete header and the
+/// length of the whole body, all following fragments are
+/// continuation fragments which only repeat the TID.
+#[derive(Debug, Clone)]
+pub struct Fragments<'a> {
+    /// Header of the first fragment, including the body length
+    header: [u8; MAX_HEADER_LEN],
+
+    header_len: usize,
+
+    tid: u8,
+
+    body: &'a [u8],
+
+    max_fragment_len: usize,
+
+    /// Offset into the body of the next fragment,
+    /// `None` if the first fragment has not been returned yet.
+    offset: Option<usize>,
+}
+
+impl<'a> Fragments<'a> {
+    /// Create the fragments for a PDU.
+    ///
+    /// The header must not contain the body length, it is added
+    /// automatically if the body is not empty.
+    pub(crate) fn new(
+        header: &[u8],
+        tid: u8,
+        body: &'a [u8],
+        max_fragment_len: usize,
+    ) -> Result<Self, Error> {
+        // Data longer than u16 MAX is not supported by the
+        // protocol
+        if body.len() > (u16::MAX as usize) {
+            return Err(Error::BodyTooLarge);
+        }
+
+        let mut first_header = [0u8; MAX_HEADER_LEN];
+
+        first_header[..header.len()].copy_from_slice(header);
+
+        // The body is optional
+        let header_len = if body.is_empty() {
+            header.len()
+        } else {
+            first_header[header.len()..header.len() + BODY_LEN_LEN]
+                .copy_from_slice(&(body.len() as u16).to_le_bytes());
+
+            header.len() + BODY_LEN_LEN
+        };
+
+        // The first fragment has to contain at least one byte of the body
+        let min_fragment_len = if body.is_empty() {
+            header_len
+        } else {
+            header_len + 1
+        };
+
+        if max_fragment_len < min_fragment_len {
+            return Err(Error::InsufficientBuffer);
+        }
+
+        Ok(Fragments {
+            header: first_header,
+            header_len,
+            tid,
+            body,
+            max_fragment_len,
+            offset: None,
+        })
+    }
+}
+
+impl<'a> Iterator for Fragments<'a> {
+    type Item = Fragment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut header = [0u8; MAX_HEADER_LEN];
+
+        let (header_len, offset) = match self.offset {
+            None => {
+                header = self.header;
+
+                (self.header_len, 0)
+            }
+            Some(offset) if offset < self.body.len() => {
+                header[0] = self.header[0] | CONTROL_FIELD_CONTINUATION;
+                header[1] = self.tid;
+
+                (CONTINUATION_HEADER_LEN, offset)
+            }
+            Some(_) => return None,
+        };
+
+        let body_len = (self.max_fragment_len - header_len).min(self.body.len() - offset);
+
+        self.offset = Some(offset + body_len);
+
+        Some(Fragment {
+            header,
+            header_len,
+            body: &self.body[offset..offset + body_len],
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{HapResponse, HapStatus, InstanceId, OpCode};
+    use proptest::{collection::vec, prelude::*};
+
+    #[test]
+    fn unfragmented_request() {
+        let mut buffer = [0u8; 32];
+        let mut reassembler = RequestReassembler::new(&mut buffer);
+
+        let request = reassembler
+            .push(&[0, 6, 1, 0x10, 0])
+            .unwrap()
+            .expect("Request should be complete");
+
+        assert_eq!(request.op_code, OpCode::ServiceSignatureRead);
+        assert_eq!(request.tid, 1);
+        assert_eq!(request.char_id, InstanceId::new(0x10));
+    }
+
+    #[test]
+    fn fragmented_request() {
+        let mut buffer = [0u8; 32];
+        let mut reassembler = RequestReassembler::new(&mut buffer);
+
+        // Characteristic write with a 6 byte body, split over three fragments
+        assert!(reassembler
+            .push(&[0, 2, 0x42, 0x22, 0, 6, 0, 1, 4])
+            .unwrap()
+            .is_none());
+        assert!(reassembler.is_pending());
+
+        assert!(reassembler.push(&[0x80, 0x42, 1, 2]).unwrap().is_none());
+
+        let request = reassembler
+            .push(&[0x80, 0x42, 3, 4])
+            .unwrap()
+            .expect("Request should be complete");
+
+        assert_eq!(request.op_code, OpCode::CharacteristicWrite);
+        assert_eq!(request.tid, 0x42);
+        assert_eq!(request.char_id, InstanceId::new(0x22));
+
+        assert!(!reassembler.is_pending());
+        assert_eq!(
+            &buffer[..13],
+            &[0, 2, 0x42, 0x22, 0, 6, 0, 1, 4, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn fragmented_request_with_64_bit_iid() {
+        let mut buffer = [0u8; 32];
+        let mut reassembler = RequestReassembler::new(&mut buffer);
+
+        assert!(reassembler
+            .push(&[0x10, 2, 0x42, 0x22, 0, 0, 0, 0, 0, 0, 1, 4, 0, 1, 2])
+            .unwrap()
+            .is_none());
+
+        let request = reassembler
+            .push(&[0x90, 0x42, 3, 4])
+            .unwrap()
+            .expect("Request should be complete");
+
+        assert_eq!(request.char_id, InstanceId::new(0x0100_0000_0000_0022));
+        assert_eq!(request.body(), Some(&[1, 2, 3, 4][..]));
+    }
+
+    #[test]
+    fn continuation_without_first_fragment() {
+        let mut buffer = [0u8; 32];
+        let mut reassembler = RequestReassembler::new(&mut buffer);
+
+        assert!(matches!(
+            reassembler.push(&[0x80, 0x42, 1, 2]),
+            Err(Error::UnexpectedContinuation)
+        ));
+    }
+
+    #[test]
+    fn continuation_with_wrong_tid() {
+        let mut buffer = [0u8; 32];
+        let mut reassembler = RequestReassembler::new(&mut buffer);
+
+        reassembler
+            .push(&[0, 2, 0x42, 0x22, 0, 4, 0, 1, 4])
+            .unwrap();
+
+        assert!(matches!(
+            reassembler.push(&[0x80, 0x43, 1, 2]),
+            Err(Error::TidMismatch {
+                expected: 0x42,
+                received: 0x43
+            })
+        ));
+        assert!(!reassembler.is_pending());
+    }
+
+    #[test]
+    fn continuation_exceeding_body_length() {
+        let mut buffer = [0u8; 32];
+        let mut reassembler = RequestReassembler::new(&mut buffer);
+
+        reassembler
+            .push(&[0, 2, 0x42, 0x22, 0, 4, 0, 1, 4])
+            .unwrap();
+
+        assert!(matches!(
+            reassembler.push(&[0x80, 0x42, 1, 2, 3]),
+            Err(Error::BadLength)
+        ));
+    }
+
+    fn collect_fragments(fragments: Fragments) -> Vec<Vec<u8>> {
+        fragments
+            .map(|fragment| {
+                let mut buffer = vec![0u8; fragment.size()];
+                fragment.write_into(&mut buffer).unwrap();
+                buffer
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unfragmented_response() {
+        let body = [1, 2, 3, 4];
+        let response = HapResponse::new(0x42, HapStatus::Success, &body);
+
+        let fragments = collect_fragments(response.fragments(20).unwrap());
+
+        assert_eq!(fragments, vec![vec![2, 0x42, 0, 4, 0, 1, 2, 3, 4]]);
+
+        let mut buffer = [0u8; 20];
+        response.write_into(&mut buffer).unwrap();
+
+        assert_eq!(&fragments[0][..], &buffer[..response.size()]);
+    }
+
+    #[test]
+    fn response_without_body() {
+        let response = HapResponse::new(0x42, HapStatus::InvalidRequest, &[]);
+
+        let fragments = collect_fragments(response.fragments(3).unwrap());
+
+        assert_eq!(fragments, vec![vec![2, 0x42, 6]]);
+    }
+
+    #[test]
+    fn fragmented_response() {
+        let body = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let response = HapResponse::new(0x42, HapStatus::Success, &body);
+
+        let fragments = collect_fragments(response.fragments(7).unwrap());
+
+        assert_eq!(
+            fragments,
+            vec![
+                vec![2, 0x42, 0, 9, 0, 1, 2],
+                vec![0x82, 0x42, 3, 4, 5, 6, 7],
+                vec![0x82, 0x42, 8, 9],
+            ]
+        );
+    }
+
+    #[test]
+    fn fragmented_request_round_trip() {
+        let body: Vec<u8> = (0..40).collect();
+        let request = HapRequest::new(
+            OpCode::CharacteristicWrite,
+            0x42,
+            InstanceId::new(0x22),
+            &body,
+        );
+
+        let fragments = collect_fragments(request.fragments(16).unwrap());
+
+        assert_eq!(fragments.len(), 4);
+        assert_eq!(&fragments[0][..7], &[0, 2, 0x42, 0x22, 0, 40, 0]);
+        assert_eq!(&fragments[1][..2], &[0x80, 0x42]);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 16));
+
+        let mut buffer = [0u8; 64];
+        let mut reassembler = RequestReassembler::new(&mut buffer);
+
+        for fragment in &fragments[..3] {
+            assert!(reassembler.push(fragment).unwrap().is_none());
+        }
+
+        let parsed = reassembler
+            .push(&fragments[3])
+            .unwrap()
+            .expect("Request should be complete");
+
+        assert_eq!(parsed.op_code, OpCode::CharacteristicWrite);
+        assert_eq!(parsed.body(), Some(&body[..]));
+    }
+
+    #[test]
+    fn fragment_size_too_small() {
+        let body = [1, 2, 3];
+        let response = HapResponse::new(0x42, HapStatus::Success, &body);
+
+        assert!(matches!(
+            response.fragments(MIN_RESPONSE_FRAGMENT_LEN - 1),
+            Err(Error::InsufficientBuffer)
+        ));
+
+        let fragments = collect_fragments(response.fragments(MIN_RESPONSE_FRAGMENT_LEN).unwrap());
+
+        assert_eq!(
+            fragments,
+            vec![vec![2, 0x42, 0, 3, 0, 1], vec![0x82, 0x42, 2, 3]]
+        );
+    }
+
+    #[test]
+    fn request_too_large_for_buffer() {
+        let mut buffer = [0u8; 8];
+        let mut reassembler = RequestReassembler::new(&mut buffer);
+
+        assert!(matches!(
+            reassembler.push(&[0, 2, 0x42, 0x22, 0, 4, 0, 1, 4]),
+            Err(Error::InsufficientBuffer)
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn push_arbitrary_fragments(fragments in vec(vec(any::<u8>(), 0..24), 0..8)) {
+            let mut buffer = [0u8; 64];
+            let mut reassembler = RequestReassembler::new(&mut buffer);
+
+            for fragment in &fragments {
+                let _ = reassembler.push(fragment);
+            }
+        }
+
+        #[test]
+        fn fragmented_request_round_trip_arbitrary(
+            tid in any::<u8>(),
+            iid in any::<u64>(),
+            body in vec(any::<u8>(), 1..600),
+            max_fragment_len in 14usize..200,
+        ) {
+            let request = HapRequest::new(OpCode::CharacteristicWrite, tid, InstanceId::new(iid), &body);
+
+            let mut buffer = [0u8; 640];
+            let mut reassembler = RequestReassembler::new(&mut buffer);
+
+            let mut fragments = request.fragments(max_fragment_len).unwrap().peekable();
+
+            while let Some(fragment) = fragments.next() {
+                let mut fragment_buffer = [0u8; 200];
+
+                prop_assert!(fragment.size() <= max_fragment_len);
+
+                let len = fragment.write_into(&mut fragment_buffer).unwrap();
+
+                let parsed = reassembler.push(&fragment_buffer[..len]).unwrap();
+
+                if fragments.peek().is_some() {
+                    prop_assert!(parsed.is_none());
+                } else {
+                    let parsed = parsed.unwrap();
+                    prop_assert_eq!(parsed.tid, tid);
+                    prop_assert_eq!(parsed.char_id, InstanceId::new(iid));
+                    prop_assert_eq!(parsed.body(), Some(&body[..]));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Long-term identity of the accessory
+//!
+//! The accessory is identified by its Device ID, which is advertised and used
+//! as its pairing identifier, and by its Ed25519 long-term key pair, which
+//! controllers use to verify it. The Device ID is provisioned during
+//! manufacturing, and the key pair is generated on first boot. Both are
+//! replaced on a factory reset, after which controllers see a new accessory.
+
+use ed25519_dalek::{SigningKey, SECRET_KEY_LENGTH};
+use rand_core::{CryptoRng, RngCore};
+
+use crate::{
+    pairing::{Accessory, PUBLIC_KEY_LEN},
+    store::PairingStore,
+    Error,
+};
+
+/// Length of the Device ID
+pub const DEVICE_ID_LEN: usize = 6;
+
+/// Length of the Device ID formatted as `XX:XX:XX:XX:XX:XX`
+pub const PAIRING_ID_LEN: usize = 3 * DEVICE_ID_LEN - 1;
+
+/// 48-bit identifier of the accessory, which is provisioned
+/// during manufacturing, and random after a factory reset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceId(pub [u8; DEVICE_ID_LEN]);
+
+impl DeviceId {
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let mut id = [0u8; DEVICE_ID_LEN];
+        rng.fill_bytes(&mut id);
+
+        DeviceId(id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; DEVICE_ID_LEN] {
+        &self.0
+    }
+
+    /// The Device ID formatted as `XX:XX:XX:XX:XX:XX`,
+    /// which is used as the pairing identifier of the accessory.
+    pub fn pairing_id(&self) -> [u8; PAIRING_ID_LEN] {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+        let mut pairing_id = [b':'; PAIRING_ID_LEN];
+
+        for (i, byte) in self.0.iter().enumerate() {
+            pairing_id[3 * i] = HEX[usize::from(byte >> 4)];
+            pairing_id[3 * i + 1] = HEX[usize::from(byte & 0xf)];
+        }
+
+        pairing_id
+    }
+}
+
+/// Device ID and long-term key pair of the accessory
+#[derive(Clone)]
+pub struct Identity {
+    device_id: DeviceId,
+
+    /// Cached, as it is needed for every pairing request
+    pairing_id: [u8; PAIRING_ID_LEN],
+
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn new(device_id: DeviceId, signing_key: SigningKey) -> Self {
+        Identity {
+            device_id,
+            pairing_id: device_id.pairing_id(),
+            signing_key,
+        }
+    }
+
+    /// Generate a new Device ID and key pair.
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let device_id = DeviceId::generate(rng);
+
+        Identity::with_device_id(device_id, rng)
+    }
+
+    /// Generate a new key pair for the given Device ID.
+    pub fn with_device_id<R: RngCore + CryptoRng>(device_id: DeviceId, rng: &mut R) -> Self {
+        let mut secret = [0u8; SECRET_KEY_LENGTH];
+        rng.fill_bytes(&mut secret);
+
+        Identity::new(device_id, SigningKey::from_bytes(&secret))
+    }
+
+    /// Load the identity from the store, or create one on first boot,
+    /// with the provisioned Device ID and a new key pair.
+    pub fn load_or_provision<S: PairingStore, R: RngCore + CryptoRng>(
+        store: &mut S,
+        device_id: DeviceId,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        if let Some(identity) = store.identity() {
+            return Ok(identity.clone());
+        }
+
+        let identity = Identity::with_device_id(device_id, rng);
+        store.set_identity(&identity)?;
+
+        Ok(identity)
+    }
+
+    pub fn device_id(&self) -> &DeviceId {
+        &self.device_id
+    }
+
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.signing_key
+    }
+
+    /// Long-term public key of the accessory
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// The identity, as used by Pair Setup and Pair Verify.
+    pub fn accessory(&self) -> Accessory<'_> {
+        Accessory {
+            pairing_id: &self.pairing_id,
+            signing_key: &self.signing_key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{pairing::TestRng, store::MemoryStore};
+
+    #[test]
+    fn pairing_id() {
+        let device_id = DeviceId([0x44, 0x55, 0x66, 0x0a, 0xbc, 0xff]);
+
+        assert_eq!(&device_id.pairing_id(), b"44:55:66:0A:BC:FF");
+    }
+
+    #[test]
+    fn provisioned_on_first_boot() {
+        let mut store = MemoryStore::new();
+        let device_id = DeviceId([0x44, 0x55, 0x66, 0x0a, 0xbc, 0xff]);
+
+        let identity = Identity::load_or_provision(&mut store, device_id, &mut TestRng(1)).unwrap();
+        assert_eq!(identity.device_id(), &device_id);
+
+        // The stored identity is used after a reboot
+        let loaded = Identity::load_or_provision(&mut store, device_id, &mut TestRng(2)).unwrap();
+
+        assert_eq!(loaded.public_key(), identity.public_key());
+        assert_eq!(loaded.accessory().pairing_id, &device_id.pairing_id());
+
+        // After a factory reset, the stored identity is used instead
+        let reset = crate::store::factory_reset(&mut store, &mut TestRng(2)).unwrap();
+
+        let loaded = Identity::load_or_provision(&mut store, device_id, &mut TestRng(3)).unwrap();
+        assert_eq!(loaded.device_id(), reset.device_id());
+        assert_ne!(loaded.device_id(), &device_id);
+    }
+
+    #[test]
+    fn generated_identities_differ() {
+        let mut rng = TestRng(1);
+
+        let first = Identity::generate(&mut rng);
+        let second = Identity::generate(&mut rng);
+
+        assert_ne!(first.device_id(), second.device_id());
+        assert_ne!(first.public_key(), second.public_key());
+    }
+}
@@ -0,0 +1,741 @@
+//! Log-structured key-value store for flash memory
+//!
+//! Flash can only be erased a page at a time, and wears out after a limited
+//! number of erase cycles. The store appends every modification as a record
+//! to the active page. Once the page is full, the latest value of each key is
+//! copied to the next page, which becomes the active page. All pages are used
+//! in turn, so that they are erased equally often.
+//!
+//! Page headers and records are protected by a CRC. A record which was only
+//! partially written when power was lost fails the check, and is ignored, so
+//! that the key keeps its previous value. A page only becomes active once its
+//! header has been written, after all records have been copied to it.
+
+use crate::Error;
+
+/// Flash memory reserved for the store
+///
+/// The store only writes multiples of `WRITE_SIZE` bytes at aligned offsets,
+/// and only to memory which has been erased since it was last written.
+pub trait Flash {
+    /// Size of a page, which is the smallest unit that can be erased.
+    const PAGE_SIZE: usize;
+
+    /// Number of pages reserved for the store, at least two.
+    const PAGE_COUNT: usize;
+
+    fn read(&self, page: usize, offset: usize, buffer: &mut [u8]) -> Result<(), Error>;
+
+    fn write(&mut self, page: usize, offset: usize, data: &[u8]) -> Result<(), Error>;
+
+    /// Erase a page, setting all its bytes to `0xff`.
+    fn erase(&mut self, page: usize) -> Result<(), Error>;
+}
+
+/// Alignment of all writes, which is the double word
+/// programmed at once by the STM32WB flash controller.
+pub const WRITE_SIZE: usize = 8;
+
+/// The page header contains the sequence number of the page, and its CRC.
+const PAGE_HEADER_LEN: usize = WRITE_SIZE;
+
+/// The record header contains the key, the length of the value,
+/// and the CRC of both and the value.
+const RECORD_HEADER_LEN: usize = WRITE_SIZE;
+
+/// Included in the CRC of the page header, to tell it apart from other data.
+const PAGE_MAGIC: &[u8] = b"HKKV";
+
+/// Value length of a record which marks a key as removed
+const REMOVED: u16 = 0xfffe;
+
+/// Size of the buffer used to copy records
+const CHUNK_LEN: usize = 4 * WRITE_SIZE;
+
+/// Key-value store on top of a `Flash`
+pub struct KvStore<F: Flash> {
+    flash: F,
+
+    /// Index of the active page
+    page: usize,
+
+    /// Sequence number of the active page, which is
+    /// incremented whenever another page becomes active.
+    sequence: u32,
+
+    /// Offset of the next record in the active page
+    offset: usize,
+}
+
+/// Header of a record stored in a page
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    key: u16,
+
+    /// Length of the value, or `None` if the key has been removed
+    len: Option<usize>,
+
+    /// Offset of the record in the page
+    offset: usize,
+}
+
+impl Record {
+    fn size(&self) -> usize {
+        RECORD_HEADER_LEN + align(self.len.unwrap_or(0))
+    }
+
+    fn end(&self) -> usize {
+        self.offset + self.size()
+    }
+}
+
+impl<F: Flash> KvStore<F> {
+    /// Open the store, and find the active page.
+    ///
+    /// If no page has a valid header, the flash is formatted.
+    pub fn new(mut flash: F) -> Result<Self, Error> {
+        let mut active: Option<(usize, u32)> = None;
+
+        for page in 0..F::PAGE_COUNT {
+            if let Some(sequence) = read_page_header(&flash, page)? {
+                let newer = match active {
+                    Some((_, active_sequence)) => sequence > active_sequence,
+                    None => true,
+                };
+
+                if newer {
+                    active = Some((page, sequence));
+                }
+            }
+        }
+
+        let (page, sequence) = match active {
+            Some(active) => active,
+            None => {
+                flash.erase(0)?;
+                write_page_header(&mut flash, 0, 0)?;
+
+                (0, 0)
+            }
+        };
+
+        let mut store = KvStore {
+            flash,
+            page,
+            sequence,
+            offset: PAGE_HEADER_LEN,
+        };
+
+        while let Some(record) = store.read_record(page, store.offset)? {
+            store.offset = record.end();
+        }
+
+        // A corrupted record can't be skipped, as its length is unknown,
+        // so no more records are appended to this page.
+        if !store.is_erased(page, store.offset)? {
+            store.offset = F::PAGE_SIZE;
+        }
+
+        Ok(store)
+    }
+
+    /// The maximum length of a value.
+    pub const fn max_value_len() -> usize {
+        F::PAGE_SIZE - PAGE_HEADER_LEN - RECORD_HEADER_LEN
+    }
+
+    /// Read the value of a key into the buffer.
+    pub fn get<'b>(&self, key: u16, buffer: &'b mut [u8]) -> Result<Option<&'b [u8]>, Error> {
+        let record = match self.find(self.page, PAGE_HEADER_LEN, key)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+
+        match record.len {
+            Some(len) => {
+                let value = buffer.get_mut(..len).ok_or(Error::InsufficientBuffer)?;
+
+                self.flash
+                    .read(self.page, record.offset + RECORD_HEADER_LEN, value)?;
+
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Set the value of a key.
+    pub fn set(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+        if value.len() > Self::max_value_len() {
+            return Err(Error::BadLength);
+        }
+
+        self.append(key, Some(value))
+    }
+
+    /// Remove a key, if it exists.
+    pub fn remove(&mut self, key: u16) -> Result<(), Error> {
+        match self.find(self.page, PAGE_HEADER_LEN, key)? {
+            Some(Record { len: Some(_), .. }) => self.append(key, None),
+            _ => Ok(()),
+        }
+    }
+
+    /// Append a record to the active page, or move to the next page if it is full.
+    fn append(&mut self, key: u16, value: Option<&[u8]>) -> Result<(), Error> {
+        let len = value.map_or(0, |value| value.len());
+
+        if self.offset + RECORD_HEADER_LEN + align(len) > F::PAGE_SIZE {
+            return self.rotate(key, value);
+        }
+
+        let offset = self.offset;
+
+        // A failed write leaves the page in an unknown state,
+        // so the next record is written to another page.
+        self.offset = F::PAGE_SIZE;
+
+        write_record(&mut self.flash, self.page, offset, key, value)?;
+
+        self.offset = offset + RECORD_HEADER_LEN + align(len);
+
+        Ok(())
+    }
+
+    /// Copy the latest value of each key to the next page, and make it the active page.
+    ///
+    /// The value of `key` is replaced by `value`.
+    fn rotate(&mut self, key: u16, value: Option<&[u8]>) -> Result<(), Error> {
+        let mut used =
+            PAGE_HEADER_LEN + value.map_or(0, |value| RECORD_HEADER_LEN + align(value.len()));
+
+        let mut offset = PAGE_HEADER_LEN;
+        while let Some(record) = self.read_record(self.page, offset)? {
+            if self.is_live(&record, key)? {
+                used += record.size();
+            }
+            offset = record.end();
+        }
+
+        // Check before erasing anything, so that the active page isn't worn for nothing
+        if used > F::PAGE_SIZE {
+            return Err(Error::StoreFull);
+        }
+
+        let target = (self.page + 1) % F::PAGE_COUNT;
+
+        self.flash.erase(target)?;
+
+        let mut target_offset = PAGE_HEADER_LEN;
+
+        let mut offset = PAGE_HEADER_LEN;
+        while let Some(record) = self.read_record(self.page, offset)? {
+            if self.is_live(&record, key)? {
+                self.copy_record(&record, target, target_offset)?;
+                target_offset += record.size();
+            }
+            offset = record.end();
+        }
+
+        // The old value has not been copied, so the new value has to be written
+        // before the page becomes active. A removed key doesn't need a record.
+        if let Some(value) = value {
+            write_record(&mut self.flash, target, target_offset, key, Some(value))?;
+            target_offset += RECORD_HEADER_LEN + align(value.len());
+        }
+
+        write_page_header(&mut self.flash, target, self.sequence.wrapping_add(1))?;
+
+        self.page = target;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.offset = target_offset;
+
+        Ok(())
+    }
+
+    /// Check if a record has to be copied to the next page, which is the case
+    /// if it is the latest value of a key other than `replaced`.
+    fn is_live(&self, record: &Record, replaced: u16) -> Result<bool, Error> {
+        if record.key == replaced || record.len.is_none() {
+            return Ok(false);
+        }
+
+        Ok(self.find(self.page, record.end(), record.key)?.is_none())
+    }
+
+    fn copy_record(&mut self, record: &Record, page: usize, offset: usize) -> Result<(), Error> {
+        let mut chunk = [0u8; CHUNK_LEN];
+
+        let mut copied = 0;
+        while copied < record.size() {
+            let len = CHUNK_LEN.min(record.size() - copied);
+
+            self.flash
+                .read(self.page, record.offset + copied, &mut chunk[..len])?;
+            self.flash.write(page, offset + copied, &chunk[..len])?;
+
+            copied += len;
+        }
+
+        Ok(())
+    }
+
+    /// Find the last record of a key, starting at the given offset.
+    fn find(&self, page: usize, mut offset: usize, key: u16) -> Result<Option<Record>, Error> {
+        let mut found = None;
+
+        while let Some(record) = self.read_record(page, offset)? {
+            if record.key == key {
+                found = Some(record);
+            }
+            offset = record.end();
+        }
+
+        Ok(found)
+    }
+
+    /// Read the record at the given offset.
+    ///
+    /// Returns `None` at the end of the page, which is either
+    /// erased memory or a record with an invalid CRC.
+    fn read_record(&self, page: usize, offset: usize) -> Result<Option<Record>, Error> {
+        if offset + RECORD_HEADER_LEN > F::PAGE_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        self.flash.read(page, offset, &mut header)?;
+
+        let key = u16::from_le_bytes([header[0], header[1]]);
+        let len = match u16::from_le_bytes([header[2], header[3]]) {
+            REMOVED => None,
+            len => Some(len as usize),
+        };
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+        let record = Record { key, len, offset };
+
+        if record.end() > F::PAGE_SIZE {
+            return Ok(None);
+        }
+
+        let mut checksum = Crc::new();
+        checksum.update(&header[..4]);
+
+        let mut chunk = [0u8; CHUNK_LEN];
+
+        let mut checked = 0;
+        while checked < len.unwrap_or(0) {
+            let chunk_len = CHUNK_LEN.min(len.unwrap_or(0) - checked);
+
+            self.flash.read(
+                page,
+                offset + RECORD_HEADER_LEN + checked,
+                &mut chunk[..chunk_len],
+            )?;
+            checksum.update(&chunk[..chunk_len]);
+
+            checked += chunk_len;
+        }
+
+        if checksum.finish() != crc {
+            return Ok(None);
+        }
+
+        Ok(Some(record))
+    }
+
+    /// Check if the record header at the given offset can be written.
+    fn is_erased(&self, page: usize, offset: usize) -> Result<bool, Error> {
+        if offset + RECORD_HEADER_LEN > F::PAGE_SIZE {
+            return Ok(false);
+        }
+
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        self.flash.read(page, offset, &mut header)?;
+
+        Ok(header.iter().all(|byte| *byte == 0xff))
+    }
+}
+
+/// Read the sequence number of a page, if it has a valid header.
+fn read_page_header<F: Flash>(flash: &F, page: usize) -> Result<Option<u32>, Error> {
+    let mut header = [0u8; PAGE_HEADER_LEN];
+    flash.read(page, 0, &mut header)?;
+
+    let sequence = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+    if page_header_crc(sequence) == crc {
+        Ok(Some(sequence))
+    } else {
+        Ok(None)
+    }
+}
+
+fn write_page_header<F: Flash>(flash: &mut F, page: usize, sequence: u32) -> Result<(), Error> {
+    let mut header = [0u8; PAGE_HEADER_LEN];
+    header[..4].copy_from_slice(&sequence.to_le_bytes());
+    header[4..].copy_from_slice(&page_header_crc(sequence).to_le_bytes());
+
+    flash.write(page, 0, &header)
+}
+
+fn page_header_crc(sequence: u32) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(PAGE_MAGIC);
+    crc.update(&sequence.to_le_bytes());
+    crc.finish()
+}
+
+/// Write a record, with the header first, so that an interrupted
+/// write never leaves data behind an erased header.
+fn write_record<F: Flash>(
+    flash: &mut F,
+    page: usize,
+    offset: usize,
+    key: u16,
+    value: Option<&[u8]>,
+) -> Result<(), Error> {
+    let len = match value {
+        Some(value) => value.len() as u16,
+        None => REMOVED,
+    };
+    let value = value.unwrap_or(&[]);
+
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    header[..2].copy_from_slice(&key.to_le_bytes());
+    header[2..4].copy_from_slice(&len.to_le_bytes());
+
+    let mut crc = Crc::new();
+    crc.update(&header[..4]);
+    crc.update(value);
+    header[4..].copy_from_slice(&crc.finish().to_le_bytes());
+
+    flash.write(page, offset, &header)?;
+
+    let aligned_len = value.len() - value.len() % WRITE_SIZE;
+    if aligned_len > 0 {
+        flash.write(page, offset + RECORD_HEADER_LEN, &value[..aligned_len])?;
+    }
+
+    // The last part of the value is padded with erased bytes
+    if aligned_len < value.len() {
+        let mut last = [0xffu8; WRITE_SIZE];
+        last[..value.len() - aligned_len].copy_from_slice(&value[aligned_len..]);
+
+        flash.write(page, offset + RECORD_HEADER_LEN + aligned_len, &last)?;
+    }
+
+    Ok(())
+}
+
+/// Round up to a multiple of `WRITE_SIZE`.
+const fn align(len: usize) -> usize {
+    len.div_ceil(WRITE_SIZE) * WRITE_SIZE
+}
+
+/// CRC-32 as used by Ethernet and zlib
+pub(crate) struct Crc(u32);
+
+impl Crc {
+    pub(crate) fn new() -> Self {
+        Crc(0xffff_ffff)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= u32::from(*byte);
+
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xedb8_8320 & mask);
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Flash simulated in RAM, which checks that it is used like real flash,
+    /// and can simulate a power loss after a number of written bytes.
+    #[derive(Clone)]
+    struct RamFlash {
+        data: Vec<u8>,
+
+        /// Number of times each page has been erased
+        erase_counts: Vec<usize>,
+
+        /// Number of bytes which can be written before power is lost
+        write_budget: Option<usize>,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            RamFlash {
+                data: vec![0xff; Self::PAGE_SIZE * Self::PAGE_COUNT],
+                erase_counts: vec![0; Self::PAGE_COUNT],
+                write_budget: None,
+            }
+        }
+
+        /// Restore power, so that the flash can be opened again.
+        fn reboot(&self) -> Self {
+            RamFlash {
+                write_budget: None,
+                ..self.clone()
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        const PAGE_SIZE: usize = 256;
+        const PAGE_COUNT: usize = 3;
+
+        fn read(&self, page: usize, offset: usize, buffer: &mut [u8]) -> Result<(), Error> {
+            assert!(offset + buffer.len() <= Self::PAGE_SIZE);
+
+            let start = page * Self::PAGE_SIZE + offset;
+            buffer.copy_from_slice(&self.data[start..start + buffer.len()]);
+
+            Ok(())
+        }
+
+        fn write(&mut self, page: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+            assert_eq!(offset % WRITE_SIZE, 0, "Unaligned write");
+            assert_eq!(data.len() % WRITE_SIZE, 0, "Unaligned write length");
+            assert!(offset + data.len() <= Self::PAGE_SIZE);
+
+            let start = page * Self::PAGE_SIZE + offset;
+            let target = &mut self.data[start..start + data.len()];
+
+            assert!(
+                target.iter().all(|byte| *byte == 0xff),
+                "Write to memory which is not erased"
+            );
+
+            let len = match &mut self.write_budget {
+                Some(budget) => {
+                    let len = data.len().min(*budget);
+                    *budget -= len;
+                    len
+                }
+                None => data.len(),
+            };
+
+            target[..len].copy_from_slice(&data[..len]);
+
+            if len < data.len() {
+                Err(Error::Storage)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn erase(&mut self, page: usize) -> Result<(), Error> {
+            if self.write_budget == Some(0) {
+                return Err(Error::Storage);
+            }
+
+            let start = page * Self::PAGE_SIZE;
+            for byte in &mut self.data[start..start + Self::PAGE_SIZE] {
+                *byte = 0xff;
+            }
+
+            self.erase_counts[page] += 1;
+
+            Ok(())
+        }
+    }
+
+    fn get(store: &KvStore<RamFlash>, key: u16) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; 256];
+
+        store
+            .get(key, &mut buffer)
+            .unwrap()
+            .map(|value| value.to_vec())
+    }
+
+    #[test]
+    fn crc() {
+        let mut crc = Crc::new();
+        crc.update(b"123456789");
+
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn empty_store() {
+        let store = KvStore::new(RamFlash::new()).unwrap();
+
+        assert_eq!(get(&store, 1), None);
+        assert_eq!(store.flash.erase_counts, [1, 0, 0]);
+    }
+
+    #[test]
+    fn set_get_and_remove() {
+        let mut store = KvStore::new(RamFlash::new()).unwrap();
+
+        store.set(1, b"first").unwrap();
+        store.set(2, b"a longer value").unwrap();
+        store.set(1, b"replaced").unwrap();
+        store.set(3, &[]).unwrap();
+
+        assert_eq!(get(&store, 1).unwrap(), b"replaced");
+        assert_eq!(get(&store, 2).unwrap(), b"a longer value");
+        assert_eq!(get(&store, 3).unwrap(), b"");
+
+        store.remove(2).unwrap();
+        store.remove(4).unwrap();
+
+        assert_eq!(get(&store, 2), None);
+        assert_eq!(get(&store, 4), None);
+    }
+
+    #[test]
+    fn reopen() {
+        let mut store = KvStore::new(RamFlash::new()).unwrap();
+
+        store.set(1, b"first").unwrap();
+        store.set(2, b"second").unwrap();
+        store.remove(1).unwrap();
+
+        let mut store = KvStore::new(store.flash.reboot()).unwrap();
+
+        assert_eq!(get(&store, 1), None);
+        assert_eq!(get(&store, 2).unwrap(), b"second");
+
+        // New records are appended after the existing ones
+        store.set(3, b"third").unwrap();
+
+        let store = KvStore::new(store.flash.reboot()).unwrap();
+
+        assert_eq!(get(&store, 2).unwrap(), b"second");
+        assert_eq!(get(&store, 3).unwrap(), b"third");
+    }
+
+    #[test]
+    fn insufficient_buffer() {
+        let mut store = KvStore::new(RamFlash::new()).unwrap();
+
+        store.set(1, b"too long").unwrap();
+
+        let mut buffer = [0u8; 4];
+        assert!(matches!(
+            store.get(1, &mut buffer),
+            Err(Error::InsufficientBuffer)
+        ));
+    }
+
+    #[test]
+    fn pages_are_rotated() {
+        let mut store = KvStore::new(RamFlash::new()).unwrap();
+
+        store.set(1, b"constant").unwrap();
+
+        for i in 0..200u32 {
+            store.set(2, &i.to_le_bytes()).unwrap();
+
+            if i % 7 == 0 {
+                store.remove(3).unwrap();
+            } else {
+                store.set(3, &[i as u8; 20]).unwrap();
+            }
+        }
+
+        let store = KvStore::new(store.flash.reboot()).unwrap();
+
+        assert_eq!(get(&store, 1).unwrap(), b"constant");
+        assert_eq!(get(&store, 2).unwrap(), 199u32.to_le_bytes());
+        assert_eq!(get(&store, 3).unwrap(), [199u8; 20]);
+
+        // All pages are erased equally often
+        let counts = &store.flash.erase_counts;
+        assert!(counts[0] > 10);
+        assert!(counts.iter().max().unwrap() - counts.iter().min().unwrap() <= 1);
+    }
+
+    #[test]
+    fn value_too_long() {
+        let mut store = KvStore::new(RamFlash::new()).unwrap();
+
+        let value = [0u8; 256];
+        let max_len = KvStore::<RamFlash>::max_value_len();
+
+        assert!(matches!(
+            store.set(1, &value[..max_len + 1]),
+            Err(Error::BadLength)
+        ));
+
+        store.set(1, &value[..max_len]).unwrap();
+        assert_eq!(get(&store, 1).unwrap().len(), max_len);
+    }
+
+    #[test]
+    fn store_full() {
+        let mut store = KvStore::new(RamFlash::new()).unwrap();
+
+        store.set(1, &[1; 120]).unwrap();
+        store.set(2, &[2; 100]).unwrap();
+
+        assert!(matches!(store.set(3, &[3; 100]), Err(Error::StoreFull)));
+
+        // The existing values are kept, and can still be replaced
+        assert_eq!(get(&store, 1).unwrap(), [1; 120]);
+        assert_eq!(get(&store, 2).unwrap(), [2; 100]);
+
+        store.set(2, &[4; 100]).unwrap();
+        assert_eq!(get(&store, 2).unwrap(), [4; 100]);
+    }
+
+    #[test]
+    fn power_loss() {
+        // Every possible point of failure, with the active page filled
+        // to different levels, so that both appending and rotating fail
+        for (filler, budget) in (1..10u8).flat_map(|filler| (0..300).map(move |b| (filler, b))) {
+            let mut store = KvStore::new(RamFlash::new()).unwrap();
+
+            store.set(1, b"kept").unwrap();
+            store.set(2, b"old").unwrap();
+
+            for i in 0..filler {
+                store.set(3, &[i; 10]).unwrap();
+            }
+
+            store.flash.write_budget = Some(budget);
+
+            let result = store.set(2, b"new value");
+            let written = result.is_ok();
+
+            let _ = store.set(3, &[0xaa; 40]);
+
+            let mut store = KvStore::new(store.flash.reboot()).unwrap();
+
+            assert_eq!(get(&store, 1).unwrap(), b"kept");
+
+            let value = get(&store, 2).unwrap();
+            assert!(value == b"old" || value == b"new value");
+
+            if written {
+                assert_eq!(value, b"new value");
+            }
+
+            let value = get(&store, 3).unwrap();
+            assert!(value == [filler - 1; 10] || value == [0xaa; 40]);
+
+            // The store can be used again after the power loss
+            store.set(2, b"after").unwrap();
+
+            let store = KvStore::new(store.flash.reboot()).unwrap();
+            assert_eq!(get(&store, 2).unwrap(), b"after");
+            assert_eq!(get(&store, 1).unwrap(), b"kept");
+        }
+    }
+}
@@ -2,10 +2,43 @@
 // tests not.
 #![cfg_attr(not(test), no_std)]
 
-use core::convert::{TryFrom, TryInto};
+use core::{
+    convert::{TryFrom, TryInto},
+    fmt,
+};
 
+// Allows the code generated by the derive macros,
+// which refers to `::homekit_ble`, to be used in tests.
+#[cfg(test)]
+extern crate self as homekit_ble;
+
+mod crypto;
+pub mod fragment;
+pub mod identity;
+pub mod kv;
+pub mod pairing;
+pub mod param;
+pub mod provisioning;
+pub mod qr;
+pub mod session;
+pub mod setup_code;
+pub mod store;
 pub mod tlv;
 
+pub use rand_core;
+
+use fragment::Fragments;
+use param::Params;
+
+/// Control field bit indicating a response PDU
+const CONTROL_FIELD_RESPONSE: u8 = 1 << 1;
+
+/// Control field bit indicating 64-bit instance IDs
+const CONTROL_FIELD_IID_64: u8 = 1 << 4;
+
+/// Control field bit indicating a continuation fragment
+const CONTROL_FIELD_CONTINUATION: u8 = 1 << 7;
+
 #[derive(Debug)]
 pub enum HapPdu<'a> {
     Request(HapRequest<'a>),
@@ -13,52 +46,60 @@ pub enum HapPdu<'a> {
 }
 
 impl HapPdu<'_> {
-    pub fn parse(data: &[u8]) -> Result<HapPdu, Error> {
+    pub fn parse(data: &[u8]) -> Result<HapPdu<'_>, Error> {
         // We need at least 1 byte for the control field
 
-        let control_field = data.get(0).ok_or(Error::BadLength)?;
-
-        let fragmented = if control_field & (1 << 7) == (1 << 7) {
-            Fragmented::Continuation
-        } else {
-            Fragmented::First
-        };
-
-        assert!(
-            fragmented == Fragmented::First,
-            "Continuation not yet implemented"
-        );
-
-        let iid_size = if control_field & (1 << 4) == (1 << 4) {
-            IidSize::Bit64
-        } else {
-            IidSize::Bit16
-        };
+        let control_field = data.first().ok_or(Error::BadLength)?;
 
-        let request_type = if control_field & (1 << 1) == (1 << 1) {
-            PduType::Response
-        } else {
-            PduType::Request
-        };
+        let (fragmented, iid_size, request_type) = parse_control_field(*control_field)?;
 
-        // check for reserved values in pdu type
-        if 0b1100 & control_field != 0 {
-            // Unsupported type of PDU.
-            return Err(Error::UnsupportedPduType((control_field & 0b1110) >> 1));
-        };
+        // A continuation fragment cannot be parsed on its own,
+        // it has to be passed to a `RequestReassembler`.
+        if fragmented == Fragmented::Continuation {
+            return Err(Error::UnexpectedContinuation);
+        }
 
         match request_type {
             PduType::Request => Ok(HapPdu::Request(HapRequest::parse_after_control(
                 &data[1..],
                 iid_size,
             )?)),
-            PduType::Response => {
-                unimplemented!("Not yet implemented");
-            }
+            PduType::Response => Ok(HapPdu::Response(HapResponse::parse_after_control(
+                &data[1..],
+            )?)),
         }
     }
 }
 
+/// Parse the control field of a HAP PDU, see section 7.3.3.1
+fn parse_control_field(control_field: u8) -> Result<(Fragmented, IidSize, PduType), Error> {
+    let fragmented = if control_field & CONTROL_FIELD_CONTINUATION != 0 {
+        Fragmented::Continuation
+    } else {
+        Fragmented::First
+    };
+
+    let iid_size = if control_field & CONTROL_FIELD_IID_64 != 0 {
+        IidSize::Bit64
+    } else {
+        IidSize::Bit16
+    };
+
+    let request_type = if control_field & CONTROL_FIELD_RESPONSE != 0 {
+        PduType::Response
+    } else {
+        PduType::Request
+    };
+
+    // check for reserved values in pdu type
+    if 0b1100 & control_field != 0 {
+        // Unsupported type of PDU.
+        return Err(Error::UnsupportedPduType((control_field & 0b1110) >> 1));
+    };
+
+    Ok((fragmented, iid_size, request_type))
+}
+
 #[derive(Debug)]
 pub struct HapRequest<'a> {
     iid_size: IidSize,
@@ -67,16 +108,38 @@ pub struct HapRequest<'a> {
 
     pub tid: u8,
 
-    pub char_id: u16,
+    pub char_id: InstanceId,
 
     data: Option<&'a [u8]>,
 }
 
-impl HapRequest<'_> {
-    fn parse_after_control(data: &[u8], iid_size: IidSize) -> Result<HapRequest, Error> {
-        // The Request Header is at least 4 bytes (excluding the control field)
+impl<'a> HapRequest<'a> {
+    /// Create a new request.
+    ///
+    /// The smallest possible size is used to encode the instance ID,
+    /// use `with_iid_size` to override it.
+    pub fn new(op_code: OpCode, tid: u8, char_id: InstanceId, body: &'a [u8]) -> Self {
+        HapRequest {
+            iid_size: char_id.min_size(),
+            op_code,
+            tid,
+            char_id,
+            data: if body.is_empty() { None } else { Some(body) },
+        }
+    }
+
+    /// Set the size used to encode the instance ID.
+    pub fn with_iid_size(mut self, iid_size: IidSize) -> Self {
+        self.iid_size = iid_size;
+        self
+    }
 
-        if data.len() < 4 {
+    fn parse_after_control(data: &'a [u8], iid_size: IidSize) -> Result<HapRequest<'a>, Error> {
+        // The Request Header is at least 4 bytes (excluding the control field),
+        // or 10 bytes when 64-bit instance IDs are used.
+        let header_len = 2 + iid_size.num_bytes();
+
+        if data.len() < header_len {
             return Err(Error::BadLength);
         }
 
@@ -84,19 +147,106 @@ impl HapRequest<'_> {
 
         let tid = data[1];
 
-        // Unwrap is safe, we know that we have at least 4 bytes
-        let char_id: u16 = u16::from_le_bytes((&data[2..4]).try_into().unwrap());
+        let char_id = InstanceId::parse(&data[2..header_len], iid_size)?;
+
+        // The body is optional, and starts with a 2 byte length field
+        let body = match &data[header_len..] {
+            [] => None,
+            [_] => return Err(Error::BadLength),
+            [len_low, len_high, body @ ..] => {
+                let body_len = u16::from_le_bytes([*len_low, *len_high]) as usize;
 
-        // TODO: Support data
+                // Additional bytes after the body are ignored
+                let body = body.get(..body_len).ok_or(Error::Truncated)?;
+
+                if body.is_empty() {
+                    None
+                } else {
+                    Some(body)
+                }
+            }
+        };
 
         Ok(HapRequest {
             iid_size,
             op_code,
             tid,
             char_id,
-            data: None,
+            data: body,
         })
     }
+
+    /// Size of the instance ID used in the request
+    pub fn iid_size(&self) -> IidSize {
+        self.iid_size
+    }
+
+    /// The raw body of the request, if present.
+    pub fn body(&self) -> Option<&'a [u8]> {
+        self.data
+    }
+
+    /// Iterate over the parameters contained in the body of the request.
+    ///
+    /// Values longer than 255 bytes are split over multiple
+    /// consecutive parameters with the same type. Use a
+    /// `tlv::TlvReader` on the body to merge them.
+    pub fn params(&self) -> Params<'a> {
+        Params::new(self.data.unwrap_or(&[]))
+    }
+
+    /// Split the request into fragments of at most `max_fragment_len` bytes.
+    ///
+    /// Fails if `max_fragment_len` is too small to contain the header
+    /// and at least one byte of the body.
+    pub fn fragments(&self, max_fragment_len: usize) -> Result<Fragments<'a>, Error> {
+        // Control Field, Opcode, TID and up to 8 bytes of instance ID
+        let mut header = [0u8; 11];
+
+        header[0] = match self.iid_size {
+            IidSize::Bit16 => 0,
+            IidSize::Bit64 => CONTROL_FIELD_IID_64,
+        };
+        header[1] = self.op_code as u8;
+        header[2] = self.tid;
+
+        let iid_len = self.char_id.write_into(&mut header[3..], self.iid_size)?;
+
+        Fragments::new(
+            &header[..3 + iid_len],
+            self.tid,
+            self.data.unwrap_or(&[]),
+            max_fragment_len,
+        )
+    }
+
+    /// Write the unfragmented request into a buffer.
+    pub fn write_into(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut fragments = self.fragments(buffer.len())?;
+
+        if self.size() > buffer.len() {
+            return Err(Error::InsufficientBuffer);
+        }
+
+        // Unwrap is safe, there is always at least one fragment
+        fragments.next().unwrap().write_into(buffer)?;
+
+        Ok(())
+    }
+
+    /// Calculate the size of the request in bytes
+    pub fn size(&self) -> usize {
+        // Header consists of Control Field, Opcode, TID, and Instance ID
+        let header_len = 3 + self.iid_size.num_bytes();
+
+        // The body is optional
+        let body_len = match self.data {
+            Some(data) => data.len() + 2,
+            None => 0,
+        };
+
+        header_len + body_len
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -105,21 +255,105 @@ enum Fragmented {
     Continuation,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 enum PduType {
     Request,
     Response,
 }
 
-#[derive(Debug)]
-enum IidSize {
+/// Size of the instance IDs used in a PDU, indicated by bit 4 of the control field
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IidSize {
     Bit16,
     Bit64,
 }
 
+impl IidSize {
+    /// Number of bytes used to encode an instance ID of this size
+    pub fn num_bytes(self) -> usize {
+        match self {
+            IidSize::Bit16 => 2,
+            IidSize::Bit64 => 8,
+        }
+    }
+}
+
+/// Instance ID of a HAP service or characteristic
+///
+/// Instance IDs are encoded using either 16 or 64 bits,
+/// depending on the `IidSize` used.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InstanceId(u64);
+
+impl InstanceId {
+    pub const fn new(id: u64) -> Self {
+        InstanceId(id)
+    }
+
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    /// The smallest size which can be used to encode this instance ID
+    pub fn min_size(self) -> IidSize {
+        if self.0 > u16::MAX as u64 {
+            IidSize::Bit64
+        } else {
+            IidSize::Bit16
+        }
+    }
+
+    /// Parse an instance ID of the given size, encoded as little endian
+    pub fn parse(data: &[u8], iid_size: IidSize) -> Result<Self, Error> {
+        let data = data.get(..iid_size.num_bytes()).ok_or(Error::BadLength)?;
+
+        // Unwraps are safe, the length of data was checked above
+        let id = match iid_size {
+            IidSize::Bit16 => u16::from_le_bytes(data.try_into().unwrap()) as u64,
+            IidSize::Bit64 => u64::from_le_bytes(data.try_into().unwrap()),
+        };
+
+        Ok(InstanceId(id))
+    }
+
+    /// Write the instance ID using the given size, returning the number of bytes written.
+    pub fn write_into(self, buffer: &mut [u8], iid_size: IidSize) -> Result<usize, Error> {
+        if self.min_size() == IidSize::Bit64 && iid_size == IidSize::Bit16 {
+            return Err(Error::InstanceIdTooLarge(self));
+        }
+
+        let len = iid_size.num_bytes();
+
+        let buffer = buffer.get_mut(..len).ok_or(Error::InsufficientBuffer)?;
+
+        buffer.copy_from_slice(&self.0.to_le_bytes()[..len]);
+
+        Ok(len)
+    }
+}
+
+impl From<u16> for InstanceId {
+    fn from(id: u16) -> Self {
+        InstanceId(id as u64)
+    }
+}
+
+impl From<u64> for InstanceId {
+    fn from(id: u64) -> Self {
+        InstanceId(id)
+    }
+}
+
+impl fmt::Display for InstanceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 /// HAP Status
 ///
 /// See Table 7-37
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum HapStatus {
     Success = 0x0,
     UnsupportedPdu = 0x1,
@@ -130,6 +364,27 @@ pub enum HapStatus {
     InvalidRequest = 0x6,
 }
 
+impl TryFrom<u8> for HapStatus {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use HapStatus::*;
+
+        let status = match value {
+            0x0 => Success,
+            0x1 => UnsupportedPdu,
+            0x2 => MaxProcedures,
+            0x3 => InsufficientAuthorization,
+            0x4 => InvalidInstanceId,
+            0x5 => InsufficientAuthentication,
+            0x6 => InvalidRequest,
+            other => return Err(Error::UnknownStatus(other)),
+        };
+
+        Ok(status)
+    }
+}
+
 #[derive(Debug)]
 pub struct HapResponse<'a> {
     tid: u8,
@@ -139,36 +394,84 @@ pub struct HapResponse<'a> {
     data: &'a [u8],
 }
 
-impl HapResponse<'_> {
+impl<'a> HapResponse<'a> {
     pub fn new(tid: u8, status: HapStatus, data: &[u8]) -> HapResponse<'_> {
         HapResponse { tid, status, data }
     }
 
-    /// Write the response into a buffer.
-    pub fn write_into(&self, buffer: &mut [u8]) -> Result<(), Error> {
-        if self.size() > buffer.len() {
-            return Err(Error::InsufficientBuffer);
-        }
+    fn parse_after_control(data: &'a [u8]) -> Result<HapResponse<'a>, Error> {
+        // The Response Header is 2 bytes (excluding the control field)
 
+        let (tid, status, rest) = match data {
+            [tid, status, rest @ ..] => (*tid, HapStatus::try_from(*status)?, rest),
+            _ => return Err(Error::BadLength),
+        };
+
+        // The body is optional, and starts with a 2 byte length field
+        let data = match rest {
+            [] => &[],
+            [_] => return Err(Error::BadLength),
+            [len_low, len_high, body @ ..] => {
+                let body_len = u16::from_le_bytes([*len_low, *len_high]) as usize;
+
+                // Additional bytes after the body are ignored
+                body.get(..body_len).ok_or(Error::Truncated)?
+            }
+        };
+
+        Ok(HapResponse { tid, status, data })
+    }
+
+    pub fn tid(&self) -> u8 {
+        self.tid
+    }
+
+    pub fn status(&self) -> HapStatus {
+        self.status
+    }
+
+    /// The raw body of the response, empty if the response has no body.
+    pub fn body(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Iterate over the parameters contained in the body of the response.
+    pub fn params(&self) -> Params<'a> {
+        Params::new(self.data)
+    }
+
+    /// Split the response into fragments of at most `max_fragment_len` bytes.
+    ///
+    /// Fails if `max_fragment_len` is too small to contain the header
+    /// and at least one byte of the body.
+    pub fn fragments(&self, max_fragment_len: usize) -> Result<Fragments<'a>, Error> {
+        let header = [CONTROL_FIELD_RESPONSE, self.tid, self.status as u8];
+
+        Fragments::new(&header, self.tid, self.data, max_fragment_len)
+    }
+
+    /// Write the unfragmented response into a buffer.
+    pub fn write_into(&self, buffer: &mut [u8]) -> Result<(), Error> {
         // Data longer than u16 MAX is not supported by the
         // protocol
         if self.data.len() > (u16::MAX as usize) {
-            panic!("Data for HapResponse has to be < u16::MAX");
+            return Err(Error::BodyTooLarge);
         }
 
-        // TODO: Support fragmentation,
+        if self.size() > buffer.len() {
+            return Err(Error::InsufficientBuffer);
+        }
 
-        // Control field fixed to 2 for now (indicating unfragmented response)
-        buffer[0] = 2;
+        buffer[0] = CONTROL_FIELD_RESPONSE;
 
         buffer[1] = self.tid;
         buffer[2] = self.status as u8;
 
-        if self.data.len() > 0 {
+        if !self.data.is_empty() {
             buffer[3] = self.data.len() as u8;
             buffer[4] = (self.data.len() >> 8) as u8;
 
-            buffer[5..(5 + self.data.len())].copy_from_slice(&self.data);
+            buffer[5..(5 + self.data.len())].copy_from_slice(self.data);
         }
 
         Ok(())
@@ -181,7 +484,7 @@ impl HapResponse<'_> {
         let header_len = 3;
 
         // The body is optional
-        let body_len = if self.data.len() > 0 {
+        let body_len = if !self.data.is_empty() {
             self.data.len() + 2
         } else {
             0
@@ -193,23 +496,67 @@ impl HapResponse<'_> {
 
 #[derive(Debug)]
 pub enum Error {
+    /// The PDU or one of its fields has an invalid length.
     BadLength,
+    /// The PDU declares more data than it contains.
+    Truncated,
     UnsupportedPduType(u8),
     UnknownOpCode(u8),
     InsufficientBuffer,
+    /// The body is longer than `u16::MAX` bytes, and cannot be encoded.
+    BodyTooLarge,
+    /// A continuation fragment was received without a preceding first fragment.
+    UnexpectedContinuation,
+    /// The TID of a continuation fragment does not match the TID of the first fragment.
+    TidMismatch {
+        expected: u8,
+        received: u8,
+    },
+    UnknownParamType(u8),
+    UnknownTlvType(u8),
+    UnknownStatus(u8),
+    /// The instance ID cannot be encoded using 16 bits.
+    InstanceIdTooLarge(InstanceId),
+    /// No TLV item with the given type is present.
+    TlvNotFound(u8),
+    /// The value of the TLV item is split over multiple items,
+    /// and cannot be accessed without copying it.
+    FragmentedTlv(u8),
+    /// The value of the TLV item has an unexpected length or format.
+    InvalidTlvValue(u8),
+    /// The pairing method is not known.
+    UnknownMethod(u8),
+    /// A proof, signature or authentication tag is invalid.
+    Authentication,
+    /// The secure session has been closed, after an authentication
+    /// failure or because all nonces have been used.
+    SessionClosed,
+    /// The maximum number of pairings has been stored,
+    /// or there is no space left in persistent storage.
+    StoreFull,
+    /// Reading or writing persistent storage failed.
+    Storage,
+    /// The setup code doesn't have 8 digits, or is too easy to guess.
+    InvalidSetupCode,
+    /// The Setup ID doesn't consist of 4 digits or upper-case letters.
+    InvalidSetupId,
+    /// The data doesn't fit into the largest supported QR code.
+    DataTooLong,
+    /// A provisioned record is missing, corrupted, or has an unsupported version.
+    InvalidRecord,
 }
 
 /// HAP Opcode, defined in Table 7-8
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum OpCode {
-    CharacteristicSignatureRead,
-    CharacteristicWrite,
-    CharacteristicRead,
-    CharacteristicTimedWrite,
-    CharacteristicExecuteWrite,
-    ServiceSignatureRead,
-    CharacteristicConfiguration,
-    ProtocolConfiguration,
+    CharacteristicSignatureRead = 1,
+    CharacteristicWrite = 2,
+    CharacteristicRead = 3,
+    CharacteristicTimedWrite = 4,
+    CharacteristicExecuteWrite = 5,
+    ServiceSignatureRead = 6,
+    CharacteristicConfiguration = 7,
+    ProtocolConfiguration = 8,
 }
 
 impl TryFrom<u8> for OpCode {
@@ -237,6 +584,8 @@ impl TryFrom<u8> for OpCode {
 #[cfg(test)]
 mod test {
     use super::*;
+    use param::ParamType;
+    use proptest::{collection::vec, prelude::*};
 
     #[test]
     fn test_parsing_service_signature_pdu() {
@@ -246,12 +595,230 @@ mod test {
 
         if let HapPdu::Request(request) = pdu {
             assert_eq!(request.op_code, OpCode::ServiceSignatureRead);
-            assert_eq!(request.char_id, 0x10);
+            assert_eq!(request.char_id, InstanceId::new(0x10));
+            assert_eq!(request.iid_size(), IidSize::Bit16);
+        } else {
+            panic!("Expected HapPdu::Request, got {:?}", pdu);
+        }
+    }
+
+    #[test]
+    fn test_parsing_request_with_body() {
+        // Characteristic write with a Value and a Return-Response parameter
+        let rx_data = [0, 2, 0x13, 0x22, 0, 7, 0, 1, 2, 0xab, 0xcd, 9, 1, 1];
+
+        let pdu = HapPdu::parse(&rx_data).unwrap();
+
+        if let HapPdu::Request(request) = pdu {
+            assert_eq!(request.op_code, OpCode::CharacteristicWrite);
+            assert_eq!(request.char_id, InstanceId::new(0x22));
+            assert_eq!(request.body(), Some(&rx_data[7..]));
+
+            let mut params = request.params();
+
+            let value = params.next().unwrap().unwrap();
+            assert_eq!(value.param_type, ParamType::Value);
+            assert_eq!(value.value, &[0xab, 0xcd]);
+
+            let return_response = params.next().unwrap().unwrap();
+            assert_eq!(return_response.param_type, ParamType::ReturnResponse);
+            assert_eq!(return_response.value, &[1]);
+
+            assert!(params.next().is_none());
+        } else {
+            panic!("Expected HapPdu::Request, got {:?}", pdu);
+        }
+    }
+
+    #[test]
+    fn test_parsing_request_with_64_bit_iid() {
+        let rx_data = [
+            0x10, 3, 0x13, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 3, 0, 1, 1, 0xff,
+        ];
+
+        let pdu = HapPdu::parse(&rx_data).unwrap();
+
+        if let HapPdu::Request(request) = pdu {
+            assert_eq!(request.op_code, OpCode::CharacteristicRead);
+            assert_eq!(request.iid_size(), IidSize::Bit64);
+            assert_eq!(request.char_id, InstanceId::new(0x0102_0304_0506_0708));
+            assert_eq!(request.body(), Some(&rx_data[13..]));
+        } else {
+            panic!("Expected HapPdu::Request, got {:?}", pdu);
+        }
+    }
+
+    #[test]
+    fn test_instance_id_encoding() {
+        let mut buffer = [0u8; 8];
+
+        let iid = InstanceId::from(0x1234u16);
+        assert_eq!(iid.write_into(&mut buffer, IidSize::Bit16).unwrap(), 2);
+        assert_eq!(&buffer[..2], &[0x34, 0x12]);
+
+        assert_eq!(iid.write_into(&mut buffer, IidSize::Bit64).unwrap(), 8);
+        assert_eq!(buffer, [0x34, 0x12, 0, 0, 0, 0, 0, 0]);
+
+        let large_iid = InstanceId::new(0x1_0000);
+        assert_eq!(large_iid.min_size(), IidSize::Bit64);
+        assert!(matches!(
+            large_iid.write_into(&mut buffer, IidSize::Bit16),
+            Err(Error::InstanceIdTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let body = [0x01, 0x02, 0xab, 0xcd];
+        let request = HapRequest::new(
+            OpCode::CharacteristicWrite,
+            0x21,
+            InstanceId::new(0x22),
+            &body,
+        );
+
+        let mut buffer = [0u8; 16];
+        request.write_into(&mut buffer).unwrap();
+
+        assert_eq!(request.size(), 11);
+        assert_eq!(
+            &buffer[..request.size()],
+            &[0, 2, 0x21, 0x22, 0, 4, 0, 0x01, 0x02, 0xab, 0xcd]
+        );
+
+        let pdu = HapPdu::parse(&buffer[..request.size()]).unwrap();
+
+        if let HapPdu::Request(parsed) = pdu {
+            assert_eq!(parsed.op_code, OpCode::CharacteristicWrite);
+            assert_eq!(parsed.tid, 0x21);
+            assert_eq!(parsed.char_id, InstanceId::new(0x22));
+            assert_eq!(parsed.body(), Some(&body[..]));
+        } else {
+            panic!("Expected HapPdu::Request, got {:?}", pdu);
+        }
+    }
+
+    #[test]
+    fn test_request_with_64_bit_iid_round_trip() {
+        let request = HapRequest::new(
+            OpCode::ServiceSignatureRead,
+            0x21,
+            InstanceId::new(0x10),
+            &[],
+        )
+        .with_iid_size(IidSize::Bit64);
+
+        let mut buffer = [0u8; 16];
+        request.write_into(&mut buffer).unwrap();
+
+        assert_eq!(
+            &buffer[..request.size()],
+            &[0x10, 6, 0x21, 0x10, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        let pdu = HapPdu::parse(&buffer[..request.size()]).unwrap();
+
+        if let HapPdu::Request(parsed) = pdu {
+            assert_eq!(parsed.iid_size(), IidSize::Bit64);
+            assert_eq!(parsed.char_id, InstanceId::new(0x10));
+            assert_eq!(parsed.body(), None);
         } else {
             panic!("Expected HapPdu::Request, got {:?}", pdu);
         }
     }
 
+    #[test]
+    fn test_parsing_request_body_too_short() {
+        // Body length indicates 8 bytes, but only 7 are present
+        let rx_data = [0, 2, 0x13, 0x22, 0, 8, 0, 1, 2, 0xab, 0xcd, 9, 1, 1];
+
+        assert!(matches!(HapPdu::parse(&rx_data), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn test_parsing_response_pdu() {
+        let rx_data = [2, 0x13, 0, 6, 0, 0x0f, 0x02, 0x04, 0x00, 0x10, 0x00];
+
+        let pdu = HapPdu::parse(&rx_data).unwrap();
+
+        if let HapPdu::Response(response) = pdu {
+            assert_eq!(response.tid(), 0x13);
+            assert_eq!(response.status(), HapStatus::Success);
+            assert_eq!(response.body(), &rx_data[5..]);
+
+            let mut params = response.params();
+
+            let properties = params.next().unwrap().unwrap();
+            assert_eq!(properties.param_type, ParamType::ServiceProperties);
+            assert_eq!(properties.value, &[0x04, 0x00]);
+
+            let linked_services = params.next().unwrap().unwrap();
+            assert_eq!(linked_services.param_type, ParamType::LinkedServices);
+            assert_eq!(linked_services.value, &[]);
+
+            assert!(params.next().is_none());
+        } else {
+            panic!("Expected HapPdu::Response, got {:?}", pdu);
+        }
+    }
+
+    #[test]
+    fn test_parsing_response_without_body() {
+        let rx_data = [2, 0x13, 6];
+
+        let pdu = HapPdu::parse(&rx_data).unwrap();
+
+        if let HapPdu::Response(response) = pdu {
+            assert_eq!(response.status(), HapStatus::InvalidRequest);
+            assert!(response.body().is_empty());
+        } else {
+            panic!("Expected HapPdu::Response, got {:?}", pdu);
+        }
+    }
+
+    #[test]
+    fn test_parsing_response_unknown_status() {
+        let rx_data = [2, 0x13, 0x42];
+
+        assert!(matches!(
+            HapPdu::parse(&rx_data),
+            Err(Error::UnknownStatus(0x42))
+        ));
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let body = [0x01, 0x03, 0xaa, 0xbb, 0xcc];
+        let response = HapResponse::new(0x99, HapStatus::InsufficientAuthorization, &body);
+
+        let mut buffer = [0u8; 16];
+        response.write_into(&mut buffer).unwrap();
+
+        let pdu = HapPdu::parse(&buffer[..response.size()]).unwrap();
+
+        if let HapPdu::Response(parsed) = pdu {
+            assert_eq!(parsed.tid(), 0x99);
+            assert_eq!(parsed.status(), HapStatus::InsufficientAuthorization);
+            assert_eq!(parsed.body(), &body);
+        } else {
+            panic!("Expected HapPdu::Response, got {:?}", pdu);
+        }
+    }
+
+    #[test]
+    fn test_response_body_too_large() {
+        let body = vec![0u8; u16::MAX as usize + 1];
+        let response = HapResponse::new(0x99, HapStatus::Success, &body);
+
+        let mut buffer = vec![0u8; body.len() + 5];
+
+        assert!(matches!(
+            response.write_into(&mut buffer),
+            Err(Error::BodyTooLarge)
+        ));
+        assert!(matches!(response.fragments(20), Err(Error::BodyTooLarge)));
+    }
+
     #[test]
     fn test_parsing_pdu_too_small() {
         // A Request PDU needs at least 5 Bytes
@@ -259,4 +826,93 @@ mod test {
 
         assert!(matches!(HapPdu::parse(&rx_data), Err(Error::BadLength)));
     }
+
+    #[test]
+    fn test_parsing_continuation_fragment() {
+        let rx_data = [0x80, 0x42, 1, 2, 3];
+
+        assert!(matches!(
+            HapPdu::parse(&rx_data),
+            Err(Error::UnexpectedContinuation)
+        ));
+    }
+
+    fn op_code() -> impl Strategy<Value = OpCode> {
+        (1u8..=8).prop_map(|op_code| OpCode::try_from(op_code).unwrap())
+    }
+
+    fn status() -> impl Strategy<Value = HapStatus> {
+        (0u8..=6).prop_map(|status| HapStatus::try_from(status).unwrap())
+    }
+
+    proptest! {
+        #[test]
+        fn parse_arbitrary_data(data in vec(any::<u8>(), 0..64)) {
+            let _ = HapPdu::parse(&data);
+        }
+
+        #[test]
+        fn request_round_trip(
+            op_code in op_code(),
+            tid in any::<u8>(),
+            iid in any::<u64>(),
+            body in vec(any::<u8>(), 0..300),
+        ) {
+            let request = HapRequest::new(op_code, tid, InstanceId::new(iid), &body);
+
+            let mut buffer = [0u8; 320];
+            request.write_into(&mut buffer).unwrap();
+
+            match HapPdu::parse(&buffer[..request.size()]).unwrap() {
+                HapPdu::Request(parsed) => {
+                    prop_assert_eq!(parsed.op_code, op_code);
+                    prop_assert_eq!(parsed.tid, tid);
+                    prop_assert_eq!(parsed.char_id, InstanceId::new(iid));
+                    prop_assert_eq!(parsed.body().unwrap_or(&[]), &body[..]);
+                }
+                HapPdu::Response(_) => prop_assert!(false, "Expected request"),
+            }
+        }
+
+        #[test]
+        fn response_round_trip(
+            tid in any::<u8>(),
+            status in status(),
+            body in vec(any::<u8>(), 0..300),
+        ) {
+            let response = HapResponse::new(tid, status, &body);
+
+            let mut buffer = [0u8; 320];
+            response.write_into(&mut buffer).unwrap();
+
+            match HapPdu::parse(&buffer[..response.size()]).unwrap() {
+                HapPdu::Response(parsed) => {
+                    prop_assert_eq!(parsed.tid(), tid);
+                    prop_assert_eq!(parsed.status(), status);
+                    prop_assert_eq!(parsed.body(), &body[..]);
+                }
+                HapPdu::Request(_) => prop_assert!(false, "Expected response"),
+            }
+        }
+
+        #[test]
+        fn write_into_arbitrary_buffer(
+            body in vec(any::<u8>(), 0..64),
+            buffer_len in 0usize..80,
+        ) {
+            let mut buffer = vec![0u8; buffer_len];
+
+            let response = HapResponse::new(1, HapStatus::Success, &body);
+            prop_assert_eq!(
+                response.write_into(&mut buffer).is_ok(),
+                response.size() <= buffer_len
+            );
+
+            let request = HapRequest::new(OpCode::CharacteristicWrite, 1, InstanceId::new(1), &body);
+            prop_assert_eq!(
+                request.write_into(&mut buffer).is_ok(),
+                request.size() <= buffer_len
+            );
+        }
+    }
 }
@@ -0,0 +1,260 @@
+//! Pairing of controllers with the accessory
+//!
+//! Pairing messages are TLV8 encoded, using the types
+//! from Table 5-6 of the HAP specification.
+
+use core::convert::TryFrom;
+
+use heapless::{consts::U36, Vec};
+
+use crate::{
+    param::ParamType,
+    tlv::{encoded_len, Tlv, TlvReader, TlvWrite, TlvWriter},
+    Error,
+};
+
+mod pairings;
+mod setup;
+pub mod srp;
+mod verify;
+
+pub use pairings::{handle_pairings, Pairings, MAX_PAIRINGS};
+pub use setup::{PairSetup, SetupRequest, MAX_SETUP_ATTEMPTS};
+pub use verify::{PairVerify, VerifiedSession};
+
+pub use ed25519_dalek::SigningKey;
+
+#[cfg(test)]
+pub(crate) use pairings::test::pairing;
+#[cfg(test)]
+pub(crate) use setup::test::TestRng;
+
+/// Length of the Ed25519 public keys of the accessory and the controllers
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Length of an Ed25519 signature
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Maximum length of a pairing identifier
+pub const MAX_PAIRING_ID_LEN: usize = 36;
+
+/// Maximum length of a pairing message, which is the response
+/// listing all pairings if the maximum number of controllers is paired
+pub const MAX_MESSAGE_LEN: usize = encoded_len(1)
+    + MAX_PAIRINGS
+        * (encoded_len(MAX_PAIRING_ID_LEN) + encoded_len(PUBLIC_KEY_LEN) + encoded_len(1))
+    + (MAX_PAIRINGS - 1) * encoded_len(0);
+
+/// Identifier of a controller, at most 36 bytes long
+pub type PairingId = Vec<u8, U36>;
+
+/// TLV types used in pairing messages, defined in Table 5-6
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TlvType {
+    Method = 0x00,
+    Identifier = 0x01,
+    Salt = 0x02,
+    PublicKey = 0x03,
+    Proof = 0x04,
+    EncryptedData = 0x05,
+    State = 0x06,
+    Error = 0x07,
+    RetryDelay = 0x08,
+    Certificate = 0x09,
+    Signature = 0x0A,
+    Permissions = 0x0B,
+    FragmentData = 0x0C,
+    FragmentLast = 0x0D,
+    Flags = 0x13,
+    Separator = 0xFF,
+}
+
+impl TryFrom<u8> for TlvType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        use TlvType::*;
+
+        let tlv_type = match value {
+            0x00 => Method,
+            0x01 => Identifier,
+            0x02 => Salt,
+            0x03 => PublicKey,
+            0x04 => Proof,
+            0x05 => EncryptedData,
+            0x06 => State,
+            0x07 => Error,
+            0x08 => RetryDelay,
+            0x09 => Certificate,
+            0x0A => Signature,
+            0x0B => Permissions,
+            0x0C => FragmentData,
+            0x0D => FragmentLast,
+            0x13 => Flags,
+            0xFF => Separator,
+            other => return Err(crate::Error::UnknownTlvType(other)),
+        };
+
+        Ok(tlv_type)
+    }
+}
+
+impl From<TlvType> for u8 {
+    fn from(tlv_type: TlvType) -> Self {
+        tlv_type as u8
+    }
+}
+
+/// Pairing methods, defined in Table 5-3
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Method {
+    PairSetup = 0,
+    PairSetupWithAuth = 1,
+    PairVerify = 2,
+    AddPairing = 3,
+    RemovePairing = 4,
+    ListPairings = 5,
+}
+
+impl TryFrom<u8> for Method {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        use Method::*;
+
+        let method = match value {
+            0 => PairSetup,
+            1 => PairSetupWithAuth,
+            2 => PairVerify,
+            3 => AddPairing,
+            4 => RemovePairing,
+            5 => ListPairings,
+            other => return Err(Error::UnknownMethod(other)),
+        };
+
+        Ok(method)
+    }
+}
+
+/// Error codes sent in pairing responses, defined in Table 5-5
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ErrorCode {
+    Unknown = 1,
+    Authentication = 2,
+    Backoff = 3,
+    MaxPeers = 4,
+    MaxTries = 5,
+    Unavailable = 6,
+    Busy = 7,
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> Self {
+        code as u8
+    }
+}
+
+/// Long-term identity of the accessory
+pub struct Accessory<'a> {
+    /// Pairing identifier of the accessory, which is the
+    /// Device ID formatted as `XX:XX:XX:XX:XX:XX`.
+    pub pairing_id: &'a [u8],
+
+    /// Long-term secret key of the accessory
+    pub signing_key: &'a SigningKey,
+}
+
+/// A controller paired with the accessory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pairing {
+    pub identifier: PairingId,
+
+    /// Long-term public key of the controller
+    pub public_key: [u8; PUBLIC_KEY_LEN],
+
+    /// Admin controllers are allowed to manage pairings
+    pub admin: bool,
+}
+
+/// Handle a write to one of the pairing characteristics.
+///
+/// The pairing message is sent in the Value parameter of the HAP request,
+/// which is usually fragmented. It is passed to `handle`, and the message
+/// written by `handle` is added as Value parameter to the response body.
+pub fn handle_write<T, W, F>(body: &[u8], response: &mut W, handle: F) -> Result<T, Error>
+where
+    W: TlvWrite,
+    F: FnOnce(&[u8], &mut TlvWriter) -> Result<T, Error>,
+{
+    let mut request = [0u8; MAX_MESSAGE_LEN];
+    let request = TlvReader::new(body).get_bytes_into(ParamType::Value, &mut request)?;
+
+    let mut buffer = [0u8; MAX_MESSAGE_LEN];
+    let mut writer = TlvWriter::new(&mut buffer);
+
+    let result = handle(request, &mut writer)?;
+
+    response.push_tlv(Tlv::new(ParamType::Value, writer.finish()))?;
+
+    Ok(result)
+}
+
+/// Concatenate multiple slices into a buffer.
+pub(crate) fn concat<'b>(buffer: &'b mut [u8], parts: &[&[u8]]) -> Result<&'b [u8], Error> {
+    let mut len = 0;
+
+    for part in parts {
+        buffer
+            .get_mut(len..len + part.len())
+            .ok_or(Error::InsufficientBuffer)?
+            .copy_from_slice(part);
+
+        len += part.len();
+    }
+
+    Ok(&buffer[..len])
+}
+
+/// Write an error response.
+pub(crate) fn write_error<W: TlvWrite>(
+    response: &mut W,
+    state: u8,
+    error: ErrorCode,
+) -> Result<(), Error> {
+    response.push_tlv(Tlv::new(TlvType::State, state))?;
+    response.push_tlv(Tlv::new(TlvType::Error, u8::from(error)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tlv::{Tlv, TlvReader, SEPARATOR_TYPE};
+
+    #[test]
+    fn convert_tlv_type() {
+        assert_eq!(TlvType::try_from(0x06).unwrap(), TlvType::State);
+        assert_eq!(u8::from(TlvType::Separator), SEPARATOR_TYPE);
+
+        assert!(matches!(
+            TlvType::try_from(0x42),
+            Err(Error::UnknownTlvType(0x42))
+        ));
+    }
+
+    #[test]
+    fn read_and_write_with_tlv_type() {
+        let mut buffer = [0u8; 8];
+
+        let len = Tlv::new(TlvType::State, 2u8)
+            .write_into(&mut buffer)
+            .unwrap();
+        assert_eq!(&buffer[..len], &[0x06, 1, 2]);
+
+        let reader = TlvReader::new(&buffer[..len]);
+        assert_eq!(reader.get_u8(TlvType::State).unwrap(), 2);
+        assert!(matches!(
+            reader.get(TlvType::Error),
+            Err(Error::TlvNotFound(0x07))
+        ));
+    }
+}
@@ -0,0 +1,449 @@
+//! Pairings procedures, defined in sections 5.10 to 5.12 of the HAP specification
+//!
+//! Admin controllers use the Pairings characteristic to add additional
+//! controllers, to remove controllers and to list all pairings of the
+//! accessory. All of these requests are only accepted in a secure session.
+//!
+//! The pairings are kept in a `PairingStore`, which writes every
+//! modification to persistent storage.
+
+use core::{convert::TryInto, slice};
+
+use heapless::{consts::U16, Vec};
+
+use super::{write_error, ErrorCode, Method, Pairing, PairingId, TlvType, PUBLIC_KEY_LEN};
+use crate::{
+    store::PairingStore,
+    tlv::{Tlv, TlvReader, TlvWrite},
+    Error,
+};
+
+/// Maximum number of controllers which can be paired with the accessory
+pub const MAX_PAIRINGS: usize = 16;
+
+/// Value of the Permissions item for admin controllers
+const PERMISSION_ADMIN: u8 = 0x01;
+
+/// Value of the Permissions item for regular controllers
+const PERMISSION_USER: u8 = 0x00;
+
+/// All controllers paired with the accessory
+#[derive(Debug, Default)]
+pub struct Pairings {
+    pairings: Vec<Pairing, U16>,
+}
+
+impl Pairings {
+    pub fn new() -> Self {
+        Pairings {
+            pairings: Vec::new(),
+        }
+    }
+
+    /// Check if at least one controller is paired.
+    pub fn is_paired(&self) -> bool {
+        !self.pairings.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairings.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Pairing> {
+        self.pairings.iter()
+    }
+
+    /// Find the pairing of the controller with the given identifier.
+    pub fn find(&self, identifier: &[u8]) -> Option<&Pairing> {
+        self.pairings
+            .iter()
+            .find(|pairing| &pairing.identifier[..] == identifier)
+    }
+
+    /// Add a new pairing, or replace the existing pairing with the same identifier.
+    ///
+    /// If the maximum number of pairings has been reached,
+    /// the new pairing is returned as error.
+    pub fn insert(&mut self, pairing: Pairing) -> Result<(), Pairing> {
+        match self
+            .pairings
+            .iter_mut()
+            .find(|existing| existing.identifier == pairing.identifier)
+        {
+            Some(existing) => {
+                *existing = pairing;
+                Ok(())
+            }
+            None => self.pairings.push(pairing),
+        }
+    }
+
+    /// Remove the pairing of the controller with the given identifier.
+    ///
+    /// If the last admin controller is removed, all pairings are removed,
+    /// and the accessory is unpaired.
+    pub fn remove(&mut self, identifier: &[u8]) -> Option<Pairing> {
+        let index = self
+            .pairings
+            .iter()
+            .position(|pairing| &pairing.identifier[..] == identifier)?;
+
+        let pairing = self.pairings.swap_remove(index);
+
+        if !self.pairings.iter().any(|pairing| pairing.admin) {
+            self.clear();
+        }
+
+        Some(pairing)
+    }
+
+    /// Remove all pairings.
+    pub fn clear(&mut self) {
+        while self.pairings.pop().is_some() {}
+    }
+}
+
+/// Handle a request to the Pairings characteristic, and write the response.
+///
+/// `controller` is the identifier of the controller of the secure session,
+/// which has to be an admin. Afterwards, sessions of controllers which
+/// are no longer paired have to be closed.
+pub fn handle_pairings<S: PairingStore, W: TlvWrite>(
+    store: &mut S,
+    request: &[u8],
+    controller: &[u8],
+    response: &mut W,
+) -> Result<(), Error> {
+    let request = TlvReader::new(request);
+
+    if request.get_u8(TlvType::State)? != 1 {
+        return write_error(response, 2, ErrorCode::Unknown);
+    }
+
+    let method = request.get_u8(TlvType::Method)?.try_into();
+
+    // Only admin controllers are allowed to manage pairings
+    if !store
+        .find_pairing(controller)
+        .is_some_and(|pairing| pairing.admin)
+    {
+        return write_error(response, 2, ErrorCode::Authentication);
+    }
+
+    match method {
+        Ok(Method::AddPairing) => handle_add(store, request, response),
+        Ok(Method::RemovePairing) => handle_remove(store, request, response),
+        Ok(Method::ListPairings) => handle_list(store, response),
+        _ => write_error(response, 2, ErrorCode::Unknown),
+    }
+}
+
+fn handle_add<S: PairingStore, W: TlvWrite>(
+    store: &mut S,
+    request: TlvReader,
+    response: &mut W,
+) -> Result<(), Error> {
+    let mut identifier = PairingId::new();
+    identifier
+        .extend_from_slice(request.get_bytes(TlvType::Identifier)?)
+        .map_err(|_| Error::InvalidTlvValue(TlvType::Identifier as u8))?;
+
+    let public_key: [u8; PUBLIC_KEY_LEN] = request
+        .get_bytes(TlvType::PublicKey)?
+        .try_into()
+        .map_err(|_| Error::InvalidTlvValue(TlvType::PublicKey as u8))?;
+
+    let admin = match request.get_u8(TlvType::Permissions)? {
+        PERMISSION_ADMIN => true,
+        PERMISSION_USER => false,
+        _ => return Err(Error::InvalidTlvValue(TlvType::Permissions as u8)),
+    };
+
+    // An existing pairing only has its permissions updated
+    match store.find_pairing(&identifier) {
+        Some(existing) if existing.public_key != public_key => {
+            return write_error(response, 2, ErrorCode::Unknown)
+        }
+        None if store.pairings().len() >= MAX_PAIRINGS => {
+            return write_error(response, 2, ErrorCode::MaxPeers)
+        }
+        _ => {}
+    }
+
+    store.save_pairing(Pairing {
+        identifier,
+        public_key,
+        admin,
+    })?;
+
+    response.push_tlv(Tlv::new(TlvType::State, 2u8))
+}
+
+fn handle_remove<S: PairingStore, W: TlvWrite>(
+    store: &mut S,
+    request: TlvReader,
+    response: &mut W,
+) -> Result<(), Error> {
+    let identifier = request.get_bytes(TlvType::Identifier)?;
+
+    // Removing a controller which isn't paired is not an error
+    store.remove_pairing(identifier)?;
+
+    response.push_tlv(Tlv::new(TlvType::State, 2u8))
+}
+
+fn handle_list<S: PairingStore, W: TlvWrite>(store: &S, response: &mut W) -> Result<(), Error> {
+    response.push_tlv(Tlv::new(TlvType::State, 2u8))?;
+
+    response.push_tlv_list(store.pairings().iter(), |response, pairing| {
+        let permissions = if pairing.admin {
+            PERMISSION_ADMIN
+        } else {
+            PERMISSION_USER
+        };
+
+        response.push_tlv(Tlv::new(TlvType::Identifier, &pairing.identifier[..]))?;
+        response.push_tlv(Tlv::new(TlvType::PublicKey, &pairing.public_key[..]))?;
+        response.push_tlv(Tlv::new(TlvType::Permissions, permissions))
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::{
+        pairing::{handle_write, MAX_MESSAGE_LEN, MAX_PAIRING_ID_LEN},
+        param::ParamType,
+        store::MemoryStore,
+        tlv::TlvVec,
+    };
+    use heapless::consts::{U2048, U512};
+
+    type Buffer = TlvVec<U512>;
+
+    /// Pairing with the given identifier, and a public key filled with `key`
+    pub(crate) fn pairing(identifier: &[u8], key: u8, admin: bool) -> Pairing {
+        let mut pairing_id = PairingId::new();
+        pairing_id.extend_from_slice(identifier).unwrap();
+
+        Pairing {
+            identifier: pairing_id,
+            public_key: [key; PUBLIC_KEY_LEN],
+            admin,
+        }
+    }
+
+    fn paired() -> MemoryStore {
+        let mut store = MemoryStore::new();
+        store.save_pairing(pairing(b"admin", 1, true)).unwrap();
+        store.save_pairing(pairing(b"user", 2, false)).unwrap();
+
+        store
+    }
+
+    fn add_request(identifier: &[u8], key: u8, permissions: u8) -> Buffer {
+        let mut request = Buffer::new();
+
+        request
+            .push(Tlv::new(TlvType::State, 1u8))
+            .and_then(|r| r.push(Tlv::new(TlvType::Method, Method::AddPairing as u8)))
+            .and_then(|r| r.push(Tlv::new(TlvType::Identifier, identifier)))
+            .and_then(|r| r.push(Tlv::new(TlvType::PublicKey, &[key; PUBLIC_KEY_LEN][..])))
+            .and_then(|r| r.push(Tlv::new(TlvType::Permissions, permissions)))
+            .unwrap();
+
+        request
+    }
+
+    fn remove_request(identifier: &[u8]) -> Buffer {
+        let mut request = Buffer::new();
+
+        request
+            .push(Tlv::new(TlvType::State, 1u8))
+            .and_then(|r| r.push(Tlv::new(TlvType::Method, Method::RemovePairing as u8)))
+            .and_then(|r| r.push(Tlv::new(TlvType::Identifier, identifier)))
+            .unwrap();
+
+        request
+    }
+
+    fn handle(store: &mut MemoryStore, request: &[u8], controller: &[u8]) -> Buffer {
+        let mut response = Buffer::new();
+
+        handle_pairings(store, request, controller, &mut response).unwrap();
+
+        response
+    }
+
+    fn error(response: &[u8]) -> Option<u8> {
+        TlvReader::new(response)
+            .find(TlvType::Error)
+            .unwrap()
+            .map(|item| item.as_u8().unwrap())
+    }
+
+    #[test]
+    fn add_pairing() {
+        let mut store = paired();
+
+        let response = handle(&mut store, &add_request(b"new", 3, 0), b"admin");
+
+        assert_eq!(TlvReader::new(&response).get_u8(TlvType::State).unwrap(), 2);
+        assert_eq!(error(&response), None);
+        assert_eq!(store.find_pairing(b"new"), Some(&pairing(b"new", 3, false)));
+    }
+
+    #[test]
+    fn update_permissions() {
+        let mut store = paired();
+
+        let response = handle(&mut store, &add_request(b"user", 2, 1), b"admin");
+
+        assert_eq!(error(&response), None);
+        assert_eq!(store.pairings().len(), 2);
+        assert!(store.find_pairing(b"user").unwrap().admin);
+    }
+
+    #[test]
+    fn add_pairing_with_different_key() {
+        let mut store = paired();
+
+        let response = handle(&mut store, &add_request(b"user", 9, 1), b"admin");
+
+        assert_eq!(error(&response), Some(ErrorCode::Unknown as u8));
+        assert_eq!(
+            store.find_pairing(b"user"),
+            Some(&pairing(b"user", 2, false))
+        );
+    }
+
+    #[test]
+    fn add_too_many_pairings() {
+        let mut store = paired();
+
+        for i in 0..(MAX_PAIRINGS - 2) as u8 {
+            store.save_pairing(pairing(&[b'a' + i], i, false)).unwrap();
+        }
+
+        let response = handle(&mut store, &add_request(b"new", 3, 0), b"admin");
+
+        assert_eq!(error(&response), Some(ErrorCode::MaxPeers as u8));
+    }
+
+    #[test]
+    fn requires_admin() {
+        let mut store = paired();
+
+        for (request, controller) in [
+            (add_request(b"new", 3, 0), &b"user"[..]),
+            (remove_request(b"admin"), &b"user"[..]),
+            (add_request(b"new", 3, 0), &b"unknown"[..]),
+        ]
+        .iter()
+        {
+            let response = handle(&mut store, request, controller);
+
+            assert_eq!(error(&response), Some(ErrorCode::Authentication as u8));
+        }
+
+        assert_eq!(store.pairings().len(), 2);
+    }
+
+    #[test]
+    fn remove_pairing() {
+        let mut store = paired();
+
+        let response = handle(&mut store, &remove_request(b"user"), b"admin");
+
+        assert_eq!(error(&response), None);
+        assert_eq!(store.find_pairing(b"user"), None);
+        assert!(store.is_paired());
+
+        // Removing it again succeeds
+        let response = handle(&mut store, &remove_request(b"user"), b"admin");
+
+        assert_eq!(error(&response), None);
+    }
+
+    #[test]
+    fn remove_last_admin() {
+        let mut store = paired();
+
+        let response = handle(&mut store, &remove_request(b"admin"), b"admin");
+
+        assert_eq!(error(&response), None);
+        assert!(!store.is_paired());
+    }
+
+    #[test]
+    fn list_pairings() {
+        let mut store = paired();
+
+        let mut request = Buffer::new();
+        request
+            .push(Tlv::new(TlvType::State, 1u8))
+            .and_then(|r| r.push(Tlv::new(TlvType::Method, Method::ListPairings as u8)))
+            .unwrap();
+
+        let response = handle(&mut store, &request, b"admin");
+
+        let response = TlvReader::new(&response);
+        assert_eq!(response.get_u8(TlvType::State).unwrap(), 2);
+
+        let mut groups = response.groups();
+
+        let first = groups.next().unwrap().unwrap();
+        assert_eq!(first.get_bytes(TlvType::Identifier).unwrap(), b"admin");
+        assert_eq!(first.get_bytes(TlvType::PublicKey).unwrap(), &[1; 32]);
+        assert_eq!(first.get_u8(TlvType::Permissions).unwrap(), 1);
+
+        let second = groups.next().unwrap().unwrap();
+        assert_eq!(second.get_bytes(TlvType::Identifier).unwrap(), b"user");
+        assert_eq!(second.get_u8(TlvType::Permissions).unwrap(), 0);
+
+        assert!(groups.next().is_none());
+    }
+
+    #[test]
+    fn list_maximum_number_of_pairings() {
+        let mut store = MemoryStore::new();
+
+        for i in 0..MAX_PAIRINGS {
+            let mut identifier = [b'0'; MAX_PAIRING_ID_LEN];
+            identifier[0] = b'a' + i as u8;
+
+            store.save_pairing(pairing(&identifier, 1, true)).unwrap();
+        }
+
+        let mut request = Buffer::new();
+        request
+            .push(Tlv::new(TlvType::State, 1u8))
+            .and_then(|r| r.push(Tlv::new(TlvType::Method, Method::ListPairings as u8)))
+            .unwrap();
+
+        let mut body = TlvVec::<U2048>::new();
+        body.push(Tlv::new(ParamType::Value, &request[..])).unwrap();
+
+        let mut response = TlvVec::<U2048>::new();
+
+        let mut controller = [b'0'; MAX_PAIRING_ID_LEN];
+        controller[0] = b'a';
+
+        handle_write(&body, &mut response, |request, writer| {
+            handle_pairings(&mut store, request, &controller, writer)
+        })
+        .unwrap();
+
+        let mut message = [0u8; MAX_MESSAGE_LEN];
+        let message = TlvReader::new(&response)
+            .get_bytes_into(ParamType::Value, &mut message)
+            .unwrap();
+
+        assert_eq!(message.len(), MAX_MESSAGE_LEN);
+        assert_eq!(TlvReader::new(message).groups().count(), MAX_PAIRINGS);
+    }
+}
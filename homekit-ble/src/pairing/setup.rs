@@ -0,0 +1,1061 @@
+//! Pair Setup, defined in section 5.6 of the HAP specification
+//!
+//! Pair Setup establishes the first pairing between a controller and the
+//! accessory, using the setup code. It consists of three request/response
+//! exchanges:
+//!
+//! - M1/M2: The accessory starts the SRP key exchange.
+//! - M3/M4: The controller proves that it knows the setup code.
+//! - M5/M6: Controller and accessory exchange their long-term public keys,
+//!   encrypted with a key derived from the SRP session key.
+
+use core::{convert::TryInto, time::Duration};
+
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use rand_core::{CryptoRng, RngCore};
+
+use super::{
+    concat,
+    srp::{Server, Verifier, KEY_LEN, PROOF_LEN, SECRET_LEN},
+    write_error, Accessory, ErrorCode, Method, Pairing, PairingId, TlvType, MAX_PAIRING_ID_LEN,
+    PUBLIC_KEY_LEN, SIGNATURE_LEN,
+};
+use crate::{
+    crypto,
+    store::PairingStore,
+    tlv::{Tlv, TlvReader, TlvWrite, TlvWriter},
+    Error,
+};
+
+/// Number of failed attempts after which Pair Setup is refused,
+/// until the counter is reset by a factory reset.
+pub const MAX_SETUP_ATTEMPTS: u8 = 100;
+
+/// Longest delay before a new attempt is accepted after failed attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Maximum length of the encrypted data in M5 and M6
+const MAX_ENCRYPTED_LEN: usize = 256;
+
+/// Maximum length of the data signed by the controller or the accessory
+const MAX_SIGNED_LEN: usize = crypto::KEY_LEN + MAX_PAIRING_ID_LEN + PUBLIC_KEY_LEN;
+
+/// State of the pair setup procedure
+// The key exchange state is large, but there is no allocator to box it.
+#[allow(clippy::large_enum_variant)]
+enum State {
+    Idle,
+
+    /// M2 has been sent, waiting for the proof of the controller in M3
+    KeyExchange(Server),
+
+    /// M4 has been sent, waiting for the long-term key of the controller in M5
+    Verified {
+        session_key: [u8; PROOF_LEN],
+    },
+}
+
+/// A request to the Pair Setup characteristic
+#[derive(Debug, Clone, Copy)]
+pub struct SetupRequest<'a> {
+    /// TLV8 encoded message of the controller
+    pub message: &'a [u8],
+
+    /// Connection on which the message was received
+    pub connection: u16,
+
+    /// Time since boot, used to delay new attempts after a failed one
+    pub time: Duration,
+}
+
+/// State machine for Pair Setup
+pub struct PairSetup {
+    verifier: Verifier,
+
+    state: State,
+
+    /// Connection of the pair setup in progress
+    connection: u16,
+
+    /// Time of the last failed attempt since boot
+    last_failure: Option<Duration>,
+}
+
+impl PairSetup {
+    /// Create the state machine for the setup code with the given verifier.
+    pub fn new(verifier: Verifier) -> Self {
+        PairSetup {
+            verifier,
+            state: State::Idle,
+            connection: 0,
+            last_failure: None,
+        }
+    }
+
+    /// Check if a pair setup procedure has been started, but not completed.
+    pub fn is_in_progress(&self) -> bool {
+        !matches!(self.state, State::Idle)
+    }
+
+    /// Abort a pair setup procedure in progress.
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+    }
+
+    /// Abort the pair setup procedure in progress, if it was started on the connection.
+    pub fn connection_closed(&mut self, connection: u16) {
+        if self.connection == connection {
+            self.reset();
+        }
+    }
+
+    /// Handle a request from the controller, and write the response.
+    ///
+    /// A new pair setup is rejected if the accessory is already paired, if
+    /// another connection has one in progress, after `MAX_SETUP_ATTEMPTS`
+    /// failed attempts, which are counted in the store, or until the retry
+    /// delay after a failed attempt has passed. Once the controller has been
+    /// verified in M5, the new pairing is returned, and has to be stored by
+    /// the caller.
+    ///
+    /// Unexpected and malformed requests don't affect a pair setup in progress.
+    /// Failed verification is reported to the controller in the response,
+    /// an error is only returned if the request is malformed, the response
+    /// can't be written, or the store can't be updated.
+    pub fn handle<S: PairingStore, W: TlvWrite, R: RngCore + CryptoRng>(
+        &mut self,
+        request: SetupRequest,
+        accessory: &Accessory,
+        store: &mut S,
+        rng: &mut R,
+        response: &mut W,
+    ) -> Result<Option<Pairing>, Error> {
+        let message = TlvReader::new(request.message);
+
+        let state = message.get_u8(TlvType::State)?;
+
+        // Only the connection which started the pair setup can continue it
+        let owner = self.is_in_progress() && request.connection == self.connection;
+
+        match (state, &self.state) {
+            (1, _) => self
+                .handle_m1(message, request, store, rng, response)
+                .map(|_| None),
+            (3, State::KeyExchange(_)) if owner => self
+                .handle_m3(message, request.time, store, response)
+                .map(|_| None),
+            (5, State::Verified { .. }) if owner => self.handle_m5(message, accessory, response),
+            _ => write_error(response, state.wrapping_add(1), ErrorCode::Unknown).map(|_| None),
+        }
+    }
+
+    /// Handle the start request, and send the salt and public key of the accessory.
+    fn handle_m1<S: PairingStore, W: TlvWrite, R: RngCore + CryptoRng>(
+        &mut self,
+        message: TlvReader,
+        request: SetupRequest,
+        store: &S,
+        rng: &mut R,
+        response: &mut W,
+    ) -> Result<(), Error> {
+        // The pair setup in progress is not aborted, it is only
+        // reset when its connection is closed.
+        if self.is_in_progress() && request.connection != self.connection {
+            return write_error(response, 2, ErrorCode::Busy);
+        }
+
+        // A retried M1 restarts the pair setup
+        self.state = State::Idle;
+
+        let method = message.get_u8(TlvType::Method)?;
+
+        if method != Method::PairSetup as u8 && method != Method::PairSetupWithAuth as u8 {
+            return write_error(response, 2, ErrorCode::Unknown);
+        }
+
+        if store.is_paired() {
+            return write_error(response, 2, ErrorCode::Unavailable);
+        }
+
+        let attempts = store.failed_setup_attempts();
+
+        if attempts >= MAX_SETUP_ATTEMPTS {
+            return write_error(response, 2, ErrorCode::MaxTries);
+        }
+
+        // After a reboot, the delay is counted from boot
+        let elapsed = request
+            .time
+            .checked_sub(self.last_failure.unwrap_or_default())
+            .unwrap_or_default();
+
+        if let Some(remaining) = retry_delay(attempts).checked_sub(elapsed) {
+            if remaining > Duration::from_secs(0) {
+                // Rounded up, so that the next attempt isn't too early
+                let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+
+                write_error(response, 2, ErrorCode::Backoff)?;
+                return response.push_tlv(Tlv::new(TlvType::RetryDelay, seconds as u16));
+            }
+        }
+
+        let mut secret = [0u8; SECRET_LEN];
+        rng.fill_bytes(&mut secret);
+
+        let server = Server::new(&self.verifier, &secret);
+
+        response.push_tlv(Tlv::new(TlvType::State, 2u8))?;
+        response.push_tlv(Tlv::new(TlvType::PublicKey, &server.public_key()[..]))?;
+        response.push_tlv(Tlv::new(TlvType::Salt, &server.salt()[..]))?;
+
+        self.state = State::KeyExchange(server);
+        self.connection = request.connection;
+
+        Ok(())
+    }
+
+    /// Handle the verify request, and send the proof of the accessory.
+    fn handle_m3<S: PairingStore, W: TlvWrite>(
+        &mut self,
+        request: TlvReader,
+        time: Duration,
+        store: &mut S,
+        response: &mut W,
+    ) -> Result<(), Error> {
+        let server = match &self.state {
+            State::KeyExchange(server) => server,
+            _ => unreachable!("M3 is only handled during the key exchange"),
+        };
+
+        let mut public_key = [0u8; KEY_LEN];
+
+        let public_key = request
+            .get(TlvType::PublicKey)?
+            .copy_into(&mut public_key)
+            .map_err(|_| Error::InvalidTlvValue(TlvType::PublicKey as u8))?;
+
+        let proof = request.get_bytes(TlvType::Proof)?;
+
+        let result = server.verify_client(public_key, proof);
+
+        // The key exchange ends with the first proof of the controller
+        self.state = State::Idle;
+
+        match result {
+            Ok((session_key, proof)) => {
+                // The setup code is correct, so the failed attempts are forgotten
+                if store.failed_setup_attempts() != 0 {
+                    store.set_failed_setup_attempts(0)?;
+                }
+
+                response.push_tlv(Tlv::new(TlvType::State, 4u8))?;
+                response.push_tlv(Tlv::new(TlvType::Proof, &proof[..]))?;
+
+                self.state = State::Verified { session_key };
+
+                Ok(())
+            }
+            Err(Error::Authentication) => {
+                self.last_failure = Some(time);
+
+                // The attempt is stored before the controller learns that it failed
+                let attempts = store.failed_setup_attempts().saturating_add(1);
+                store.set_failed_setup_attempts(attempts)?;
+
+                write_error(response, 4, ErrorCode::Authentication)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Handle the exchange request, verifying the controller and
+    /// sending the long-term public key of the accessory.
+    fn handle_m5<W: TlvWrite>(
+        &mut self,
+        request: TlvReader,
+        accessory: &Accessory,
+        response: &mut W,
+    ) -> Result<Option<Pairing>, Error> {
+        let session_key = match &self.state {
+            State::Verified { session_key } => *session_key,
+            _ => unreachable!("M5 is only handled after the key exchange"),
+        };
+
+        let encryption_key = crypto::derive_key(
+            &session_key,
+            b"Pair-Setup-Encrypt-Salt",
+            b"Pair-Setup-Encrypt-Info",
+        );
+
+        let mut buffer = [0u8; MAX_ENCRYPTED_LEN];
+
+        let encrypted_len = request
+            .get(TlvType::EncryptedData)?
+            .copy_into(&mut buffer)
+            .map_err(|_| Error::InvalidTlvValue(TlvType::EncryptedData as u8))?
+            .len();
+
+        // The exchange ends with the first encrypted data of the controller
+        self.state = State::Idle;
+
+        let pairing = match crypto::decrypt(
+            &encryption_key,
+            b"PS-Msg05",
+            &[],
+            &mut buffer[..encrypted_len],
+        ) {
+            Ok(data) => verify_controller(&session_key, TlvReader::new(data)),
+            Err(e) => Err(e),
+        };
+
+        let pairing = match pairing {
+            Ok(pairing) => pairing,
+            Err(Error::Authentication) => {
+                write_error(response, 6, ErrorCode::Authentication)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Sign the long-term public key of the accessory
+        let accessory_x = crypto::derive_key(
+            &session_key,
+            b"Pair-Setup-Accessory-Sign-Salt",
+            b"Pair-Setup-Accessory-Sign-Info",
+        );
+
+        let public_key = accessory.signing_key.verifying_key().to_bytes();
+
+        let mut accessory_info = [0u8; MAX_SIGNED_LEN];
+        let accessory_info = concat(
+            &mut accessory_info,
+            &[&accessory_x, accessory.pairing_id, &public_key],
+        )?;
+
+        let signature = accessory.signing_key.sign(accessory_info).to_bytes();
+
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        writer
+            .push(Tlv::new(TlvType::Identifier, accessory.pairing_id))?
+            .push(Tlv::new(TlvType::PublicKey, &public_key[..]))?
+            .push(Tlv::new(TlvType::Signature, &signature[..]))?;
+
+        let len = writer.len();
+
+        let encrypted_len = crypto::encrypt(&encryption_key, b"PS-Msg06", &[], &mut buffer, len)?;
+
+        response.push_tlv(Tlv::new(TlvType::State, 6u8))?;
+        response.push_tlv(Tlv::new(TlvType::EncryptedData, &buffer[..encrypted_len]))?;
+
+        Ok(Some(pairing))
+    }
+}
+
+/// Delay before a new attempt after failed attempts, which doubles with every failed attempt.
+fn retry_delay(failed_attempts: u8) -> Duration {
+    match failed_attempts {
+        0 => Duration::from_secs(0),
+        n => Duration::from_secs(1 << (n - 1).min(12)).min(MAX_RETRY_DELAY),
+    }
+}
+
+/// Verify the signature of the controller over its long-term public key.
+fn verify_controller(session_key: &[u8; PROOF_LEN], data: TlvReader) -> Result<Pairing, Error> {
+    let identifier = data.get_bytes(TlvType::Identifier)?;
+
+    let public_key: [u8; PUBLIC_KEY_LEN] = data
+        .get_bytes(TlvType::PublicKey)?
+        .try_into()
+        .map_err(|_| Error::InvalidTlvValue(TlvType::PublicKey as u8))?;
+
+    let signature: [u8; SIGNATURE_LEN] = data
+        .get_bytes(TlvType::Signature)?
+        .try_into()
+        .map_err(|_| Error::InvalidTlvValue(TlvType::Signature as u8))?;
+
+    let controller_x = crypto::derive_key(
+        session_key,
+        b"Pair-Setup-Controller-Sign-Salt",
+        b"Pair-Setup-Controller-Sign-Info",
+    );
+
+    let mut controller_info = [0u8; MAX_SIGNED_LEN];
+    let controller_info = concat(
+        &mut controller_info,
+        &[&controller_x, identifier, &public_key],
+    )
+    .map_err(|_| Error::InvalidTlvValue(TlvType::Identifier as u8))?;
+
+    VerifyingKey::from_bytes(&public_key)
+        .and_then(|key| key.verify_strict(controller_info, &Signature::from_bytes(&signature)))
+        .map_err(|_| Error::Authentication)?;
+
+    let mut pairing_id = PairingId::new();
+    pairing_id
+        .extend_from_slice(identifier)
+        .map_err(|_| Error::InvalidTlvValue(TlvType::Identifier as u8))?;
+
+    Ok(Pairing {
+        identifier: pairing_id,
+        public_key,
+        admin: true,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::{
+        fragment::RequestReassembler, pairing::handle_write, pairing::srp::client::Client,
+        param::ParamType, store::MemoryStore, HapRequest, InstanceId, OpCode,
+    };
+    use ed25519_dalek::SigningKey;
+    use heapless::consts::U1024;
+
+    pub(crate) const SETUP_CODE: &[u8] = b"123-45-678";
+    pub(crate) const ACCESSORY_ID: &[u8] = b"44:55:66:44:55:66";
+    pub(crate) const CONTROLLER_ID: &[u8] = b"8D2C3A36-84F6-4B2A-9E8B-8D9A2D4F6B2C";
+
+    type Response = crate::tlv::TlvVec<U1024>;
+
+    /// Deterministic random number generator, only to be used in tests
+    pub(crate) struct TestRng(pub u64);
+
+    impl RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for TestRng {}
+
+    /// Software controller, which performs pair setup with the accessory
+    pub(crate) struct Controller {
+        pub signing_key: SigningKey,
+
+        srp: Client,
+
+        session_key: [u8; PROOF_LEN],
+
+        proof: [u8; PROOF_LEN],
+    }
+
+    impl Controller {
+        pub(crate) fn new() -> Self {
+            Controller {
+                signing_key: SigningKey::from_bytes(&[0x33; 32]),
+                srp: Client::new(&[0x17; SECRET_LEN]),
+                session_key: [0; PROOF_LEN],
+                proof: [0; PROOF_LEN],
+            }
+        }
+
+        pub(crate) fn m1(&self) -> Response {
+            let mut request = Response::new();
+
+            request
+                .push(Tlv::new(TlvType::State, 1u8))
+                .and_then(|r| r.push(Tlv::new(TlvType::Method, Method::PairSetup as u8)))
+                .unwrap();
+
+            request
+        }
+
+        pub(crate) fn m3(&mut self, m2: &[u8], setup_code: &[u8]) -> Response {
+            let m2 = TlvReader::new(m2);
+
+            assert_eq!(m2.get_u8(TlvType::State).unwrap(), 2);
+
+            let mut salt = [0u8; 16];
+            m2.get_bytes_into(TlvType::Salt, &mut salt).unwrap();
+
+            let mut server_public_key = [0u8; KEY_LEN];
+            m2.get_bytes_into(TlvType::PublicKey, &mut server_public_key)
+                .unwrap();
+
+            let (session_key, proof) = self.srp.process(&salt, setup_code, &server_public_key);
+
+            self.session_key = session_key;
+            self.proof = proof;
+
+            let mut request = Response::new();
+
+            request
+                .push(Tlv::new(TlvType::State, 3u8))
+                .and_then(|r| r.push(Tlv::new(TlvType::PublicKey, &self.srp.public_key()[..])))
+                .and_then(|r| r.push(Tlv::new(TlvType::Proof, &proof[..])))
+                .unwrap();
+
+            request
+        }
+
+        pub(crate) fn m5(&self, m4: &[u8]) -> Response {
+            let m4 = TlvReader::new(m4);
+
+            assert_eq!(m4.get_u8(TlvType::State).unwrap(), 4);
+            assert_eq!(
+                m4.get_bytes(TlvType::Proof).unwrap(),
+                &self.srp.server_proof(&self.proof, &self.session_key)[..]
+            );
+
+            let controller_x = crypto::derive_key(
+                &self.session_key,
+                b"Pair-Setup-Controller-Sign-Salt",
+                b"Pair-Setup-Controller-Sign-Info",
+            );
+
+            let public_key = self.signing_key.verifying_key().to_bytes();
+
+            let mut info = [0u8; MAX_SIGNED_LEN];
+            let info = concat(&mut info, &[&controller_x, CONTROLLER_ID, &public_key]).unwrap();
+
+            let signature = self.signing_key.sign(info).to_bytes();
+
+            let mut buffer = [0u8; MAX_ENCRYPTED_LEN];
+            let mut writer = TlvWriter::new(&mut buffer);
+
+            writer
+                .push(Tlv::new(TlvType::Identifier, CONTROLLER_ID))
+                .and_then(|w| w.push(Tlv::new(TlvType::PublicKey, &public_key[..])))
+                .and_then(|w| w.push(Tlv::new(TlvType::Signature, &signature[..])))
+                .unwrap();
+
+            let len = writer.len();
+            let len = crypto::encrypt(&self.encryption_key(), b"PS-Msg05", &[], &mut buffer, len)
+                .unwrap();
+
+            let mut request = Response::new();
+
+            request
+                .push(Tlv::new(TlvType::State, 5u8))
+                .and_then(|r| r.push(Tlv::new(TlvType::EncryptedData, &buffer[..len])))
+                .unwrap();
+
+            request
+        }
+
+        /// Verify M6, returning the long-term public key of the accessory.
+        pub(crate) fn verify_m6(&self, m6: &[u8]) -> [u8; PUBLIC_KEY_LEN] {
+            let m6 = TlvReader::new(m6);
+
+            assert_eq!(m6.get_u8(TlvType::State).unwrap(), 6);
+
+            let mut buffer = [0u8; MAX_ENCRYPTED_LEN];
+            let encrypted = m6
+                .get_bytes_into(TlvType::EncryptedData, &mut buffer)
+                .unwrap()
+                .len();
+
+            let data = crypto::decrypt(
+                &self.encryption_key(),
+                b"PS-Msg06",
+                &[],
+                &mut buffer[..encrypted],
+            )
+            .unwrap();
+
+            let data = TlvReader::new(data);
+
+            let identifier = data.get_bytes(TlvType::Identifier).unwrap();
+            let public_key: [u8; PUBLIC_KEY_LEN] = data
+                .get_bytes(TlvType::PublicKey)
+                .unwrap()
+                .try_into()
+                .unwrap();
+            let signature: [u8; SIGNATURE_LEN] = data
+                .get_bytes(TlvType::Signature)
+                .unwrap()
+                .try_into()
+                .unwrap();
+
+            let accessory_x = crypto::derive_key(
+                &self.session_key,
+                b"Pair-Setup-Accessory-Sign-Salt",
+                b"Pair-Setup-Accessory-Sign-Info",
+            );
+
+            let mut info = [0u8; MAX_SIGNED_LEN];
+            let info = concat(&mut info, &[&accessory_x, identifier, &public_key]).unwrap();
+
+            VerifyingKey::from_bytes(&public_key)
+                .unwrap()
+                .verify_strict(info, &Signature::from_bytes(&signature))
+                .expect("Accessory signature should be valid");
+
+            public_key
+        }
+
+        fn encryption_key(&self) -> [u8; crypto::KEY_LEN] {
+            crypto::derive_key(
+                &self.session_key,
+                b"Pair-Setup-Encrypt-Salt",
+                b"Pair-Setup-Encrypt-Info",
+            )
+        }
+    }
+
+    pub(crate) fn accessory_key() -> SigningKey {
+        SigningKey::from_bytes(&[0x55; 32])
+    }
+
+    fn pair_setup() -> PairSetup {
+        PairSetup::new(Verifier::new([0x11; 16], SETUP_CODE))
+    }
+
+    /// Request on the first connection, the given number of seconds after boot
+    fn request(message: &[u8], seconds: u64) -> SetupRequest<'_> {
+        SetupRequest {
+            message,
+            connection: 1,
+            time: Duration::from_secs(seconds),
+        }
+    }
+
+    fn handle(
+        pair_setup: &mut PairSetup,
+        message: &[u8],
+        store: &mut MemoryStore,
+    ) -> (Option<Pairing>, Response) {
+        handle_request(pair_setup, request(message, 0), store)
+    }
+
+    fn handle_request(
+        pair_setup: &mut PairSetup,
+        request: SetupRequest,
+        store: &mut MemoryStore,
+    ) -> (Option<Pairing>, Response) {
+        let signing_key = accessory_key();
+
+        let accessory = Accessory {
+            pairing_id: ACCESSORY_ID,
+            signing_key: &signing_key,
+        };
+
+        let mut response = Response::new();
+
+        let pairing = pair_setup
+            .handle(request, &accessory, store, &mut TestRng(42), &mut response)
+            .unwrap();
+
+        (pairing, response)
+    }
+
+    #[test]
+    fn successful_pair_setup() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let mut controller = Controller::new();
+
+        let (pairing, m2) = handle(&mut pair_setup, &controller.m1(), &mut store);
+        assert!(pairing.is_none());
+        assert!(pair_setup.is_in_progress());
+
+        let m3 = controller.m3(&m2, SETUP_CODE);
+        let (pairing, m4) = handle(&mut pair_setup, &m3, &mut store);
+        assert!(pairing.is_none());
+
+        let m5 = controller.m5(&m4);
+        let (pairing, m6) = handle(&mut pair_setup, &m5, &mut store);
+
+        let pairing = pairing.expect("Controller should be paired");
+        assert_eq!(&pairing.identifier[..], CONTROLLER_ID);
+        assert_eq!(
+            pairing.public_key,
+            controller.signing_key.verifying_key().to_bytes()
+        );
+        assert!(pairing.admin);
+
+        assert_eq!(
+            controller.verify_m6(&m6),
+            accessory_key().verifying_key().to_bytes()
+        );
+        assert!(!pair_setup.is_in_progress());
+    }
+
+    #[test]
+    fn wrong_setup_code() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let mut controller = Controller::new();
+
+        let (_, m2) = handle(&mut pair_setup, &controller.m1(), &mut store);
+
+        let m3 = controller.m3(&m2, b"111-22-333");
+        let (_, m4) = handle(&mut pair_setup, &m3, &mut store);
+
+        let m4 = TlvReader::new(&m4);
+        assert_eq!(m4.get_u8(TlvType::State).unwrap(), 4);
+        assert_eq!(
+            m4.get_u8(TlvType::Error).unwrap(),
+            ErrorCode::Authentication as u8
+        );
+        assert!(!pair_setup.is_in_progress());
+        assert_eq!(store.failed_setup_attempts(), 1);
+
+        // The counter is reset once the correct setup code is used
+        let mut controller = Controller::new();
+
+        let (_, m2) = handle_request(&mut pair_setup, request(&controller.m1(), 1), &mut store);
+        let (_, m4) = handle(&mut pair_setup, &controller.m3(&m2, SETUP_CODE), &mut store);
+
+        assert_eq!(TlvReader::new(&m4).get_u8(TlvType::State).unwrap(), 4);
+        assert_eq!(store.failed_setup_attempts(), 0);
+    }
+
+    #[test]
+    fn too_many_attempts() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let mut controller = Controller::new();
+
+        store
+            .set_failed_setup_attempts(MAX_SETUP_ATTEMPTS - 1)
+            .unwrap();
+
+        // The last allowed attempt, after the retry delay since boot
+        let (_, m2) = handle_request(
+            &mut pair_setup,
+            request(&controller.m1(), MAX_RETRY_DELAY.as_secs()),
+            &mut store,
+        );
+        handle(
+            &mut pair_setup,
+            &controller.m3(&m2, b"111-22-333"),
+            &mut store,
+        );
+
+        assert_eq!(store.failed_setup_attempts(), MAX_SETUP_ATTEMPTS);
+
+        let (_, m2) = handle(&mut pair_setup, &Controller::new().m1(), &mut store);
+
+        let m2 = TlvReader::new(&m2);
+        assert_eq!(m2.get_u8(TlvType::State).unwrap(), 2);
+        assert_eq!(
+            m2.get_u8(TlvType::Error).unwrap(),
+            ErrorCode::MaxTries as u8
+        );
+        assert!(!pair_setup.is_in_progress());
+    }
+
+    #[test]
+    fn concurrent_pair_setup() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let mut controller = Controller::new();
+
+        let (_, m2) = handle(&mut pair_setup, &controller.m1(), &mut store);
+
+        let m1 = Controller::new().m1();
+        let (_, busy) = handle_request(
+            &mut pair_setup,
+            SetupRequest {
+                message: &m1,
+                connection: 2,
+                time: Duration::from_secs(0),
+            },
+            &mut store,
+        );
+
+        let busy = TlvReader::new(&busy);
+        assert_eq!(busy.get_u8(TlvType::State).unwrap(), 2);
+        assert_eq!(busy.get_u8(TlvType::Error).unwrap(), ErrorCode::Busy as u8);
+
+        // The pair setup in progress is not affected
+        let (_, m4) = handle(&mut pair_setup, &controller.m3(&m2, SETUP_CODE), &mut store);
+        let (pairing, _) = handle(&mut pair_setup, &controller.m5(&m4), &mut store);
+
+        assert!(pairing.is_some());
+    }
+
+    #[test]
+    fn already_paired() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let controller = Controller::new();
+
+        let mut identifier = PairingId::new();
+        identifier.extend_from_slice(CONTROLLER_ID).unwrap();
+
+        store
+            .save_pairing(Pairing {
+                identifier,
+                public_key: controller.signing_key.verifying_key().to_bytes(),
+                admin: true,
+            })
+            .unwrap();
+
+        let (_, m2) = handle(&mut pair_setup, &controller.m1(), &mut store);
+
+        let m2 = TlvReader::new(&m2);
+        assert_eq!(m2.get_u8(TlvType::State).unwrap(), 2);
+        assert_eq!(
+            m2.get_u8(TlvType::Error).unwrap(),
+            ErrorCode::Unavailable as u8
+        );
+    }
+
+    #[test]
+    fn unexpected_state() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let mut controller = Controller::new();
+
+        let (_, m2) = handle(&mut pair_setup, &controller.m1(), &mut store);
+        let m3 = controller.m3(&m2, SETUP_CODE);
+
+        // M3 without a key exchange in progress
+        let (_, response) = handle(&mut self::pair_setup(), &m3, &mut store);
+
+        let response = TlvReader::new(&response);
+        assert_eq!(response.get_u8(TlvType::State).unwrap(), 4);
+        assert_eq!(
+            response.get_u8(TlvType::Error).unwrap(),
+            ErrorCode::Unknown as u8
+        );
+    }
+
+    #[test]
+    fn tampered_m5() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let mut controller = Controller::new();
+
+        let (_, m2) = handle(&mut pair_setup, &controller.m1(), &mut store);
+        let (_, m4) = handle(&mut pair_setup, &controller.m3(&m2, SETUP_CODE), &mut store);
+
+        let m5 = controller.m5(&m4);
+        let len = m5.len();
+
+        // Flip a bit of the authentication tag
+        let mut tampered = [0u8; 512];
+        tampered[..len].copy_from_slice(&m5);
+        tampered[len - 1] ^= 1;
+
+        let (pairing, m6) = handle(&mut pair_setup, &tampered[..len], &mut store);
+
+        assert!(pairing.is_none());
+        assert_eq!(
+            TlvReader::new(&m6).get_u8(TlvType::Error).unwrap(),
+            ErrorCode::Authentication as u8
+        );
+    }
+
+    #[test]
+    fn retry_delay_after_failed_attempt() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let mut controller = Controller::new();
+
+        let (_, m2) = handle_request(&mut pair_setup, request(&controller.m1(), 10), &mut store);
+        handle_request(
+            &mut pair_setup,
+            request(&controller.m3(&m2, b"111-22-333"), 10),
+            &mut store,
+        );
+
+        store.set_failed_setup_attempts(3).unwrap();
+
+        let (_, m2) = handle_request(&mut pair_setup, request(&controller.m1(), 11), &mut store);
+
+        let m2 = TlvReader::new(&m2);
+        assert_eq!(m2.get_u8(TlvType::Error).unwrap(), ErrorCode::Backoff as u8);
+        assert_eq!(m2.get_u16(TlvType::RetryDelay).unwrap(), 3);
+        assert!(!pair_setup.is_in_progress());
+
+        // Accepted once the delay has passed
+        let (_, m2) = handle_request(&mut pair_setup, request(&controller.m1(), 14), &mut store);
+
+        assert!(TlvReader::new(&m2).find(TlvType::Error).unwrap().is_none());
+        assert!(pair_setup.is_in_progress());
+    }
+
+    #[test]
+    fn retry_delay_after_boot() {
+        let mut store = MemoryStore::new();
+        store.set_failed_setup_attempts(2).unwrap();
+
+        let (_, m2) = handle(&mut pair_setup(), &Controller::new().m1(), &mut store);
+
+        assert_eq!(TlvReader::new(&m2).get_u16(TlvType::RetryDelay).unwrap(), 2);
+    }
+
+    #[test]
+    fn restart_on_same_connection() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+
+        handle(&mut pair_setup, &Controller::new().m1(), &mut store);
+
+        // The controller starts again, without closing the connection
+        let mut controller = Controller::new();
+
+        let (_, m2) = handle(&mut pair_setup, &controller.m1(), &mut store);
+        let (_, m4) = handle(&mut pair_setup, &controller.m3(&m2, SETUP_CODE), &mut store);
+        let (pairing, _) = handle(&mut pair_setup, &controller.m5(&m4), &mut store);
+
+        assert!(pairing.is_some());
+    }
+
+    #[test]
+    fn unexpected_message_keeps_pair_setup() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let mut controller = Controller::new();
+
+        let (_, m2) = handle(&mut pair_setup, &controller.m1(), &mut store);
+        let m3 = controller.m3(&m2, SETUP_CODE);
+
+        // M5 before M3
+        let mut m5 = Response::new();
+        m5.push(Tlv::new(TlvType::State, 5u8)).unwrap();
+
+        let (_, response) = handle(&mut pair_setup, &m5, &mut store);
+        assert_eq!(
+            TlvReader::new(&response).get_u8(TlvType::Error).unwrap(),
+            ErrorCode::Unknown as u8
+        );
+
+        // M3 without the proof
+        let mut malformed = Response::new();
+        malformed.push(Tlv::new(TlvType::State, 3u8)).unwrap();
+
+        let signing_key = accessory_key();
+        let accessory = Accessory {
+            pairing_id: ACCESSORY_ID,
+            signing_key: &signing_key,
+        };
+
+        assert!(pair_setup
+            .handle(
+                request(&malformed, 0),
+                &accessory,
+                &mut store,
+                &mut TestRng(42),
+                &mut Response::new(),
+            )
+            .is_err());
+
+        // M3 from another connection
+        let (_, response) = handle_request(
+            &mut pair_setup,
+            SetupRequest {
+                message: &m3,
+                connection: 2,
+                time: Duration::from_secs(0),
+            },
+            &mut store,
+        );
+        assert_eq!(
+            TlvReader::new(&response).get_u8(TlvType::Error).unwrap(),
+            ErrorCode::Unknown as u8
+        );
+
+        let (_, m4) = handle(&mut pair_setup, &m3, &mut store);
+        let (pairing, _) = handle(&mut pair_setup, &controller.m5(&m4), &mut store);
+
+        assert!(pairing.is_some());
+    }
+
+    #[test]
+    fn closing_other_connection_keeps_pair_setup() {
+        let mut pair_setup = pair_setup();
+
+        handle(
+            &mut pair_setup,
+            &Controller::new().m1(),
+            &mut MemoryStore::new(),
+        );
+
+        pair_setup.connection_closed(2);
+        assert!(pair_setup.is_in_progress());
+
+        pair_setup.connection_closed(1);
+        assert!(!pair_setup.is_in_progress());
+    }
+
+    #[test]
+    fn m3_in_fragmented_hap_request() {
+        let mut pair_setup = pair_setup();
+        let mut store = MemoryStore::new();
+        let mut controller = Controller::new();
+
+        let (_, m2) = handle(&mut pair_setup, &controller.m1(), &mut store);
+        let m3 = controller.m3(&m2, SETUP_CODE);
+
+        // The Value parameter is longer than a single TLV item
+        assert!(m3.len() > 255);
+
+        let mut body = Response::new();
+        body.push(Tlv::new(ParamType::Value, &m3[..])).unwrap();
+
+        let hap_request = HapRequest::new(
+            OpCode::CharacteristicWrite,
+            0x42,
+            InstanceId::new(0x22),
+            &body,
+        );
+
+        let mut buffer = [0u8; 512];
+        let mut reassembler = RequestReassembler::new(&mut buffer);
+        let mut reassembled = None;
+
+        for fragment in hap_request.fragments(20).unwrap() {
+            let mut data = [0u8; 20];
+            let len = fragment.write_into(&mut data).unwrap();
+
+            if let Some(request) = reassembler.push(&data[..len]).unwrap() {
+                reassembled = request.body().map(|body| body.to_vec());
+            }
+        }
+
+        let signing_key = accessory_key();
+        let accessory = Accessory {
+            pairing_id: ACCESSORY_ID,
+            signing_key: &signing_key,
+        };
+
+        let mut response = Response::new();
+
+        let pairing = handle_write(&reassembled.unwrap(), &mut response, |message, writer| {
+            pair_setup.handle(
+                request(message, 0),
+                &accessory,
+                &mut store,
+                &mut TestRng(42),
+                writer,
+            )
+        })
+        .unwrap();
+
+        assert!(pairing.is_none());
+
+        let mut m4 = [0u8; 512];
+        let m4 = TlvReader::new(&response)
+            .get_bytes_into(ParamType::Value, &mut m4)
+            .unwrap();
+
+        // Fails if M4 doesn't contain the proof of the accessory
+        let m5 = controller.m5(m4);
+        let (pairing, _) = handle(&mut pair_setup, &m5, &mut store);
+
+        assert!(pairing.is_some());
+    }
+}
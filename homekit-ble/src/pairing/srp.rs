@@ -0,0 +1,398 @@
+//! SRP-6a key exchange, as used by Pair Setup
+//!
+//! HAP uses the 3072-bit group from RFC 5054 with SHA-512 as hash function,
+//! and `Pair-Setup` as user name. The password is the setup code in the
+//! format `XXX-XX-XXX`.
+//!
+//! Numbers are encoded as big endian, padded to the length of the modulus.
+
+use crypto_bigint::{
+    modular::runtime_mod::{DynResidue, DynResidueParams},
+    Encoding, NonZero, Zero, U256, U3072, U512,
+};
+use sha2::{Digest, Sha512};
+
+use crate::Error;
+
+/// Length of the modulus, and of all public values, in bytes
+pub const KEY_LEN: usize = 384;
+
+/// Length of the salt used to calculate the verifier
+pub const SALT_LEN: usize = 16;
+
+/// Length of the proofs and the session key, which are SHA-512 hashes
+pub const PROOF_LEN: usize = 64;
+
+/// Length of the secret values, in bytes
+pub const SECRET_LEN: usize = 32;
+
+pub(crate) const USERNAME: &[u8] = b"Pair-Setup";
+
+/// Prime of the 3072-bit group, from RFC 5054
+const N: U3072 = U3072::from_be_hex(concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF",
+));
+
+/// Generator of the 3072-bit group
+const G: u8 = 5;
+
+fn params() -> DynResidueParams<{ U3072::LIMBS }> {
+    DynResidueParams::new(&N)
+}
+
+fn residue(value: &U3072) -> DynResidue<{ U3072::LIMBS }> {
+    DynResidue::new(value, params())
+}
+
+/// Convert a big endian number with at most `KEY_LEN` bytes.
+fn from_be_bytes(data: &[u8]) -> U3072 {
+    let mut padded = [0u8; KEY_LEN];
+    padded[KEY_LEN - data.len()..].copy_from_slice(data);
+
+    U3072::from_be_slice(&padded)
+}
+
+fn generator() -> DynResidue<{ U3072::LIMBS }> {
+    residue(&U3072::from_u8(G))
+}
+
+/// The multiplier parameter, `k = H(N | PAD(g))`
+fn multiplier() -> U3072 {
+    let hash = Sha512::new()
+        .chain_update(N.to_be_bytes())
+        .chain_update(U3072::from_u8(G).to_be_bytes())
+        .finalize();
+
+    from_be_bytes(&hash)
+}
+
+/// The private key derived from the password, `x = H(s | H(I | ":" | P))`
+fn private_key(salt: &[u8; SALT_LEN], setup_code: &[u8]) -> U512 {
+    let identity = Sha512::new()
+        .chain_update(USERNAME)
+        .chain_update(b":")
+        .chain_update(setup_code)
+        .finalize();
+
+    let hash = Sha512::new()
+        .chain_update(salt)
+        .chain_update(identity)
+        .finalize();
+
+    U512::from_be_slice(&hash)
+}
+
+/// The scrambling parameter, `u = H(PAD(A) | PAD(B))`
+fn scrambling_parameter(a_pub: &[u8; KEY_LEN], b_pub: &[u8; KEY_LEN]) -> U512 {
+    let hash = Sha512::new()
+        .chain_update(a_pub)
+        .chain_update(b_pub)
+        .finalize();
+
+    U512::from_be_slice(&hash)
+}
+
+/// Calculate the proof of the client, `M1 = H(H(N) xor H(g) | H(I) | s | A | B | K)`
+fn client_proof(
+    salt: &[u8; SALT_LEN],
+    a_pub: &[u8; KEY_LEN],
+    b_pub: &[u8; KEY_LEN],
+    session_key: &[u8; PROOF_LEN],
+) -> [u8; PROOF_LEN] {
+    let hash_n = Sha512::digest(N.to_be_bytes());
+    let hash_g = Sha512::digest([G]);
+
+    let mut hash_group = [0u8; PROOF_LEN];
+
+    for (out, (n, g)) in hash_group.iter_mut().zip(hash_n.iter().zip(hash_g.iter())) {
+        *out = n ^ g;
+    }
+
+    Sha512::new()
+        .chain_update(hash_group)
+        .chain_update(Sha512::digest(USERNAME))
+        .chain_update(salt)
+        .chain_update(a_pub)
+        .chain_update(b_pub)
+        .chain_update(session_key)
+        .finalize()
+        .into()
+}
+
+/// Calculate the proof of the server, `M2 = H(A | M1 | K)`
+fn server_proof(
+    a_pub: &[u8; KEY_LEN],
+    client_proof: &[u8; PROOF_LEN],
+    session_key: &[u8; PROOF_LEN],
+) -> [u8; PROOF_LEN] {
+    Sha512::new()
+        .chain_update(a_pub)
+        .chain_update(client_proof)
+        .chain_update(session_key)
+        .finalize()
+        .into()
+}
+
+/// Salt and verifier for a setup code
+///
+/// The verifier can be calculated in advance, so that
+/// the setup code itself doesn't have to be stored.
+#[derive(Clone)]
+pub struct Verifier {
+    pub salt: [u8; SALT_LEN],
+
+    pub verifier: [u8; KEY_LEN],
+}
+
+impl Verifier {
+    /// Calculate the verifier for a setup code, `v = g^x`.
+    pub fn new(salt: [u8; SALT_LEN], setup_code: &[u8]) -> Self {
+        let x = private_key(&salt, setup_code);
+
+        let verifier = generator().pow_bounded_exp(&x, 512).retrieve();
+
+        Verifier {
+            salt,
+            verifier: verifier.to_be_bytes(),
+        }
+    }
+}
+
+/// Server side of the key exchange
+pub struct Server {
+    salt: [u8; SALT_LEN],
+
+    verifier: U3072,
+
+    secret: U256,
+
+    public_key: [u8; KEY_LEN],
+}
+
+impl Server {
+    /// Start a key exchange using the secret value `b`.
+    ///
+    /// The secret has to be generated using a cryptographically secure
+    /// random number generator.
+    pub fn new(verifier: &Verifier, secret: &[u8; SECRET_LEN]) -> Self {
+        let secret = U256::from_be_slice(secret);
+        let v = from_be_bytes(&verifier.verifier);
+
+        // B = k*v + g^b
+        let public_key =
+            residue(&multiplier()) * residue(&v) + generator().pow_bounded_exp(&secret, 256);
+
+        Server {
+            salt: verifier.salt,
+            verifier: v,
+            secret,
+            public_key: public_key.retrieve().to_be_bytes(),
+        }
+    }
+
+    pub fn salt(&self) -> &[u8; SALT_LEN] {
+        &self.salt
+    }
+
+    /// The public key `B` of the server
+    pub fn public_key(&self) -> &[u8; KEY_LEN] {
+        &self.public_key
+    }
+
+    /// Verify the proof of the client.
+    ///
+    /// Returns the session key `K` and the proof of the server `M2`, or
+    /// `Error::Authentication` if the client used a different setup code.
+    pub fn verify_client(
+        &self,
+        client_public_key: &[u8],
+        proof: &[u8],
+    ) -> Result<([u8; PROOF_LEN], [u8; PROOF_LEN]), Error> {
+        if client_public_key.len() > KEY_LEN || proof.len() != PROOF_LEN {
+            return Err(Error::Authentication);
+        }
+
+        let a_value = from_be_bytes(client_public_key);
+        let a_pub = a_value.to_be_bytes();
+
+        // Unwrap is safe, the modulus is not zero
+        let a_value = a_value.rem(&NonZero::new(N).unwrap());
+
+        // The public key of the client must not be a multiple of N
+        if bool::from(a_value.is_zero()) {
+            return Err(Error::Authentication);
+        }
+
+        let u = scrambling_parameter(&a_pub, &self.public_key);
+
+        // S = (A * v^u) ^ b
+        let shared_secret = (residue(&a_value) * residue(&self.verifier).pow_bounded_exp(&u, 512))
+            .pow_bounded_exp(&self.secret, 256);
+
+        let session_key: [u8; PROOF_LEN] =
+            Sha512::digest(shared_secret.retrieve().to_be_bytes()).into();
+
+        let expected_proof = client_proof(&self.salt, &a_pub, &self.public_key, &session_key);
+
+        if !constant_time_eq(&expected_proof, proof) {
+            return Err(Error::Authentication);
+        }
+
+        Ok((
+            session_key,
+            server_proof(&a_pub, &expected_proof, &session_key),
+        ))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Client side of the key exchange, used to test the server
+#[cfg(test)]
+pub(crate) mod client {
+    use super::*;
+
+    pub struct Client {
+        secret: U256,
+
+        public_key: [u8; KEY_LEN],
+    }
+
+    impl Client {
+        pub fn new(secret: &[u8; SECRET_LEN]) -> Self {
+            let secret = U256::from_be_slice(secret);
+
+            let public_key = generator().pow_bounded_exp(&secret, 256).retrieve();
+
+            Client {
+                secret,
+                public_key: public_key.to_be_bytes(),
+            }
+        }
+
+        pub fn public_key(&self) -> &[u8; KEY_LEN] {
+            &self.public_key
+        }
+
+        /// Calculate the session key and the proof of the client.
+        pub fn process(
+            &self,
+            salt: &[u8; SALT_LEN],
+            setup_code: &[u8],
+            server_public_key: &[u8; KEY_LEN],
+        ) -> ([u8; PROOF_LEN], [u8; PROOF_LEN]) {
+            let u = scrambling_parameter(&self.public_key, server_public_key);
+            let x = private_key(salt, setup_code);
+
+            // S = (B - k*g^x) ^ (a + u*x)
+            let base = residue(&from_be_bytes(server_public_key))
+                - residue(&multiplier()) * generator().pow_bounded_exp(&x, 512);
+
+            let shared_secret = base.pow_bounded_exp(&self.secret, 256)
+                * base.pow_bounded_exp(&u, 512).pow_bounded_exp(&x, 512);
+
+            let session_key: [u8; PROOF_LEN] =
+                Sha512::digest(shared_secret.retrieve().to_be_bytes()).into();
+
+            let proof = client_proof(salt, &self.public_key, server_public_key, &session_key);
+
+            (session_key, proof)
+        }
+
+        pub fn server_proof(
+            &self,
+            client_proof: &[u8; PROOF_LEN],
+            session_key: &[u8; PROOF_LEN],
+        ) -> [u8; PROOF_LEN] {
+            server_proof(&self.public_key, client_proof, session_key)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::client::Client;
+    use super::*;
+
+    const SALT: [u8; SALT_LEN] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+    const SETUP_CODE: &[u8] = b"123-45-678";
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn key_exchange() {
+        let verifier = Verifier::new(SALT, SETUP_CODE);
+
+        assert_eq!(&verifier.verifier[..8], &from_hex("b07f982bc93f5cdd")[..]);
+        assert_eq!(&verifier.verifier[376..], &from_hex("ce15942cdb25f5db")[..]);
+
+        let server = Server::new(&verifier, &[0x42; SECRET_LEN]);
+
+        assert_eq!(&server.public_key()[..8], &from_hex("d61a52a4d069dc91")[..]);
+        assert_eq!(
+            &server.public_key()[376..],
+            &from_hex("d5484582d2eb154e")[..]
+        );
+
+        let client = Client::new(&[0x17; SECRET_LEN]);
+
+        let (client_key, client_proof) = client.process(&SALT, SETUP_CODE, server.public_key());
+
+        assert_eq!(&client_proof[..], &from_hex("ee33c74bc304782df3a080d7d86d8a652b35731350365c6cf8b0b95d2039a7cad312e94aa432553cedbf97e32fde5c0868d23f38ba7b39694f0a1292d10ae7a8")[..]);
+
+        let (server_key, server_proof) = server
+            .verify_client(client.public_key(), &client_proof)
+            .unwrap();
+
+        assert_eq!(server_key, client_key);
+        assert_eq!(&server_key[..], &from_hex("7b9f377fcfd4c56fd42a67ff21b78d5c86decf0a328ee27ca83b9a1af85b560316e96298617d633aaf30256785958c2fbedb9b2e55b8813eece640803634a4a0")[..]);
+        assert_eq!(&server_proof[..], &from_hex("4b35f9b65c6941e76cc0dfff486674f144c96655fdd3fa8286f316bb0d7d77e99c34f0aa988d6a7a636cb83d01567099a93fa49209697232f35089136f35482b")[..]);
+        assert_eq!(
+            server_proof,
+            client.server_proof(&client_proof, &client_key)
+        );
+    }
+
+    #[test]
+    fn wrong_setup_code() {
+        let verifier = Verifier::new(SALT, SETUP_CODE);
+        let server = Server::new(&verifier, &[0x42; SECRET_LEN]);
+
+        let client = Client::new(&[0x17; SECRET_LEN]);
+        let (_, client_proof) = client.process(&SALT, b"123-45-679", server.public_key());
+
+        assert!(matches!(
+            server.verify_client(client.public_key(), &client_proof),
+            Err(Error::Authentication)
+        ));
+    }
+
+    #[test]
+    fn zero_client_public_key() {
+        let verifier = Verifier::new(SALT, SETUP_CODE);
+        let server = Server::new(&verifier, &[0x42; SECRET_LEN]);
+
+        assert!(matches!(
+            server.verify_client(&N.to_be_bytes(), &[0; PROOF_LEN]),
+            Err(Error::Authentication)
+        ));
+    }
+}
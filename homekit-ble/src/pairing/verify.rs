@@ -0,0 +1,556 @@
+//! Pair Verify, defined in section 5.7 of the HAP specification
+//!
+//! Pair Verify is performed every time a paired controller connects.
+//! Both sides generate an ephemeral Curve25519 key pair, and prove their
+//! identity by signing the ephemeral public keys with their long-term keys.
+//! The resulting shared secret is used to derive the keys of the session.
+
+use core::convert::TryInto;
+
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use rand_core::{CryptoRng, RngCore};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::{
+    concat, write_error, Accessory, ErrorCode, Pairing, TlvType, MAX_PAIRING_ID_LEN,
+    PUBLIC_KEY_LEN, SIGNATURE_LEN,
+};
+use crate::{
+    crypto::{self, KEY_LEN},
+    tlv::{Tlv, TlvReader, TlvWrite, TlvWriter},
+    Error,
+};
+
+/// Maximum length of the encrypted data in M2 and M3
+const MAX_ENCRYPTED_LEN: usize = 128;
+
+/// Maximum length of the data signed by the controller or the accessory
+const MAX_SIGNED_LEN: usize = 2 * PUBLIC_KEY_LEN + MAX_PAIRING_ID_LEN;
+
+/// State of the pair verify procedure
+enum State {
+    Idle,
+
+    /// M2 has been sent, waiting for the controller to prove its identity in M3
+    Started {
+        shared_secret: [u8; 32],
+        accessory_public_key: [u8; PUBLIC_KEY_LEN],
+        controller_public_key: [u8; PUBLIC_KEY_LEN],
+    },
+}
+
+/// A session with a verified controller
+#[derive(Debug, Clone)]
+pub struct VerifiedSession {
+    /// The controller, as stored when it was paired
+    pub controller: Pairing,
+
+    /// Key to encrypt data sent by the accessory
+    pub accessory_to_controller: [u8; KEY_LEN],
+
+    /// Key to decrypt data sent by the controller
+    pub controller_to_accessory: [u8; KEY_LEN],
+}
+
+/// State machine for Pair Verify
+pub struct PairVerify {
+    state: State,
+}
+
+impl PairVerify {
+    pub fn new() -> Self {
+        PairVerify { state: State::Idle }
+    }
+
+    /// Abort a pair verify procedure in progress.
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+    }
+
+    /// Handle a request from the controller, and write the response.
+    ///
+    /// The controller is looked up by its pairing identifier with `find_pairing`.
+    /// Once it has been verified in M3, the keys for the session are returned.
+    ///
+    /// Failed verification is reported to the controller in the response,
+    /// an error is only returned if the request is malformed or the response
+    /// can't be written.
+    pub fn handle<W, R, F>(
+        &mut self,
+        request: &[u8],
+        accessory: &Accessory,
+        find_pairing: F,
+        rng: &mut R,
+        response: &mut W,
+    ) -> Result<Option<VerifiedSession>, Error>
+    where
+        W: TlvWrite,
+        R: RngCore + CryptoRng,
+        F: FnOnce(&[u8]) -> Option<Pairing>,
+    {
+        let request = TlvReader::new(request);
+
+        let state = request.get_u8(TlvType::State)?;
+
+        let result = match (state, &self.state) {
+            (1, _) => self
+                .handle_m1(request, accessory, rng, response)
+                .map(|_| None),
+            (3, State::Started { .. }) => self.handle_m3(request, find_pairing, response),
+            _ => {
+                self.state = State::Idle;
+                write_error(response, state.wrapping_add(1), ErrorCode::Unknown).map(|_| None)
+            }
+        };
+
+        if result.is_err() {
+            self.state = State::Idle;
+        }
+
+        result
+    }
+
+    /// Handle the start request, and send the signed ephemeral public key of the accessory.
+    fn handle_m1<W: TlvWrite, R: RngCore + CryptoRng>(
+        &mut self,
+        request: TlvReader,
+        accessory: &Accessory,
+        rng: &mut R,
+        response: &mut W,
+    ) -> Result<(), Error> {
+        self.state = State::Idle;
+
+        let controller_public_key: [u8; PUBLIC_KEY_LEN] = request
+            .get_bytes(TlvType::PublicKey)?
+            .try_into()
+            .map_err(|_| Error::InvalidTlvValue(TlvType::PublicKey as u8))?;
+
+        let secret = EphemeralSecret::random_from_rng(&mut *rng);
+        let accessory_public_key = PublicKey::from(&secret).to_bytes();
+
+        let shared_secret = secret.diffie_hellman(&PublicKey::from(controller_public_key));
+
+        // Reject low order points, which result in a known shared secret
+        if !shared_secret.was_contributory() {
+            return write_error(response, 2, ErrorCode::Authentication);
+        }
+
+        let shared_secret = shared_secret.to_bytes();
+
+        let mut accessory_info = [0u8; MAX_SIGNED_LEN];
+        let accessory_info = concat(
+            &mut accessory_info,
+            &[
+                &accessory_public_key,
+                accessory.pairing_id,
+                &controller_public_key,
+            ],
+        )?;
+
+        let signature = accessory.signing_key.sign(accessory_info).to_bytes();
+
+        let mut buffer = [0u8; MAX_ENCRYPTED_LEN];
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        writer
+            .push(Tlv::new(TlvType::Identifier, accessory.pairing_id))?
+            .push(Tlv::new(TlvType::Signature, &signature[..]))?;
+
+        let len = writer.len();
+
+        let encrypted_len = crypto::encrypt(
+            &encryption_key(&shared_secret),
+            b"PV-Msg02",
+            &[],
+            &mut buffer,
+            len,
+        )?;
+
+        response.push_tlv(Tlv::new(TlvType::State, 2u8))?;
+        response.push_tlv(Tlv::new(TlvType::PublicKey, &accessory_public_key[..]))?;
+        response.push_tlv(Tlv::new(TlvType::EncryptedData, &buffer[..encrypted_len]))?;
+
+        self.state = State::Started {
+            shared_secret,
+            accessory_public_key,
+            controller_public_key,
+        };
+
+        Ok(())
+    }
+
+    /// Handle the finish request, verifying the signature of the controller.
+    fn handle_m3<W: TlvWrite, F: FnOnce(&[u8]) -> Option<Pairing>>(
+        &mut self,
+        request: TlvReader,
+        find_pairing: F,
+        response: &mut W,
+    ) -> Result<Option<VerifiedSession>, Error> {
+        let (shared_secret, accessory_public_key, controller_public_key) = match &self.state {
+            State::Started {
+                shared_secret,
+                accessory_public_key,
+                controller_public_key,
+            } => (
+                *shared_secret,
+                *accessory_public_key,
+                *controller_public_key,
+            ),
+            _ => unreachable!("M3 is only handled after M2 has been sent"),
+        };
+
+        self.state = State::Idle;
+
+        let mut buffer = [0u8; MAX_ENCRYPTED_LEN];
+
+        let encrypted_len = request
+            .get(TlvType::EncryptedData)?
+            .copy_into(&mut buffer)
+            .map_err(|_| Error::InvalidTlvValue(TlvType::EncryptedData as u8))?
+            .len();
+
+        let controller = crypto::decrypt(
+            &encryption_key(&shared_secret),
+            b"PV-Msg03",
+            &[],
+            &mut buffer[..encrypted_len],
+        )
+        .and_then(|data| {
+            verify_controller(
+                TlvReader::new(data),
+                &controller_public_key,
+                &accessory_public_key,
+                find_pairing,
+            )
+        });
+
+        let controller = match controller {
+            Ok(controller) => controller,
+            Err(Error::Authentication) => {
+                write_error(response, 4, ErrorCode::Authentication)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        response.push_tlv(Tlv::new(TlvType::State, 4u8))?;
+
+        Ok(Some(VerifiedSession {
+            controller,
+            accessory_to_controller: crypto::derive_key(
+                &shared_secret,
+                b"Control-Salt",
+                b"Control-Read-Encryption-Key",
+            ),
+            controller_to_accessory: crypto::derive_key(
+                &shared_secret,
+                b"Control-Salt",
+                b"Control-Write-Encryption-Key",
+            ),
+        }))
+    }
+}
+
+impl Default for PairVerify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn encryption_key(shared_secret: &[u8; 32]) -> [u8; KEY_LEN] {
+    crypto::derive_key(
+        shared_secret,
+        b"Pair-Verify-Encrypt-Salt",
+        b"Pair-Verify-Encrypt-Info",
+    )
+}
+
+/// Verify the signature of a paired controller over the ephemeral public keys.
+fn verify_controller<F: FnOnce(&[u8]) -> Option<Pairing>>(
+    data: TlvReader,
+    controller_public_key: &[u8; PUBLIC_KEY_LEN],
+    accessory_public_key: &[u8; PUBLIC_KEY_LEN],
+    find_pairing: F,
+) -> Result<Pairing, Error> {
+    let identifier = data.get_bytes(TlvType::Identifier)?;
+
+    let signature: [u8; SIGNATURE_LEN] = data
+        .get_bytes(TlvType::Signature)?
+        .try_into()
+        .map_err(|_| Error::InvalidTlvValue(TlvType::Signature as u8))?;
+
+    // Unknown controllers are treated like an invalid signature
+    let pairing = find_pairing(identifier).ok_or(Error::Authentication)?;
+
+    let mut controller_info = [0u8; MAX_SIGNED_LEN];
+    let controller_info = concat(
+        &mut controller_info,
+        &[controller_public_key, identifier, accessory_public_key],
+    )
+    .map_err(|_| Error::InvalidTlvValue(TlvType::Identifier as u8))?;
+
+    VerifyingKey::from_bytes(&pairing.public_key)
+        .and_then(|key| key.verify_strict(controller_info, &Signature::from_bytes(&signature)))
+        .map_err(|_| Error::Authentication)?;
+
+    Ok(pairing)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::pairing::{
+        setup::test::{accessory_key, TestRng, ACCESSORY_ID, CONTROLLER_ID},
+        PairingId,
+    };
+    use ed25519_dalek::SigningKey;
+    use heapless::consts::U256;
+
+    type Response = crate::tlv::TlvVec<U256>;
+
+    /// Software controller, which performs pair verify with the accessory
+    pub(crate) struct Controller {
+        signing_key: SigningKey,
+
+        secret: Option<EphemeralSecret>,
+
+        public_key: [u8; PUBLIC_KEY_LEN],
+
+        shared_secret: [u8; 32],
+    }
+
+    impl Controller {
+        pub(crate) fn new(signing_key: SigningKey) -> Self {
+            let secret = EphemeralSecret::random_from_rng(TestRng(7));
+            let public_key = PublicKey::from(&secret).to_bytes();
+
+            Controller {
+                signing_key,
+                secret: Some(secret),
+                public_key,
+                shared_secret: [0; 32],
+            }
+        }
+
+        pub(crate) fn m1(&self) -> Response {
+            let mut request = Response::new();
+
+            request
+                .push(Tlv::new(TlvType::State, 1u8))
+                .and_then(|r| r.push(Tlv::new(TlvType::PublicKey, &self.public_key[..])))
+                .unwrap();
+
+            request
+        }
+
+        pub(crate) fn m3(&mut self, m2: &[u8], accessory_key: &VerifyingKey) -> Response {
+            let m2 = TlvReader::new(m2);
+
+            assert_eq!(m2.get_u8(TlvType::State).unwrap(), 2);
+
+            let accessory_public_key: [u8; PUBLIC_KEY_LEN] = m2
+                .get_bytes(TlvType::PublicKey)
+                .unwrap()
+                .try_into()
+                .unwrap();
+
+            self.shared_secret = self
+                .secret
+                .take()
+                .unwrap()
+                .diffie_hellman(&PublicKey::from(accessory_public_key))
+                .to_bytes();
+
+            let key = encryption_key(&self.shared_secret);
+
+            // Verify the accessory
+            let mut buffer = [0u8; MAX_ENCRYPTED_LEN];
+            let len = m2
+                .get_bytes_into(TlvType::EncryptedData, &mut buffer)
+                .unwrap()
+                .len();
+            let data = TlvReader::new(
+                crypto::decrypt(&key, b"PV-Msg02", &[], &mut buffer[..len]).unwrap(),
+            );
+
+            let identifier = data.get_bytes(TlvType::Identifier).unwrap();
+            let signature: [u8; SIGNATURE_LEN] = data
+                .get_bytes(TlvType::Signature)
+                .unwrap()
+                .try_into()
+                .unwrap();
+
+            let mut info = [0u8; MAX_SIGNED_LEN];
+            let info = concat(
+                &mut info,
+                &[&accessory_public_key, identifier, &self.public_key],
+            )
+            .unwrap();
+
+            accessory_key
+                .verify_strict(info, &Signature::from_bytes(&signature))
+                .expect("Accessory signature should be valid");
+
+            // Prove the identity of the controller
+            let mut info = [0u8; MAX_SIGNED_LEN];
+            let info = concat(
+                &mut info,
+                &[&self.public_key, CONTROLLER_ID, &accessory_public_key],
+            )
+            .unwrap();
+
+            let signature = self.signing_key.sign(info).to_bytes();
+
+            let mut buffer = [0u8; MAX_ENCRYPTED_LEN];
+            let mut writer = TlvWriter::new(&mut buffer);
+
+            writer
+                .push(Tlv::new(TlvType::Identifier, CONTROLLER_ID))
+                .and_then(|w| w.push(Tlv::new(TlvType::Signature, &signature[..])))
+                .unwrap();
+
+            let len = writer.len();
+            let len = crypto::encrypt(&key, b"PV-Msg03", &[], &mut buffer, len).unwrap();
+
+            let mut request = Response::new();
+
+            request
+                .push(Tlv::new(TlvType::State, 3u8))
+                .and_then(|r| r.push(Tlv::new(TlvType::EncryptedData, &buffer[..len])))
+                .unwrap();
+
+            request
+        }
+
+        /// Keys to encrypt and decrypt data sent by the controller
+        pub(crate) fn session_keys(&self) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+            (
+                crypto::derive_key(
+                    &self.shared_secret,
+                    b"Control-Salt",
+                    b"Control-Write-Encryption-Key",
+                ),
+                crypto::derive_key(
+                    &self.shared_secret,
+                    b"Control-Salt",
+                    b"Control-Read-Encryption-Key",
+                ),
+            )
+        }
+    }
+
+    pub(crate) fn controller_key() -> SigningKey {
+        SigningKey::from_bytes(&[0x33; 32])
+    }
+
+    pub(crate) fn pairing() -> Pairing {
+        let mut identifier = PairingId::new();
+        identifier.extend_from_slice(CONTROLLER_ID).unwrap();
+
+        Pairing {
+            identifier,
+            public_key: controller_key().verifying_key().to_bytes(),
+            admin: true,
+        }
+    }
+
+    fn handle(
+        pair_verify: &mut PairVerify,
+        request: &[u8],
+        paired: Option<Pairing>,
+    ) -> (Option<VerifiedSession>, Response) {
+        let signing_key = accessory_key();
+
+        let accessory = Accessory {
+            pairing_id: ACCESSORY_ID,
+            signing_key: &signing_key,
+        };
+
+        let mut response = Response::new();
+
+        let session = pair_verify
+            .handle(
+                request,
+                &accessory,
+                |identifier| paired.filter(|pairing| &pairing.identifier[..] == identifier),
+                &mut TestRng(42),
+                &mut response,
+            )
+            .unwrap();
+
+        (session, response)
+    }
+
+    #[test]
+    fn successful_pair_verify() {
+        let mut pair_verify = PairVerify::new();
+        let mut controller = Controller::new(controller_key());
+
+        let (session, m2) = handle(&mut pair_verify, &controller.m1(), Some(pairing()));
+        assert!(session.is_none());
+
+        let m3 = controller.m3(&m2, &accessory_key().verifying_key());
+        let (session, m4) = handle(&mut pair_verify, &m3, Some(pairing()));
+
+        assert_eq!(TlvReader::new(&m4).get_u8(TlvType::State).unwrap(), 4);
+        assert!(TlvReader::new(&m4).find(TlvType::Error).unwrap().is_none());
+
+        let session = session.expect("Controller should be verified");
+        assert_eq!(session.controller, pairing());
+
+        let (write_key, read_key) = controller.session_keys();
+        assert_eq!(session.controller_to_accessory, write_key);
+        assert_eq!(session.accessory_to_controller, read_key);
+        assert_ne!(write_key, read_key);
+    }
+
+    #[test]
+    fn unknown_controller() {
+        let mut pair_verify = PairVerify::new();
+        let mut controller = Controller::new(controller_key());
+
+        let (_, m2) = handle(&mut pair_verify, &controller.m1(), None);
+
+        let m3 = controller.m3(&m2, &accessory_key().verifying_key());
+        let (session, m4) = handle(&mut pair_verify, &m3, None);
+
+        assert!(session.is_none());
+        assert_eq!(
+            TlvReader::new(&m4).get_u8(TlvType::Error).unwrap(),
+            ErrorCode::Authentication as u8
+        );
+    }
+
+    #[test]
+    fn wrong_controller_key() {
+        let mut pair_verify = PairVerify::new();
+        let mut controller = Controller::new(SigningKey::from_bytes(&[0x44; 32]));
+
+        let (_, m2) = handle(&mut pair_verify, &controller.m1(), Some(pairing()));
+
+        let m3 = controller.m3(&m2, &accessory_key().verifying_key());
+        let (session, m4) = handle(&mut pair_verify, &m3, Some(pairing()));
+
+        assert!(session.is_none());
+        assert_eq!(
+            TlvReader::new(&m4).get_u8(TlvType::Error).unwrap(),
+            ErrorCode::Authentication as u8
+        );
+    }
+
+    #[test]
+    fn low_order_public_key() {
+        let mut request = Response::new();
+        request
+            .push(Tlv::new(TlvType::State, 1u8))
+            .and_then(|r| r.push(Tlv::new(TlvType::PublicKey, &[0u8; 32][..])))
+            .unwrap();
+
+        let (_, m2) = handle(&mut PairVerify::new(), &request, Some(pairing()));
+
+        assert_eq!(
+            TlvReader::new(&m2).get_u8(TlvType::Error).unwrap(),
+            ErrorCode::Authentication as u8
+        );
+    }
+}
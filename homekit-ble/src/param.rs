@@ -0,0 +1,189 @@
+//! HAP-BLE parameters contained in the body of a PDU
+//!
+//! The body of a HAP PDU is a list of TLV items, using
+//! the parameter types from Table 7-10 of the HAP specification.
+
+use core::convert::TryFrom;
+
+use crate::Error;
+
+/// HAP-BLE parameter types, defined in Table 7-10
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ParamType {
+    Value = 0x01,
+    AdditionalAuthorizationData = 0x02,
+    Origin = 0x03,
+    CharacteristicType = 0x04,
+    CharacteristicInstanceId = 0x05,
+    ServiceType = 0x06,
+    ServiceInstanceId = 0x07,
+    Ttl = 0x08,
+    ReturnResponse = 0x09,
+    CharacteristicProperties = 0x0A,
+    GattUserDescription = 0x0B,
+    GattPresentationFormat = 0x0C,
+    GattValidRange = 0x0D,
+    StepValue = 0x0E,
+    ServiceProperties = 0x0F,
+    LinkedServices = 0x10,
+    ValidValues = 0x11,
+    ValidValuesRange = 0x12,
+}
+
+impl TryFrom<u8> for ParamType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use ParamType::*;
+
+        let param_type = match value {
+            0x01 => Value,
+            0x02 => AdditionalAuthorizationData,
+            0x03 => Origin,
+            0x04 => CharacteristicType,
+            0x05 => CharacteristicInstanceId,
+            0x06 => ServiceType,
+            0x07 => ServiceInstanceId,
+            0x08 => Ttl,
+            0x09 => ReturnResponse,
+            0x0A => CharacteristicProperties,
+            0x0B => GattUserDescription,
+            0x0C => GattPresentationFormat,
+            0x0D => GattValidRange,
+            0x0E => StepValue,
+            0x0F => ServiceProperties,
+            0x10 => LinkedServices,
+            0x11 => ValidValues,
+            0x12 => ValidValuesRange,
+            other => return Err(Error::UnknownParamType(other)),
+        };
+
+        Ok(param_type)
+    }
+}
+
+impl From<ParamType> for u8 {
+    fn from(param_type: ParamType) -> Self {
+        param_type as u8
+    }
+}
+
+/// A single parameter from the body of a HAP PDU
+#[derive(Debug, PartialEq)]
+pub struct Param<'a> {
+    pub param_type: ParamType,
+
+    pub value: &'a [u8],
+}
+
+/// Iterator over the parameters in the body of a HAP PDU.
+///
+/// Iteration stops after the first error.
+#[derive(Debug, Clone)]
+pub struct Params<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Params<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Params { data }
+    }
+}
+
+impl<'a> Iterator for Params<'a> {
+    type Item = Result<Param<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (param_type, len, rest) = match self.data {
+            [] => return None,
+            [param_type, len, rest @ ..] => (*param_type, *len as usize, rest),
+            [_] => {
+                self.data = &[];
+                return Some(Err(Error::BadLength));
+            }
+        };
+
+        if rest.len() < len {
+            self.data = &[];
+            return Some(Err(Error::Truncated));
+        }
+
+        let (value, rest) = rest.split_at(len);
+
+        self.data = rest;
+
+        match ParamType::try_from(param_type) {
+            Ok(param_type) => Some(Ok(Param { param_type, value })),
+            Err(e) => {
+                self.data = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    #[test]
+    fn parse_params() {
+        let body = [0x01, 0x02, 0x12, 0x34, 0x08, 0x01, 0x0a, 0x03, 0x00];
+
+        let mut params = Params::new(&body);
+
+        assert_eq!(
+            params.next().unwrap().unwrap(),
+            Param {
+                param_type: ParamType::Value,
+                value: &[0x12, 0x34]
+            }
+        );
+        assert_eq!(
+            params.next().unwrap().unwrap(),
+            Param {
+                param_type: ParamType::Ttl,
+                value: &[0x0a]
+            }
+        );
+        assert_eq!(
+            params.next().unwrap().unwrap(),
+            Param {
+                param_type: ParamType::Origin,
+                value: &[]
+            }
+        );
+        assert!(params.next().is_none());
+    }
+
+    #[test]
+    fn parse_truncated_param() {
+        let body = [0x01, 0x04, 0x12, 0x34];
+
+        let mut params = Params::new(&body);
+
+        assert!(matches!(params.next(), Some(Err(Error::Truncated))));
+        assert!(params.next().is_none());
+    }
+
+    #[test]
+    fn parse_unknown_param() {
+        let body = [0x42, 0x01, 0x00];
+
+        let mut params = Params::new(&body);
+
+        assert!(matches!(
+            params.next(),
+            Some(Err(Error::UnknownParamType(0x42)))
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn parse_arbitrary_params(body in vec(any::<u8>(), 0..64)) {
+            // Every item consumes at least two bytes, so iteration has to terminate
+            prop_assert!(Params::new(&body).count() <= body.len() / 2 + 1);
+        }
+    }
+}
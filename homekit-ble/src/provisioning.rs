@@ -0,0 +1,391 @@
+//! Manufacturing data provisioned on the accessory
+//!
+//! The names shown in the Accessory Information service, the accessory
+//! category and the setup material are written to the accessory during
+//! manufacturing by `homekit-provision`.
+//!
+//! Calculating the SRP verifier for the setup code takes a long time with
+//! the 3072-bit group, and requires the setup code on the accessory. Instead,
+//! the verifier is calculated on the host, so the accessory never sees the
+//! setup code itself.
+//!
+//! The record starts with a magic value, its version and the length of its
+//! content, which consists of TLV8 items. It ends with a CRC-32 of all
+//! preceding bytes, so that erased or corrupted flash is detected.
+
+use core::convert::TryInto;
+
+use rand_core::{CryptoRng, RngCore};
+
+use crate::{
+    identity::{DeviceId, DEVICE_ID_LEN},
+    kv::Crc,
+    pairing::srp::{Verifier, KEY_LEN, SALT_LEN},
+    setup_code::{SetupCode, SetupId, SETUP_ID_LEN},
+    tlv::{encoded_len, Tlv, TlvReader, TlvWriter},
+    Error,
+};
+
+/// Identifies a manufacturing data record
+const MAGIC: &[u8; 4] = b"HKMD";
+
+/// Version of the record layout
+const VERSION: u8 = 1;
+
+/// Magic, version and length of the content
+const HEADER_LEN: usize = MAGIC.len() + 1 + 2;
+
+const CRC_LEN: usize = 4;
+
+pub const MAX_MANUFACTURER_LEN: usize = 64;
+pub const MAX_MODEL_LEN: usize = 32;
+pub const MAX_SERIAL_NUMBER_LEN: usize = 32;
+pub const MAX_HARDWARE_REVISION_LEN: usize = 16;
+
+/// Maximum length of the name, which is also advertised as local name
+pub const MAX_NAME_LEN: usize = 16;
+
+/// Maximum length of an encoded record
+pub const MAX_RECORD_LEN: usize = HEADER_LEN
+    + encoded_len(MAX_MANUFACTURER_LEN)
+    + encoded_len(MAX_MODEL_LEN)
+    + encoded_len(MAX_SERIAL_NUMBER_LEN)
+    + encoded_len(MAX_HARDWARE_REVISION_LEN)
+    + encoded_len(MAX_NAME_LEN)
+    + encoded_len(1)
+    + encoded_len(SALT_LEN)
+    + encoded_len(KEY_LEN)
+    + encoded_len(SETUP_ID_LEN)
+    + encoded_len(DEVICE_ID_LEN)
+    + CRC_LEN;
+
+/// TLV types used to encode the record
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum RecordTlvType {
+    Manufacturer = 0x01,
+    Model = 0x02,
+    SerialNumber = 0x03,
+    HardwareRevision = 0x04,
+    Name = 0x05,
+    Category = 0x06,
+    Salt = 0x07,
+    Verifier = 0x08,
+    SetupId = 0x09,
+    DeviceId = 0x0a,
+}
+
+impl From<RecordTlvType> for u8 {
+    fn from(tlv_type: RecordTlvType) -> Self {
+        tlv_type as u8
+    }
+}
+
+/// Salt and verifier of the setup code, and the identifiers of the accessory
+pub struct SetupMaterial {
+    pub verifier: Verifier,
+
+    pub setup_id: SetupId,
+
+    /// Device ID used until the first factory reset
+    pub device_id: DeviceId,
+}
+
+impl SetupMaterial {
+    /// Calculate the verifier for the setup code, using a random salt.
+    pub fn generate<R: RngCore + CryptoRng>(
+        setup_code: &SetupCode,
+        setup_id: SetupId,
+        device_id: DeviceId,
+        rng: &mut R,
+    ) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        rng.fill_bytes(&mut salt);
+
+        Self::with_salt(setup_code, salt, setup_id, device_id)
+    }
+
+    /// Calculate the verifier for the setup code, using the given salt.
+    pub fn with_salt(
+        setup_code: &SetupCode,
+        salt: [u8; SALT_LEN],
+        setup_id: SetupId,
+        device_id: DeviceId,
+    ) -> Self {
+        SetupMaterial {
+            verifier: Verifier::new(salt, &setup_code.to_bytes()),
+            setup_id,
+            device_id,
+        }
+    }
+}
+
+/// Information about the accessory, which is written during manufacturing
+pub struct ManufacturingData<'a> {
+    pub manufacturer: &'a str,
+
+    pub model: &'a str,
+
+    pub serial_number: &'a str,
+
+    pub hardware_revision: &'a str,
+
+    pub name: &'a str,
+
+    /// Accessory category, which is advertised and part of the setup payload
+    pub category: u8,
+
+    pub setup: SetupMaterial,
+}
+
+impl<'a> ManufacturingData<'a> {
+    /// Encode the record, which is at most `MAX_RECORD_LEN` bytes long.
+    ///
+    /// Fails if a string is empty or too long.
+    pub fn encode<'b>(&self, buffer: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        self.check_lengths()?;
+
+        if buffer.len() < HEADER_LEN + CRC_LEN {
+            return Err(Error::InsufficientBuffer);
+        }
+
+        let (header, rest) = buffer.split_at_mut(HEADER_LEN);
+        let content_len = rest.len() - CRC_LEN;
+
+        let mut writer = TlvWriter::new(&mut rest[..content_len]);
+
+        writer
+            .push(Tlv::new(RecordTlvType::Manufacturer, self.manufacturer))?
+            .push(Tlv::new(RecordTlvType::Model, self.model))?
+            .push(Tlv::new(RecordTlvType::SerialNumber, self.serial_number))?
+            .push(Tlv::new(
+                RecordTlvType::HardwareRevision,
+                self.hardware_revision,
+            ))?
+            .push(Tlv::new(RecordTlvType::Name, self.name))?
+            .push(Tlv::new(RecordTlvType::Category, self.category))?
+            .push(Tlv::new(RecordTlvType::Salt, &self.setup.verifier.salt[..]))?
+            .push(Tlv::new(
+                RecordTlvType::Verifier,
+                &self.setup.verifier.verifier[..],
+            ))?
+            .push(Tlv::new(
+                RecordTlvType::SetupId,
+                &self.setup.setup_id.as_bytes()[..],
+            ))?
+            .push(Tlv::new(
+                RecordTlvType::DeviceId,
+                &self.setup.device_id.as_bytes()[..],
+            ))?;
+
+        let content_len = writer.len();
+
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()] = VERSION;
+        header[MAGIC.len() + 1..].copy_from_slice(&(content_len as u16).to_le_bytes());
+
+        let len = HEADER_LEN + content_len;
+
+        let mut crc = Crc::new();
+        crc.update(&buffer[..len]);
+        buffer[len..][..CRC_LEN].copy_from_slice(&crc.finish().to_le_bytes());
+
+        Ok(&buffer[..len + CRC_LEN])
+    }
+
+    /// Decode a record written by `encode`, which may be followed by other data.
+    ///
+    /// Fails with `Error::InvalidRecord` if the record is missing, corrupted or
+    /// has an unsupported version.
+    pub fn decode(data: &'a [u8]) -> Result<Self, Error> {
+        let header = data.get(..HEADER_LEN).ok_or(Error::InvalidRecord)?;
+
+        if &header[..MAGIC.len()] != MAGIC || header[MAGIC.len()] != VERSION {
+            return Err(Error::InvalidRecord);
+        }
+
+        let content_len = usize::from(u16::from_le_bytes([header[5], header[6]]));
+        let len = HEADER_LEN + content_len;
+
+        let crc = data.get(len..len + CRC_LEN).ok_or(Error::InvalidRecord)?;
+
+        let mut checksum = Crc::new();
+        checksum.update(&data[..len]);
+
+        if checksum.finish().to_le_bytes() != crc {
+            return Err(Error::InvalidRecord);
+        }
+
+        let record = Self::parse(&data[HEADER_LEN..len]).map_err(|_| Error::InvalidRecord)?;
+
+        record.check_lengths()?;
+
+        Ok(record)
+    }
+
+    fn parse(content: &'a [u8]) -> Result<Self, Error> {
+        let reader = TlvReader::new(content);
+
+        let mut verifier = Verifier {
+            salt: [0; SALT_LEN],
+            verifier: [0; KEY_LEN],
+        };
+
+        verifier.salt = reader
+            .get_bytes(RecordTlvType::Salt)?
+            .try_into()
+            .map_err(|_| Error::InvalidTlvValue(RecordTlvType::Salt as u8))?;
+
+        if reader
+            .get(RecordTlvType::Verifier)?
+            .copy_into(&mut verifier.verifier)?
+            .len()
+            != KEY_LEN
+        {
+            return Err(Error::InvalidTlvValue(RecordTlvType::Verifier as u8));
+        }
+
+        let device_id = reader
+            .get_bytes(RecordTlvType::DeviceId)?
+            .try_into()
+            .map(DeviceId)
+            .map_err(|_| Error::InvalidTlvValue(RecordTlvType::DeviceId as u8))?;
+
+        Ok(ManufacturingData {
+            manufacturer: reader.get_str(RecordTlvType::Manufacturer)?,
+            model: reader.get_str(RecordTlvType::Model)?,
+            serial_number: reader.get_str(RecordTlvType::SerialNumber)?,
+            hardware_revision: reader.get_str(RecordTlvType::HardwareRevision)?,
+            name: reader.get_str(RecordTlvType::Name)?,
+            category: reader.get_u8(RecordTlvType::Category)?,
+            setup: SetupMaterial {
+                verifier,
+                setup_id: SetupId::new(reader.get_bytes(RecordTlvType::SetupId)?)?,
+                device_id,
+            },
+        })
+    }
+
+    fn check_lengths(&self) -> Result<(), Error> {
+        let fields = [
+            (self.manufacturer, MAX_MANUFACTURER_LEN),
+            (self.model, MAX_MODEL_LEN),
+            (self.serial_number, MAX_SERIAL_NUMBER_LEN),
+            (self.hardware_revision, MAX_HARDWARE_REVISION_LEN),
+            (self.name, MAX_NAME_LEN),
+        ];
+
+        if fields
+            .iter()
+            .any(|(value, max_len)| value.is_empty() || value.len() > *max_len)
+        {
+            return Err(Error::InvalidRecord);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pairing::TestRng;
+
+    fn record() -> ManufacturingData<'static> {
+        ManufacturingData {
+            manufacturer: "Dominik Corp.",
+            model: "M001",
+            serial_number: "S12345",
+            hardware_revision: "1.0.0",
+            name: "hokt",
+            category: 10,
+            setup: SetupMaterial::generate(
+                &SetupCode::parse(b"518-08-582").unwrap(),
+                SetupId::new(b"1QJ8").unwrap(),
+                DeviceId([0xc8, 0xd8, 0x3d, 0x0a, 0xb0, 0x4b]),
+                &mut TestRng(5),
+            ),
+        }
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let record = record();
+
+        // The record is followed by erased flash
+        let mut page = [0xff; 4096];
+        let len = record.encode(&mut page).unwrap().len();
+
+        let decoded = ManufacturingData::decode(&page).unwrap();
+
+        assert_eq!(decoded.manufacturer, "Dominik Corp.");
+        assert_eq!(decoded.model, "M001");
+        assert_eq!(decoded.serial_number, "S12345");
+        assert_eq!(decoded.hardware_revision, "1.0.0");
+        assert_eq!(decoded.name, "hokt");
+        assert_eq!(decoded.category, 10);
+        assert_eq!(decoded.setup.setup_id, record.setup.setup_id);
+        assert_eq!(decoded.setup.device_id, record.setup.device_id);
+        assert_eq!(decoded.setup.verifier.salt, record.setup.verifier.salt);
+
+        // The verifier matches the setup code
+        let expected = Verifier::new(record.setup.verifier.salt, b"518-08-582");
+        assert_eq!(&decoded.setup.verifier.verifier[..], &expected.verifier[..]);
+
+        assert!(len <= MAX_RECORD_LEN);
+    }
+
+    #[test]
+    fn maximum_length() {
+        let long = core::str::from_utf8(&[b'x'; MAX_MANUFACTURER_LEN]).unwrap();
+
+        let record = ManufacturingData {
+            manufacturer: long,
+            model: &long[..MAX_MODEL_LEN],
+            serial_number: &long[..MAX_SERIAL_NUMBER_LEN],
+            hardware_revision: &long[..MAX_HARDWARE_REVISION_LEN],
+            name: &long[..MAX_NAME_LEN],
+            ..record()
+        };
+
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+        assert_eq!(record.encode(&mut buffer).unwrap().len(), MAX_RECORD_LEN);
+
+        let record = ManufacturingData {
+            name: &long[..MAX_NAME_LEN + 1],
+            ..record
+        };
+
+        assert!(matches!(
+            record.encode(&mut [0u8; 2 * MAX_RECORD_LEN]),
+            Err(Error::InvalidRecord)
+        ));
+    }
+
+    #[test]
+    fn invalid_records() {
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+        let len = record().encode(&mut buffer).unwrap().len();
+
+        // Erased flash
+        assert!(matches!(
+            ManufacturingData::decode(&[0xff; MAX_RECORD_LEN]),
+            Err(Error::InvalidRecord)
+        ));
+
+        assert!(matches!(
+            ManufacturingData::decode(&buffer[..len - 1]),
+            Err(Error::InvalidRecord)
+        ));
+
+        // A record of a later version, and a corrupted serial number
+        for offset in [MAGIC.len(), 30] {
+            let mut corrupted = buffer;
+            corrupted[offset] ^= 0x01;
+
+            assert!(matches!(
+                ManufacturingData::decode(&corrupted[..len]),
+                Err(Error::InvalidRecord)
+            ));
+        }
+    }
+}
@@ -0,0 +1,889 @@
+//! QR codes for the setup payload
+//!
+//! A small QR code encoder, which doesn't need an allocator. It supports the
+//! alphanumeric and byte modes, and versions up to 10, which is more than
+//! enough for the `X-HM://` setup payload. The mode and the smallest version
+//! which fits the data are selected automatically.
+
+use core::str;
+
+use crate::Error;
+
+/// Largest supported version
+pub const MAX_VERSION: u8 = 10;
+
+/// Width and height of the largest supported QR code, in modules
+pub const MAX_SIZE: usize = 17 + 4 * MAX_VERSION as usize;
+
+/// Width of the light border around the code, in modules
+pub const QUIET_ZONE: usize = 4;
+
+/// Number of codewords of the largest supported version
+const MAX_CODEWORDS: usize = 346;
+
+/// Maximum number of error correction codewords in a block
+const MAX_BLOCK_ECC_LEN: usize = 30;
+
+const MODULE_BYTES: usize = (MAX_SIZE * MAX_SIZE).div_ceil(8);
+
+/// Characters which can be encoded in alphanumeric mode
+const ALPHANUMERIC: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+/// Padding bytes, which are added alternately after the data
+const PADDING: [u8; 2] = [0xec, 0x11];
+
+/// Error correction level, from the lowest to the highest redundancy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EccLevel {
+    /// Recovers about 7% of the data
+    Low,
+    /// Recovers about 15% of the data
+    Medium,
+    /// Recovers about 25% of the data
+    Quartile,
+    /// Recovers about 30% of the data
+    High,
+}
+
+impl EccLevel {
+    /// Value of the level in the format information
+    fn format_bits(self) -> u32 {
+        match self {
+            EccLevel::Low => 1,
+            EccLevel::Medium => 0,
+            EccLevel::Quartile => 3,
+            EccLevel::High => 2,
+        }
+    }
+}
+
+/// Error correction codewords per block, by level and version
+const ECC_CODEWORDS_PER_BLOCK: [[u8; MAX_VERSION as usize]; 4] = [
+    [7, 10, 15, 20, 26, 18, 20, 24, 30, 18],
+    [10, 16, 26, 18, 24, 16, 18, 22, 22, 26],
+    [13, 22, 18, 26, 18, 24, 18, 22, 20, 24],
+    [17, 28, 22, 16, 22, 28, 26, 26, 24, 28],
+];
+
+/// Number of error correction blocks, by level and version
+const ECC_BLOCKS: [[u8; MAX_VERSION as usize]; 4] = [
+    [1, 1, 1, 1, 1, 2, 2, 2, 2, 4],
+    [1, 1, 1, 2, 2, 4, 4, 4, 5, 5],
+    [1, 1, 2, 2, 4, 4, 6, 6, 8, 8],
+    [1, 1, 2, 4, 4, 4, 5, 6, 8, 8],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Alphanumeric,
+    Byte,
+}
+
+impl Mode {
+    fn indicator(self) -> u32 {
+        match self {
+            Mode::Alphanumeric => 0x2,
+            Mode::Byte => 0x4,
+        }
+    }
+
+    fn char_count_bits(self, version: u8) -> usize {
+        match (self, version) {
+            (Mode::Alphanumeric, 1..=9) => 9,
+            (Mode::Alphanumeric, _) => 11,
+            (Mode::Byte, 1..=9) => 8,
+            (Mode::Byte, _) => 16,
+        }
+    }
+
+    /// Number of bits needed to encode `len` characters, including the header.
+    fn encoded_bits(self, len: usize, version: u8) -> usize {
+        let data_bits = match self {
+            Mode::Alphanumeric => 11 * (len / 2) + 6 * (len % 2),
+            Mode::Byte => 8 * len,
+        };
+
+        4 + self.char_count_bits(version) + data_bits
+    }
+}
+
+/// Matrix of modules, which are either dark or light
+pub struct QrCode {
+    version: u8,
+
+    size: usize,
+
+    modules: [u8; MODULE_BYTES],
+
+    /// Modules which are part of a pattern or of the format and version
+    /// information, and which are therefore not masked.
+    functions: [u8; MODULE_BYTES],
+}
+
+impl QrCode {
+    /// Encode the data in the smallest possible QR code.
+    ///
+    /// The alphanumeric mode is used if possible, the byte mode otherwise.
+    pub fn encode(data: &[u8], ecc: EccLevel) -> Result<Self, Error> {
+        let mode = if data.iter().all(|c| ALPHANUMERIC.contains(c)) {
+            Mode::Alphanumeric
+        } else {
+            Mode::Byte
+        };
+
+        let version = (1..=MAX_VERSION)
+            .find(|&version| {
+                data.len() < 1 << mode.char_count_bits(version)
+                    && mode.encoded_bits(data.len(), version) <= 8 * data_codewords(version, ecc)
+            })
+            .ok_or(Error::DataTooLong)?;
+
+        let mut bits = BitBuffer::new();
+
+        bits.append(mode.indicator(), 4);
+        bits.append(data.len() as u32, mode.char_count_bits(version));
+
+        match mode {
+            Mode::Alphanumeric => {
+                for pair in data.chunks(2) {
+                    let value = pair.iter().fold(0, |value, c| {
+                        value * 45 + ALPHANUMERIC.iter().position(|a| a == c).unwrap_or(0) as u32
+                    });
+
+                    bits.append(value, 1 + 5 * pair.len());
+                }
+            }
+            Mode::Byte => {
+                for byte in data {
+                    bits.append(u32::from(*byte), 8);
+                }
+            }
+        }
+
+        let capacity = 8 * data_codewords(version, ecc);
+
+        // Terminator, and padding to a full byte
+        bits.append(0, (capacity - bits.len).min(4));
+        bits.append(0, (8 - bits.len % 8) % 8);
+
+        for padding in PADDING.iter().cycle() {
+            if bits.len >= capacity {
+                break;
+            }
+            bits.append(u32::from(*padding), 8);
+        }
+
+        let mut codewords = [0u8; MAX_CODEWORDS];
+        let len = add_ecc_and_interleave(&bits.data[..capacity / 8], version, ecc, &mut codewords);
+
+        let mut code = QrCode {
+            version,
+            size: 17 + 4 * usize::from(version),
+            modules: [0; MODULE_BYTES],
+            functions: [0; MODULE_BYTES],
+        };
+
+        code.draw_function_patterns();
+        code.draw_codewords(&codewords[..len]);
+
+        // Use the mask which results in the lowest penalty
+        let mut best_mask = 0;
+        let mut best_penalty = u32::MAX;
+
+        for mask in 0..8 {
+            code.apply_mask(mask);
+            code.draw_format_bits(ecc, mask);
+
+            let penalty = code.penalty();
+            if penalty < best_penalty {
+                best_mask = mask;
+                best_penalty = penalty;
+            }
+
+            // Masking twice restores the original modules
+            code.apply_mask(mask);
+        }
+
+        code.apply_mask(best_mask);
+        code.draw_format_bits(ecc, best_mask);
+
+        Ok(code)
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Width and height of the code, in modules, without the quiet zone.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Check if the module at the given column and row is dark.
+    pub fn module(&self, x: usize, y: usize) -> bool {
+        get_bit(&self.modules, y * self.size + x)
+    }
+
+    /// Render the code as text, with two characters per module, and call
+    /// `line` for each row, including the rows of the quiet zone.
+    ///
+    /// Dark modules are rendered as `##`, light modules as spaces.
+    pub fn render_ascii<F: FnMut(&str)>(&self, mut line: F) {
+        let width = self.size + 2 * QUIET_ZONE;
+
+        let mut buffer = [b' '; 2 * (MAX_SIZE + 2 * QUIET_ZONE)];
+
+        for y in 0..width {
+            for x in 0..width {
+                let dark = (QUIET_ZONE..QUIET_ZONE + self.size).contains(&x)
+                    && (QUIET_ZONE..QUIET_ZONE + self.size).contains(&y)
+                    && self.module(x - QUIET_ZONE, y - QUIET_ZONE);
+
+                let c = if dark { b'#' } else { b' ' };
+                buffer[2 * x] = c;
+                buffer[2 * x + 1] = c;
+            }
+
+            line(str::from_utf8(&buffer[..2 * width]).unwrap_or_default());
+        }
+    }
+
+    fn set_module(&mut self, x: usize, y: usize, dark: bool) {
+        set_bit(&mut self.modules, y * self.size + x, dark);
+    }
+
+    fn is_function(&self, x: usize, y: usize) -> bool {
+        get_bit(&self.functions, y * self.size + x)
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.set_module(x, y, dark);
+        set_bit(&mut self.functions, y * self.size + x, true);
+    }
+
+    fn draw_function_patterns(&mut self) {
+        let size = self.size;
+
+        // Timing patterns
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        // Finder patterns, which overwrite parts of the timing patterns
+        self.draw_finder_pattern(3, 3);
+        self.draw_finder_pattern(size - 4, 3);
+        self.draw_finder_pattern(3, size - 4);
+
+        // Alignment patterns, except where they would overlap the finder patterns
+        let (positions, count) = alignment_positions(self.version);
+
+        for (i, x) in positions[..count].iter().enumerate() {
+            for (j, y) in positions[..count].iter().enumerate() {
+                let corner = (i == 0 || j == 0) && (i + j == 0 || i + j == count - 1);
+
+                if !corner {
+                    self.draw_alignment_pattern(*x, *y);
+                }
+            }
+        }
+
+        // Reserve the format information, which is drawn after masking
+        self.draw_format_bits(EccLevel::Low, 0);
+        self.draw_version_bits();
+    }
+
+    /// Draw a finder pattern and its separator, centered at the given module.
+    fn draw_finder_pattern(&mut self, x: usize, y: usize) {
+        for dy in -4..=4isize {
+            for dx in -4..=4isize {
+                let distance = dx.abs().max(dy.abs());
+
+                let xx = x as isize + dx;
+                let yy = y as isize + dy;
+
+                if (0..self.size as isize).contains(&xx) && (0..self.size as isize).contains(&yy) {
+                    self.set_function(xx as usize, yy as usize, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    /// Draw an alignment pattern, centered at the given module.
+    fn draw_alignment_pattern(&mut self, x: usize, y: usize) {
+        for dy in 0..5 {
+            for dx in 0..5 {
+                let distance = (dx as isize - 2).abs().max((dy as isize - 2).abs());
+
+                self.set_function(x + dx - 2, y + dy - 2, distance != 1);
+            }
+        }
+    }
+
+    /// Draw both copies of the format information, and the dark module.
+    fn draw_format_bits(&mut self, ecc: EccLevel, mask: u8) {
+        let bits = format_bits(ecc, mask);
+        let size = self.size;
+
+        // First copy, around the top left finder pattern
+        for i in 0..6 {
+            self.set_function(8, i, bit(bits, i));
+        }
+        self.set_function(8, 7, bit(bits, 6));
+        self.set_function(8, 8, bit(bits, 7));
+        self.set_function(7, 8, bit(bits, 8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(bits, i));
+        }
+
+        // Second copy, split between the other finder patterns
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(bits, i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(bits, i));
+        }
+
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Draw both copies of the version information, which is only present from version 7.
+    fn draw_version_bits(&mut self) {
+        if self.version < 7 {
+            return;
+        }
+
+        let bits = version_bits(self.version);
+
+        for i in 0..18 {
+            let a = self.size - 11 + i % 3;
+            let b = i / 3;
+
+            self.set_function(a, b, bit(bits, i));
+            self.set_function(b, a, bit(bits, i));
+        }
+    }
+
+    /// Draw the codewords in the zigzag pattern, starting at the bottom right corner.
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+
+        let mut right = size - 1;
+        loop {
+            // The vertical timing pattern is skipped
+            if right == 6 {
+                right = 5;
+            }
+
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+
+                    if !self.is_function(x, y) && i < codewords.len() * 8 {
+                        self.set_module(x, y, get_bit(codewords, i));
+                        i += 1;
+                    }
+                }
+            }
+
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// Invert the data modules selected by the mask pattern.
+    fn apply_mask(&mut self, mask: u8) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+
+                if invert && !self.is_function(x, y) {
+                    let dark = self.module(x, y);
+                    self.set_module(x, y, !dark);
+                }
+            }
+        }
+    }
+
+    /// Penalty of the current modules, which is minimised by the choice of the mask.
+    fn penalty(&self) -> u32 {
+        let size = self.size;
+        let mut penalty = 0;
+
+        // Rows and columns are checked the same way
+        for transposed in [false, true] {
+            let module = |i: usize, j: usize| {
+                if transposed {
+                    self.module(i, j)
+                } else {
+                    self.module(j, i)
+                }
+            };
+
+            for i in 0..size {
+                // Runs of five or more modules of the same color
+                let mut run = 1;
+                for j in 1..size {
+                    if module(i, j) == module(i, j - 1) {
+                        run += 1;
+                    } else {
+                        run = 1;
+                    }
+
+                    if run == 5 {
+                        penalty += 3;
+                    } else if run > 5 {
+                        penalty += 1;
+                    }
+                }
+
+                // Patterns which look like a finder pattern
+                for j in 0..size.saturating_sub(10) {
+                    let window = (0..11).fold(0u16, |w, k| w << 1 | u16::from(module(i, j + k)));
+
+                    if window == 0b101_1101_0000 || window == 0b000_0101_1101 {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        // Blocks of 2x2 modules of the same color
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.module(x, y);
+
+                if self.module(x + 1, y) == dark
+                    && self.module(x, y + 1) == dark
+                    && self.module(x + 1, y + 1) == dark
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // Imbalance of dark and light modules
+        let total = size * size;
+        let dark = (0..total).filter(|i| get_bit(&self.modules, *i)).count();
+
+        let percent = dark * 100 / total;
+        penalty += 10 * (percent.abs_diff(50) / 5) as u32;
+
+        penalty
+    }
+}
+
+/// Bits of data, which are filled from the most significant bit of each byte
+struct BitBuffer {
+    data: [u8; MAX_CODEWORDS],
+
+    /// Number of bits
+    len: usize,
+}
+
+impl BitBuffer {
+    fn new() -> Self {
+        BitBuffer {
+            data: [0; MAX_CODEWORDS],
+            len: 0,
+        }
+    }
+
+    /// Append the lowest `count` bits of the value.
+    fn append(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            set_bit(&mut self.data, self.len, (value >> i) & 1 != 0);
+            self.len += 1;
+        }
+    }
+}
+
+fn get_bit(bytes: &[u8], index: usize) -> bool {
+    bytes[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+fn set_bit(bytes: &mut [u8], index: usize, value: bool) {
+    if value {
+        bytes[index / 8] |= 0x80 >> (index % 8);
+    } else {
+        bytes[index / 8] &= !(0x80 >> (index % 8));
+    }
+}
+
+fn bit(value: u32, index: usize) -> bool {
+    (value >> index) & 1 != 0
+}
+
+/// Number of modules available for codewords, after the function patterns.
+fn raw_data_modules(version: u8) -> usize {
+    let version = usize::from(version);
+
+    let mut modules = (16 * version + 128) * version + 64;
+
+    if version >= 2 {
+        let alignment_count = version / 7 + 2;
+        modules -= (25 * alignment_count - 10) * alignment_count - 55;
+
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+
+    modules
+}
+
+fn ecc_table_entry(table: &[[u8; MAX_VERSION as usize]; 4], version: u8, ecc: EccLevel) -> usize {
+    let level = match ecc {
+        EccLevel::Low => 0,
+        EccLevel::Medium => 1,
+        EccLevel::Quartile => 2,
+        EccLevel::High => 3,
+    };
+
+    usize::from(table[level][usize::from(version) - 1])
+}
+
+/// Number of data codewords, without the error correction codewords.
+fn data_codewords(version: u8, ecc: EccLevel) -> usize {
+    raw_data_modules(version) / 8
+        - ecc_table_entry(&ECC_CODEWORDS_PER_BLOCK, version, ecc)
+            * ecc_table_entry(&ECC_BLOCKS, version, ecc)
+}
+
+/// Positions of the alignment patterns, which are used for both rows and columns.
+fn alignment_positions(version: u8) -> ([usize; 3], usize) {
+    let mut positions = [0; 3];
+
+    if version == 1 {
+        return (positions, 0);
+    }
+
+    let size = 17 + 4 * usize::from(version);
+    let count = usize::from(version) / 7 + 2;
+    let step = (usize::from(version) * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+
+    positions[0] = 6;
+    for i in 1..count {
+        positions[count - i] = size - 7 - (i - 1) * step;
+    }
+
+    (positions, count)
+}
+
+/// Format information, protected by a BCH code and masked.
+fn format_bits(ecc: EccLevel, mask: u8) -> u32 {
+    let data = ecc.format_bits() << 3 | u32::from(mask);
+
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+
+    (data << 10 | remainder) ^ 0x5412
+}
+
+/// Version information, protected by a BCH code.
+fn version_bits(version: u8) -> u32 {
+    let mut remainder = u32::from(version);
+    for _ in 0..12 {
+        remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+    }
+
+    u32::from(version) << 12 | remainder
+}
+
+/// Split the data into blocks, add the error correction codewords to each
+/// block, and interleave the blocks. Returns the number of codewords.
+fn add_ecc_and_interleave(data: &[u8], version: u8, ecc: EccLevel, result: &mut [u8]) -> usize {
+    let block_count = ecc_table_entry(&ECC_BLOCKS, version, ecc);
+    let block_ecc_len = ecc_table_entry(&ECC_CODEWORDS_PER_BLOCK, version, ecc);
+    let raw_codewords = raw_data_modules(version) / 8;
+
+    // The last blocks are one codeword longer than the first ones
+    let short_block_count = block_count - raw_codewords % block_count;
+    let short_block_data_len = raw_codewords / block_count - block_ecc_len;
+
+    let block_start =
+        |block: usize| block * short_block_data_len + block.saturating_sub(short_block_count);
+    let block_data_len =
+        |block: usize| short_block_data_len + usize::from(block >= short_block_count);
+
+    let mut divisor = [0u8; MAX_BLOCK_ECC_LEN];
+    reed_solomon_divisor(&mut divisor[..block_ecc_len]);
+
+    let mut len = 0;
+
+    for i in 0..=short_block_data_len {
+        for block in 0..block_count {
+            if i < block_data_len(block) {
+                result[len] = data[block_start(block) + i];
+                len += 1;
+            }
+        }
+    }
+
+    // The error correction codewords of each block are written with a
+    // stride of `block_count`, which interleaves them.
+    for block in 0..block_count {
+        let block_data = &data[block_start(block)..][..block_data_len(block)];
+
+        let mut remainder = [0u8; MAX_BLOCK_ECC_LEN];
+        reed_solomon_remainder(
+            block_data,
+            &divisor[..block_ecc_len],
+            &mut remainder[..block_ecc_len],
+        );
+
+        for (i, codeword) in remainder[..block_ecc_len].iter().enumerate() {
+            result[len + i * block_count + block] = *codeword;
+        }
+    }
+
+    len + block_count * block_ecc_len
+}
+
+/// Multiply in GF(2^8), with the polynomial used by QR codes.
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z = 0u8;
+
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x1d);
+        z ^= ((y >> i) & 1) * x;
+    }
+
+    z
+}
+
+/// Generator polynomial with the degree given by the length of the divisor,
+/// without the leading coefficient, which is always one.
+fn reed_solomon_divisor(divisor: &mut [u8]) {
+    let degree = divisor.len();
+
+    divisor.fill(0);
+    divisor[degree - 1] = 1;
+
+    let mut root = 1;
+
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_multiply(divisor[j], root);
+
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+
+        root = gf_multiply(root, 0x02);
+    }
+}
+
+/// Error correction codewords of the data, which are the remainder of the
+/// polynomial division by the divisor.
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8], remainder: &mut [u8]) {
+    remainder.fill(0);
+
+    for byte in data {
+        let factor = byte ^ remainder[0];
+
+        remainder.copy_within(1.., 0);
+        remainder[remainder.len() - 1] = 0;
+
+        for (r, d) in remainder.iter_mut().zip(divisor) {
+            *r ^= gf_multiply(*d, factor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Read the first copy of the format information back from the code.
+    fn read_format_bits(code: &QrCode) -> u32 {
+        (0..15).fold(0, |bits, i| {
+            let (x, y) = match i {
+                0..=5 => (8, i),
+                6 => (8, 7),
+                7 => (8, 8),
+                8 => (7, 8),
+                _ => (14 - i, 8),
+            };
+
+            bits | u32::from(code.module(x, y)) << i
+        })
+    }
+
+    #[test]
+    fn hello_world_codewords() {
+        // Example 1-M code from the QR code tutorial at thonky.com
+        let mut codewords = [0u8; MAX_CODEWORDS];
+
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+
+        let len = add_ecc_and_interleave(&data, 1, EccLevel::Medium, &mut codewords);
+
+        assert_eq!(len, 26);
+        assert_eq!(&codewords[..16], &data);
+        assert_eq!(
+            &codewords[16..26],
+            &[196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+    }
+
+    #[test]
+    fn interleave_blocks() {
+        // Version 5-Q has two blocks with 15 data codewords, and two with 16
+        let data: Vec<u8> = (0..62).collect();
+
+        let mut codewords = [0u8; MAX_CODEWORDS];
+        let len = add_ecc_and_interleave(&data, 5, EccLevel::Quartile, &mut codewords);
+
+        assert_eq!(len, 134);
+        assert_eq!(&codewords[..8], &[0, 15, 30, 46, 1, 16, 31, 47]);
+        assert_eq!(&codewords[56..62], &[14, 29, 44, 60, 45, 61]);
+
+        // The error correction codewords of the last block
+        let mut divisor = [0u8; 18];
+        reed_solomon_divisor(&mut divisor);
+
+        let mut remainder = [0u8; 18];
+        reed_solomon_remainder(&data[46..], &divisor, &mut remainder);
+
+        for (i, codeword) in remainder.iter().enumerate() {
+            assert_eq!(codewords[62 + 4 * i + 3], *codeword);
+        }
+    }
+
+    #[test]
+    fn format_information() {
+        assert_eq!(format_bits(EccLevel::Low, 0), 0b111011111000100);
+        assert_eq!(format_bits(EccLevel::Medium, 0), 0b101010000010010);
+        assert_eq!(format_bits(EccLevel::Quartile, 0), 0b011010101011111);
+        assert_eq!(format_bits(EccLevel::High, 0), 0b001011010001001);
+    }
+
+    #[test]
+    fn version_information() {
+        assert_eq!(version_bits(7), 0b000111110010010100);
+        assert_eq!(version_bits(10), 0b001010010011010011);
+    }
+
+    #[test]
+    fn capacities() {
+        assert_eq!(data_codewords(1, EccLevel::Low), 19);
+        assert_eq!(data_codewords(1, EccLevel::High), 9);
+        assert_eq!(data_codewords(5, EccLevel::Quartile), 62);
+        assert_eq!(data_codewords(10, EccLevel::Medium), 216);
+        assert_eq!(data_codewords(10, EccLevel::High), 122);
+
+        assert_eq!(raw_data_modules(MAX_VERSION) / 8, MAX_CODEWORDS);
+    }
+
+    #[test]
+    fn alignment_pattern_positions() {
+        assert_eq!(alignment_positions(1).1, 0);
+        assert_eq!(alignment_positions(2), ([6, 18, 0], 2));
+        assert_eq!(alignment_positions(7), ([6, 22, 38], 3));
+        assert_eq!(alignment_positions(10), ([6, 28, 50], 3));
+    }
+
+    #[test]
+    fn setup_payload_fits_version_1() {
+        let code = QrCode::encode(b"X-HM://00A4W1PVQ1QJ8", EccLevel::Medium).unwrap();
+
+        assert_eq!(code.version(), 1);
+        assert_eq!(code.size(), 21);
+
+        // The format information matches the level, and one of the masks
+        let format = read_format_bits(&code);
+        assert!((0..8).any(|mask| format_bits(EccLevel::Medium, mask) == format));
+
+        // Finder pattern in the top left corner
+        for i in 0..7 {
+            assert!(code.module(i, 0));
+            assert!(code.module(0, i));
+            assert!(!code.module(i, 7));
+        }
+        assert!(code.module(3, 3));
+        assert!(!code.module(1, 1));
+
+        // Dark module
+        assert!(code.module(8, code.size() - 8));
+    }
+
+    #[test]
+    fn select_mode_and_version() {
+        // Lower case letters need byte mode
+        assert_eq!(
+            QrCode::encode(b"x-hm://00a4w1pvq1qj8", EccLevel::Medium)
+                .unwrap()
+                .version(),
+            2
+        );
+
+        assert_eq!(
+            QrCode::encode(b"X-HM://00A4W1PVQ1QJ8", EccLevel::High)
+                .unwrap()
+                .version(),
+            2
+        );
+
+        let code = QrCode::encode(&[b'a'; 200], EccLevel::Low).unwrap();
+        assert_eq!(code.version(), 9);
+        assert_eq!(code.size(), 53);
+
+        // Version information is read the same from both copies
+        let code = QrCode::encode(&[b'a'; 110], EccLevel::Medium).unwrap();
+        assert_eq!(code.version(), 7);
+
+        for i in 0..18 {
+            let a = code.size() - 11 + i % 3;
+            let b = i / 3;
+
+            assert_eq!(code.module(a, b), bit(version_bits(7), i));
+            assert_eq!(code.module(b, a), bit(version_bits(7), i));
+        }
+
+        assert!(matches!(
+            QrCode::encode(&[0; 300], EccLevel::Low),
+            Err(Error::DataTooLong)
+        ));
+    }
+
+    #[test]
+    fn render() {
+        let code = QrCode::encode(b"X-HM://00A4W1PVQ1QJ8", EccLevel::Medium).unwrap();
+
+        let mut lines = Vec::new();
+        code.render_ascii(|line| lines.push(line.to_string()));
+
+        let width = 2 * (code.size() + 2 * QUIET_ZONE);
+
+        assert_eq!(lines.len(), code.size() + 2 * QUIET_ZONE);
+        assert!(lines.iter().all(|line| line.len() == width));
+
+        assert_eq!(lines[0].trim(), "");
+        assert_eq!(&lines[QUIET_ZONE][..8], "        ");
+        assert_eq!(&lines[QUIET_ZONE][8..22], "##############");
+    }
+}
@@ -0,0 +1,323 @@
+//! Secure sessions with verified controllers
+//!
+//! After Pair Verify, every HAP-BLE PDU fragment written or read by the
+//! controller is encrypted with ChaCha20-Poly1305, followed by a 16 byte
+//! authentication tag. Each direction uses its own key and a 64-bit nonce
+//! counter, which starts at zero and is incremented for every fragment.
+//! The counters are not transmitted, so a fragment which is lost, replayed
+//! or reordered fails authentication, which ends the session.
+//!
+//! The keys are discarded when the session ends, or is dropped.
+
+use core::ptr;
+
+use crate::{
+    crypto::{self, KEY_LEN},
+    pairing::{Pairing, VerifiedSession},
+    Error,
+};
+
+pub use crate::crypto::TAG_LEN;
+
+/// Encryption of the PDUs exchanged with a verified controller
+pub struct SecureSession {
+    controller: Pairing,
+
+    /// Key to encrypt data sent by the accessory
+    encrypt_key: [u8; KEY_LEN],
+
+    /// Key to decrypt data sent by the controller
+    decrypt_key: [u8; KEY_LEN],
+
+    /// Nonce of the next fragment sent by the accessory
+    encrypt_counter: u64,
+
+    /// Nonce of the next fragment expected from the controller
+    decrypt_counter: u64,
+
+    closed: bool,
+}
+
+impl SecureSession {
+    /// Start a session with the keys derived by Pair Verify.
+    pub fn new(session: VerifiedSession) -> Self {
+        SecureSession {
+            controller: session.controller,
+            encrypt_key: session.accessory_to_controller,
+            decrypt_key: session.controller_to_accessory,
+            encrypt_counter: 0,
+            decrypt_counter: 0,
+            closed: false,
+        }
+    }
+
+    /// The controller which has been verified for this session.
+    pub fn controller(&self) -> &Pairing {
+        &self.controller
+    }
+
+    /// Close the session, and discard the keys.
+    fn close(&mut self) {
+        // Volatile writes, so that they aren't optimized away when the session is dropped
+        unsafe {
+            ptr::write_volatile(&mut self.encrypt_key, [0; KEY_LEN]);
+            ptr::write_volatile(&mut self.decrypt_key, [0; KEY_LEN]);
+        }
+
+        self.closed = true;
+    }
+
+    /// Decrypt a fragment written by the controller in place.
+    ///
+    /// If the fragment can't be authenticated, the session is closed.
+    pub fn decrypt<'b>(&mut self, data: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        let nonce = next_nonce(self.closed, self.decrypt_counter)?;
+
+        match crypto::decrypt(&self.decrypt_key, &nonce, &[], data) {
+            Ok(data) => {
+                self.decrypt_counter += 1;
+                Ok(data)
+            }
+            Err(e) => {
+                self.close();
+                Err(e)
+            }
+        }
+    }
+
+    /// Encrypt the first `len` bytes of the buffer in place, and append the tag.
+    ///
+    /// Returns the length of the encrypted fragment, which is `TAG_LEN`
+    /// bytes longer than the plaintext.
+    pub fn encrypt(&mut self, buffer: &mut [u8], len: usize) -> Result<usize, Error> {
+        let nonce = next_nonce(self.closed, self.encrypt_counter)?;
+
+        let len = crypto::encrypt(&self.encrypt_key, &nonce, &[], buffer, len)?;
+
+        self.encrypt_counter += 1;
+
+        Ok(len)
+    }
+}
+
+impl Drop for SecureSession {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// The nonce for the given counter value, if it can still be used.
+fn next_nonce(closed: bool, counter: u64) -> Result<[u8; 8], Error> {
+    // The counter must never wrap around, as this would reuse a nonce
+    if closed || counter == u64::MAX {
+        return Err(Error::SessionClosed);
+    }
+
+    Ok(counter.to_le_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        fragment::RequestReassembler, pairing::PairingId, HapRequest, HapResponse, HapStatus,
+        InstanceId, OpCode,
+    };
+
+    const CONTROLLER_TO_ACCESSORY: [u8; KEY_LEN] = [0x11; KEY_LEN];
+    const ACCESSORY_TO_CONTROLLER: [u8; KEY_LEN] = [0x22; KEY_LEN];
+
+    fn session() -> SecureSession {
+        SecureSession::new(VerifiedSession {
+            controller: Pairing {
+                identifier: PairingId::new(),
+                public_key: [0; 32],
+                admin: true,
+            },
+            accessory_to_controller: ACCESSORY_TO_CONTROLLER,
+            controller_to_accessory: CONTROLLER_TO_ACCESSORY,
+        })
+    }
+
+    /// Encrypt data like the controller does.
+    fn controller_encrypt(counter: u64, data: &[u8], buffer: &mut [u8]) -> usize {
+        buffer[..data.len()].copy_from_slice(data);
+
+        crypto::encrypt(
+            &CONTROLLER_TO_ACCESSORY,
+            &counter.to_le_bytes(),
+            &[],
+            buffer,
+            data.len(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn decrypt_in_order() {
+        let mut session = session();
+
+        for counter in 0..3 {
+            let mut buffer = [0u8; 32];
+            let len = controller_encrypt(counter, &[0x00, 0x01, 0x02], &mut buffer);
+
+            assert_eq!(
+                session.decrypt(&mut buffer[..len]).unwrap(),
+                &[0x00, 0x01, 0x02]
+            );
+        }
+
+        assert!(!session.closed);
+    }
+
+    #[test]
+    fn replayed_fragment_closes_session() {
+        let mut session = session();
+
+        let mut buffer = [0u8; 32];
+        let len = controller_encrypt(0, b"first", &mut buffer);
+
+        let mut replayed = buffer;
+
+        session.decrypt(&mut buffer[..len]).unwrap();
+
+        assert!(matches!(
+            session.decrypt(&mut replayed[..len]),
+            Err(Error::Authentication)
+        ));
+        assert!(session.closed);
+
+        // Even valid data is rejected after the session has been closed
+        let len = controller_encrypt(1, b"second", &mut buffer);
+
+        assert!(matches!(
+            session.decrypt(&mut buffer[..len]),
+            Err(Error::SessionClosed)
+        ));
+    }
+
+    #[test]
+    fn encrypt_with_increasing_nonce() {
+        let mut session = session();
+
+        for counter in 0..3u64 {
+            let mut buffer = [0u8; 32];
+            buffer[..4].copy_from_slice(b"data");
+
+            let len = session.encrypt(&mut buffer, 4).unwrap();
+            assert_eq!(len, 4 + TAG_LEN);
+
+            assert_eq!(
+                crypto::decrypt(
+                    &ACCESSORY_TO_CONTROLLER,
+                    &counter.to_le_bytes(),
+                    &[],
+                    &mut buffer[..len]
+                )
+                .unwrap(),
+                b"data"
+            );
+        }
+    }
+
+    #[test]
+    fn failed_encryption_keeps_nonce() {
+        let mut session = session();
+
+        let mut short_buffer = [0u8; 8];
+        assert!(matches!(
+            session.encrypt(&mut short_buffer, 4),
+            Err(Error::InsufficientBuffer)
+        ));
+
+        let mut buffer = [0u8; 32];
+        let len = session.encrypt(&mut buffer, 4).unwrap();
+
+        assert!(crypto::decrypt(
+            &ACCESSORY_TO_CONTROLLER,
+            &0u64.to_le_bytes(),
+            &[],
+            &mut buffer[..len]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn fragmented_round_trip() {
+        let mut session = session();
+
+        // The controller encrypts every fragment of the request separately
+        let body: Vec<u8> = (0..48).collect();
+        let request = HapRequest::new(
+            OpCode::CharacteristicWrite,
+            0x42,
+            InstanceId::new(0x22),
+            &body,
+        );
+
+        let mut request_buffer = [0u8; 64];
+        let mut reassembler = RequestReassembler::new(&mut request_buffer);
+
+        let fragments: Vec<_> = request.fragments(20).unwrap().collect();
+        assert!(fragments.len() > 1);
+
+        for (counter, fragment) in fragments.iter().enumerate() {
+            let mut plaintext = [0u8; 20];
+            let len = fragment.write_into(&mut plaintext).unwrap();
+
+            let mut buffer = [0u8; 20 + TAG_LEN];
+            let len = controller_encrypt(counter as u64, &plaintext[..len], &mut buffer);
+
+            let data = session.decrypt(&mut buffer[..len]).unwrap();
+
+            if let Some(request) = reassembler.push(data).unwrap() {
+                assert_eq!(counter, fragments.len() - 1);
+                assert_eq!(request.tid, 0x42);
+                assert_eq!(request.body(), Some(&body[..]));
+            }
+        }
+
+        assert!(!reassembler.is_pending());
+
+        // The accessory encrypts every fragment of the response separately
+        let response = HapResponse::new(0x42, HapStatus::Success, &body);
+
+        let fragments: Vec<_> = response.fragments(20).unwrap().collect();
+        assert!(fragments.len() > 1);
+
+        for (counter, fragment) in fragments.iter().enumerate() {
+            let mut plaintext = [0u8; 20];
+            let plaintext_len = fragment.write_into(&mut plaintext).unwrap();
+
+            let mut buffer = [0u8; 20 + TAG_LEN];
+            buffer[..plaintext_len].copy_from_slice(&plaintext[..plaintext_len]);
+
+            let len = session.encrypt(&mut buffer, plaintext_len).unwrap();
+
+            let data = crypto::decrypt(
+                &ACCESSORY_TO_CONTROLLER,
+                &(counter as u64).to_le_bytes(),
+                &[],
+                &mut buffer[..len],
+            )
+            .unwrap();
+            assert_eq!(data, &plaintext[..plaintext_len]);
+
+            // Every fragment starts with the control field and the TID
+            assert_eq!(fragment.is_first(), counter == 0);
+            assert_eq!(data[1], 0x42);
+        }
+    }
+
+    #[test]
+    fn exhausted_nonce() {
+        let mut session = session();
+        session.encrypt_counter = u64::MAX;
+
+        let mut buffer = [0u8; 32];
+        assert!(matches!(
+            session.encrypt(&mut buffer, 4),
+            Err(Error::SessionClosed)
+        ));
+    }
+}
@@ -0,0 +1,325 @@
+//! Setup code, Setup ID and setup payload
+//!
+//! The setup code is the 8-digit number which the user enters to pair a
+//! controller with the accessory. Together with the accessory category, it
+//! is encoded in the `X-HM://` setup payload, which is shown as a QR code or
+//! stored in an NFC tag. The payload also contains the Setup ID, which the
+//! controller uses to find the accessory by the setup hash it advertises.
+
+use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha512};
+
+use crate::{identity::DeviceId, Error};
+
+/// Length of a setup code formatted as `XXX-XX-XXX`
+pub const SETUP_CODE_LEN: usize = 10;
+
+/// Length of a Setup ID
+pub const SETUP_ID_LEN: usize = 4;
+
+/// Length of the setup hash in the advertisement
+pub const SETUP_HASH_LEN: usize = 4;
+
+const PAYLOAD_PREFIX: &[u8] = b"X-HM://";
+
+/// Number of base-36 digits used to encode the payload value
+const PAYLOAD_DIGITS: usize = 9;
+
+/// Length of the setup payload URI
+pub const SETUP_PAYLOAD_LEN: usize = PAYLOAD_PREFIX.len() + PAYLOAD_DIGITS + SETUP_ID_LEN;
+
+/// Digits used by the Setup ID and the setup payload
+const BASE36_DIGITS: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Number of valid setup codes, which have 8 digits
+const SETUP_CODE_COUNT: u32 = 100_000_000;
+
+/// Setup codes which are not allowed, as they are too easy to guess
+const TRIVIAL_CODES: [u32; 12] = [
+    0, 11111111, 22222222, 33333333, 44444444, 55555555, 66666666, 77777777, 88888888, 99999999,
+    12345678, 87654321,
+];
+
+/// 8-digit code used to pair with the accessory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SetupCode(u32);
+
+impl SetupCode {
+    /// Create a setup code from its numeric value.
+    ///
+    /// Fails if the code has more than 8 digits, or is one of the trivial codes.
+    pub fn new(code: u32) -> Result<Self, Error> {
+        if code >= SETUP_CODE_COUNT || TRIVIAL_CODES.contains(&code) {
+            return Err(Error::InvalidSetupCode);
+        }
+
+        Ok(SetupCode(code))
+    }
+
+    /// Generate a random setup code.
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        // Values above the largest multiple of the code count are
+        // rejected, so that all codes are equally likely.
+        let limit = u32::MAX - u32::MAX % SETUP_CODE_COUNT;
+
+        loop {
+            let value = rng.next_u32();
+
+            if value >= limit {
+                continue;
+            }
+
+            if let Ok(code) = SetupCode::new(value % SETUP_CODE_COUNT) {
+                return code;
+            }
+        }
+    }
+
+    /// Parse a setup code, formatted either as `XXXXXXXX` or as `XXX-XX-XXX`.
+    pub fn parse(code: &[u8]) -> Result<Self, Error> {
+        let has_dashes = match code.len() {
+            8 => false,
+            SETUP_CODE_LEN => code[3] == b'-' && code[6] == b'-',
+            _ => false,
+        };
+
+        if code.len() != 8 && !has_dashes {
+            return Err(Error::InvalidSetupCode);
+        }
+
+        let mut value = 0;
+
+        for (i, digit) in code.iter().enumerate() {
+            if has_dashes && (i == 3 || i == 6) {
+                continue;
+            }
+
+            if !digit.is_ascii_digit() {
+                return Err(Error::InvalidSetupCode);
+            }
+
+            value = value * 10 + u32::from(digit - b'0');
+        }
+
+        SetupCode::new(value)
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    /// The setup code formatted as `XXX-XX-XXX`, which is used for Pair Setup.
+    pub fn to_bytes(&self) -> [u8; SETUP_CODE_LEN] {
+        let mut formatted = [b'-'; SETUP_CODE_LEN];
+
+        let mut value = self.0;
+
+        for position in (0..SETUP_CODE_LEN).rev() {
+            if position == 3 || position == 6 {
+                continue;
+            }
+
+            formatted[position] = b'0' + (value % 10) as u8;
+            value /= 10;
+        }
+
+        formatted
+    }
+}
+
+/// Four alphanumeric characters, which identify the accessory during setup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SetupId([u8; SETUP_ID_LEN]);
+
+impl SetupId {
+    /// Create a Setup ID, which may only contain digits and upper-case letters.
+    pub fn new(id: &[u8]) -> Result<Self, Error> {
+        if id.len() != SETUP_ID_LEN || !id.iter().all(|c| BASE36_DIGITS.contains(c)) {
+            return Err(Error::InvalidSetupId);
+        }
+
+        let mut setup_id = [0u8; SETUP_ID_LEN];
+        setup_id.copy_from_slice(id);
+
+        Ok(SetupId(setup_id))
+    }
+
+    /// Generate a random Setup ID.
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let mut setup_id = [0u8; SETUP_ID_LEN];
+
+        for c in setup_id.iter_mut() {
+            // Same as for the setup code, so that all characters are equally likely
+            let value = loop {
+                let mut byte = [0u8];
+                rng.fill_bytes(&mut byte);
+
+                if byte[0] < 252 {
+                    break byte[0];
+                }
+            };
+
+            *c = BASE36_DIGITS[usize::from(value % 36)];
+        }
+
+        SetupId(setup_id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; SETUP_ID_LEN] {
+        &self.0
+    }
+}
+
+/// Transports which are advertised in the setup payload
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SetupFlags {
+    pub nfc: bool,
+    pub ip: bool,
+    pub ble: bool,
+}
+
+impl SetupFlags {
+    fn bits(self) -> u64 {
+        u64::from(self.nfc) | u64::from(self.ip) << 1 | u64::from(self.ble) << 2
+    }
+}
+
+/// Encode the `X-HM://` setup payload, which is shown as a QR code.
+///
+/// The payload value contains, from the most significant bit, the version (3 bits),
+/// reserved bits (4 bits), the category (8 bits), the flags (4 bits) and the
+/// setup code (27 bits). It is encoded as 9 base-36 digits, followed by the Setup ID.
+pub fn setup_payload(
+    code: &SetupCode,
+    setup_id: &SetupId,
+    category: u8,
+    flags: SetupFlags,
+) -> [u8; SETUP_PAYLOAD_LEN] {
+    let mut value = u64::from(code.value()) | flags.bits() << 27 | u64::from(category) << 31;
+
+    let mut payload = [0u8; SETUP_PAYLOAD_LEN];
+
+    let (prefix, rest) = payload.split_at_mut(PAYLOAD_PREFIX.len());
+    let (digits, id) = rest.split_at_mut(PAYLOAD_DIGITS);
+
+    prefix.copy_from_slice(PAYLOAD_PREFIX);
+
+    for digit in digits.iter_mut().rev() {
+        *digit = BASE36_DIGITS[(value % 36) as usize];
+        value /= 36;
+    }
+
+    id.copy_from_slice(setup_id.as_bytes());
+
+    payload
+}
+
+/// Hash which is advertised, so that a controller can find the accessory
+/// with the Setup ID from the setup payload.
+///
+/// It consists of the first four bytes of the SHA-512 hash of the
+/// Setup ID, followed by the Device ID formatted as `XX:XX:XX:XX:XX:XX`.
+pub fn setup_hash(setup_id: &SetupId, device_id: &DeviceId) -> [u8; SETUP_HASH_LEN] {
+    let hash = Sha512::new()
+        .chain_update(setup_id.as_bytes())
+        .chain_update(device_id.pairing_id())
+        .finalize();
+
+    let mut setup_hash = [0u8; SETUP_HASH_LEN];
+    setup_hash.copy_from_slice(&hash[..SETUP_HASH_LEN]);
+
+    setup_hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pairing::TestRng;
+
+    #[test]
+    fn parse_setup_code() {
+        let code = SetupCode::parse(b"031-45-154").unwrap();
+
+        assert_eq!(code.value(), 3145154);
+        assert_eq!(&code.to_bytes(), b"031-45-154");
+
+        assert_eq!(SetupCode::parse(b"03145154").unwrap(), code);
+    }
+
+    #[test]
+    fn invalid_setup_codes() {
+        for code in [
+            &b"123-45-678"[..],
+            b"000-00-000",
+            b"99999999",
+            b"876-54-321",
+            b"1234567",
+            b"123456789",
+            b"123-456-78",
+            b"1a345678",
+            b"123+45+679",
+        ] {
+            assert!(
+                matches!(SetupCode::parse(code), Err(Error::InvalidSetupCode)),
+                "{:?}",
+                code
+            );
+        }
+
+        assert!(SetupCode::new(100_000_000).is_err());
+    }
+
+    #[test]
+    fn generate_setup_code() {
+        let mut rng = TestRng(7);
+
+        for _ in 0..1000 {
+            let code = SetupCode::generate(&mut rng);
+
+            assert!(code.value() < SETUP_CODE_COUNT);
+            assert!(!TRIVIAL_CODES.contains(&code.value()));
+            assert_eq!(SetupCode::parse(&code.to_bytes()).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn setup_id() {
+        assert!(SetupId::new(b"7OSX").is_ok());
+        assert!(matches!(SetupId::new(b"7osx"), Err(Error::InvalidSetupId)));
+        assert!(matches!(SetupId::new(b"7OS"), Err(Error::InvalidSetupId)));
+
+        let mut rng = TestRng(3);
+
+        for _ in 0..100 {
+            let setup_id = SetupId::generate(&mut rng);
+
+            assert_eq!(SetupId::new(setup_id.as_bytes()).unwrap(), setup_id);
+        }
+    }
+
+    #[test]
+    fn encode_setup_payload() {
+        let code = SetupCode::parse(b"518-08-582").unwrap();
+        let setup_id = SetupId::new(b"1QJ8").unwrap();
+
+        let payload = setup_payload(
+            &code,
+            &setup_id,
+            10,
+            SetupFlags {
+                ble: true,
+                ..SetupFlags::default()
+            },
+        );
+
+        assert_eq!(&payload, b"X-HM://00A4W1PVQ1QJ8");
+    }
+
+    #[test]
+    fn setup_hash_of_device() {
+        let setup_id = SetupId::new(b"7OSX").unwrap();
+        let device_id = DeviceId([0xC8, 0xD8, 0x3D, 0x0A, 0xB0, 0x4B]);
+
+        assert_eq!(setup_hash(&setup_id, &device_id), [0xbd, 0x04, 0xe5, 0xf6]);
+    }
+}
@@ -0,0 +1,484 @@
+//! Persistent state of the accessory
+//!
+//! The paired controllers, the identity of the accessory, the number of
+//! failed Pair Setup attempts, and the configuration and global state numbers
+//! which are advertised have to survive a reboot. They are accessed through the `PairingStore` trait,
+//! which is implemented on top of the storage available on the device.
+//!
+//! `MemoryStore` keeps the state in RAM. It can be encoded as TLV8 data,
+//! so that backends can use it as a cache, and write the encoded state to
+//! persistent storage whenever it is modified.
+
+use core::convert::TryInto;
+
+use ed25519_dalek::{SigningKey, SECRET_KEY_LENGTH};
+use rand_core::{CryptoRng, RngCore};
+
+use crate::{
+    identity::{DeviceId, Identity, DEVICE_ID_LEN},
+    pairing::{Pairing, PairingId, Pairings, MAX_PAIRINGS, MAX_PAIRING_ID_LEN, PUBLIC_KEY_LEN},
+    tlv::{encoded_len, Tlv, TlvReader, TlvWrite},
+    Error,
+};
+
+/// Length of an encoded pairing, without the identifier
+const PAIRING_HEADER_LEN: usize = 1 + PUBLIC_KEY_LEN;
+
+/// Maximum length of the state encoded by `MemoryStore::encode`
+pub const MAX_ENCODED_LEN: usize = encoded_len(SECRET_KEY_LENGTH)
+    + encoded_len(DEVICE_ID_LEN)
+    + encoded_len(1)
+    + encoded_len(2)
+    + encoded_len(1)
+    + MAX_PAIRINGS * encoded_len(PAIRING_HEADER_LEN + MAX_PAIRING_ID_LEN);
+
+/// TLV types used to encode the state
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum StoreTlvType {
+    AccessoryKey = 0x01,
+    ConfigNumber = 0x02,
+    GlobalStateNumber = 0x03,
+    Pairing = 0x04,
+    DeviceId = 0x05,
+    FailedSetupAttempts = 0x06,
+}
+
+impl From<StoreTlvType> for u8 {
+    fn from(tlv_type: StoreTlvType) -> Self {
+        tlv_type as u8
+    }
+}
+
+/// Storage for the pairing state of the accessory
+///
+/// Reads are served from memory, so that they can't fail. Modifications
+/// have to be written to persistent storage before they return.
+pub trait PairingStore {
+    /// All controllers paired with the accessory.
+    fn pairings(&self) -> &Pairings;
+
+    /// Add a new pairing, or replace the existing pairing with the same identifier.
+    ///
+    /// Fails if `MAX_PAIRINGS` controllers are already paired.
+    fn save_pairing(&mut self, pairing: Pairing) -> Result<(), Error>;
+
+    /// Remove the pairing with the given identifier, if it exists.
+    ///
+    /// If the last admin is removed, all pairings are removed.
+    fn remove_pairing(&mut self, identifier: &[u8]) -> Result<Option<Pairing>, Error>;
+
+    /// Remove all pairings.
+    fn remove_all_pairings(&mut self) -> Result<(), Error>;
+
+    /// The identity of the accessory, if one has been stored.
+    fn identity(&self) -> Option<&Identity>;
+
+    fn set_identity(&mut self, identity: &Identity) -> Result<(), Error>;
+
+    /// The configuration number, which changes when the
+    /// accessory's services or characteristics change.
+    fn config_number(&self) -> u8;
+
+    fn set_config_number(&mut self, config_number: u8) -> Result<(), Error>;
+
+    /// The global state number, which is incremented when
+    /// a characteristic value changes while disconnected.
+    fn global_state_number(&self) -> u16;
+
+    fn set_global_state_number(&mut self, gsn: u16) -> Result<(), Error>;
+
+    /// The number of failed Pair Setup attempts, which
+    /// is limited to prevent guessing the setup code.
+    fn failed_setup_attempts(&self) -> u8;
+
+    fn set_failed_setup_attempts(&mut self, attempts: u8) -> Result<(), Error>;
+
+    /// Find the pairing of the controller with the given identifier.
+    fn find_pairing(&self, identifier: &[u8]) -> Option<&Pairing> {
+        self.pairings().find(identifier)
+    }
+
+    /// Check if at least one controller is paired.
+    fn is_paired(&self) -> bool {
+        self.pairings().is_paired()
+    }
+
+    /// Increment the configuration number, which wraps around from 255 to 1.
+    fn increment_config_number(&mut self) -> Result<u8, Error> {
+        let config_number = match self.config_number() {
+            u8::MAX => 1,
+            n => n + 1,
+        };
+
+        self.set_config_number(config_number)?;
+
+        Ok(config_number)
+    }
+
+    /// Increment the global state number, which wraps around from 65535 to 1.
+    fn increment_global_state_number(&mut self) -> Result<u16, Error> {
+        let gsn = match self.global_state_number() {
+            u16::MAX => 1,
+            n => n + 1,
+        };
+
+        self.set_global_state_number(gsn)?;
+
+        Ok(gsn)
+    }
+}
+
+/// Remove all pairings, and replace the identity of the accessory,
+/// so that controllers see it as a new accessory.
+///
+/// The count of failed Pair Setup attempts is reset as well,
+/// so that an accessory which refuses pairing can be paired again.
+pub fn factory_reset<S: PairingStore, R: RngCore + CryptoRng>(
+    store: &mut S,
+    rng: &mut R,
+) -> Result<Identity, Error> {
+    store.remove_all_pairings()?;
+    store.set_failed_setup_attempts(0)?;
+
+    let identity = Identity::generate(rng);
+    store.set_identity(&identity)?;
+
+    Ok(identity)
+}
+
+/// Pairing state kept in RAM
+pub struct MemoryStore {
+    pairings: Pairings,
+
+    identity: Option<Identity>,
+
+    config_number: u8,
+
+    global_state_number: u16,
+
+    failed_setup_attempts: u8,
+}
+
+impl MemoryStore {
+    /// Create the state of an unpaired accessory.
+    pub fn new() -> Self {
+        MemoryStore {
+            pairings: Pairings::new(),
+            identity: None,
+            config_number: 1,
+            global_state_number: 1,
+            failed_setup_attempts: 0,
+        }
+    }
+
+    /// Encode the state as TLV8 data, which is at most `MAX_ENCODED_LEN` bytes long.
+    pub fn encode<W: TlvWrite>(&self, writer: &mut W) -> Result<(), Error> {
+        if let Some(identity) = &self.identity {
+            writer.push_tlv(Tlv::new(
+                StoreTlvType::AccessoryKey,
+                &identity.signing_key().to_bytes()[..],
+            ))?;
+            writer.push_tlv(Tlv::new(
+                StoreTlvType::DeviceId,
+                &identity.device_id().as_bytes()[..],
+            ))?;
+        }
+
+        writer.push_tlv(Tlv::new(StoreTlvType::ConfigNumber, self.config_number))?;
+        writer.push_tlv(Tlv::new(
+            StoreTlvType::GlobalStateNumber,
+            self.global_state_number,
+        ))?;
+        writer.push_tlv(Tlv::new(
+            StoreTlvType::FailedSetupAttempts,
+            self.failed_setup_attempts,
+        ))?;
+
+        // Items of the same type are only merged if the first one has the
+        // maximum length, so the pairings don't need to be separated.
+        for pairing in self.pairings.iter() {
+            let mut value = [0u8; PAIRING_HEADER_LEN + MAX_PAIRING_ID_LEN];
+
+            value[0] = pairing.admin as u8;
+            value[1..PAIRING_HEADER_LEN].copy_from_slice(&pairing.public_key);
+            value[PAIRING_HEADER_LEN..][..pairing.identifier.len()]
+                .copy_from_slice(&pairing.identifier);
+
+            let len = PAIRING_HEADER_LEN + pairing.identifier.len();
+
+            writer.push_tlv(Tlv::new(StoreTlvType::Pairing, &value[..len]))?;
+        }
+
+        Ok(())
+    }
+
+    /// Decode the state written by `encode`.
+    ///
+    /// Empty data results in the state of an unpaired accessory.
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        let mut store = MemoryStore::new();
+
+        let mut accessory_key = None;
+        let mut device_id = None;
+
+        for item in TlvReader::new(data).iter() {
+            let item = item?;
+
+            match item.tlv_type() {
+                t if t == StoreTlvType::AccessoryKey as u8 => {
+                    accessory_key = Some(
+                        item.as_slice()
+                            .and_then(|key| key.try_into().ok())
+                            .map(SigningKey::from_bytes)
+                            .ok_or(Error::InvalidTlvValue(t))?,
+                    );
+                }
+                t if t == StoreTlvType::DeviceId as u8 => {
+                    device_id = Some(
+                        item.as_slice()
+                            .and_then(|id| id.try_into().ok())
+                            .map(DeviceId)
+                            .ok_or(Error::InvalidTlvValue(t))?,
+                    );
+                }
+                t if t == StoreTlvType::ConfigNumber as u8 => {
+                    store.config_number = item.as_u8()?;
+                }
+                t if t == StoreTlvType::GlobalStateNumber as u8 => {
+                    store.global_state_number = item.as_u16()?;
+                }
+                t if t == StoreTlvType::FailedSetupAttempts as u8 => {
+                    store.failed_setup_attempts = item.as_u8()?;
+                }
+                t if t == StoreTlvType::Pairing as u8 => {
+                    let value = item
+                        .as_slice()
+                        .filter(|value| value.len() > PAIRING_HEADER_LEN)
+                        .ok_or(Error::InvalidTlvValue(t))?;
+
+                    let mut identifier = PairingId::new();
+                    identifier
+                        .extend_from_slice(&value[PAIRING_HEADER_LEN..])
+                        .map_err(|_| Error::InvalidTlvValue(t))?;
+
+                    let mut public_key = [0u8; PUBLIC_KEY_LEN];
+                    public_key.copy_from_slice(&value[1..PAIRING_HEADER_LEN]);
+
+                    store.save_pairing(Pairing {
+                        identifier,
+                        public_key,
+                        admin: value[0] != 0,
+                    })?;
+                }
+                // Ignore unknown items, which might be added in a later version
+                _ => {}
+            }
+        }
+
+        // Only a complete identity is used, otherwise a new one is generated
+        if let (Some(key), Some(device_id)) = (accessory_key, device_id) {
+            store.identity = Some(Identity::new(device_id, key));
+        }
+
+        Ok(store)
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PairingStore for MemoryStore {
+    fn pairings(&self) -> &Pairings {
+        &self.pairings
+    }
+
+    fn save_pairing(&mut self, pairing: Pairing) -> Result<(), Error> {
+        self.pairings.insert(pairing).map_err(|_| Error::StoreFull)
+    }
+
+    fn remove_pairing(&mut self, identifier: &[u8]) -> Result<Option<Pairing>, Error> {
+        Ok(self.pairings.remove(identifier))
+    }
+
+    fn remove_all_pairings(&mut self) -> Result<(), Error> {
+        self.pairings.clear();
+        Ok(())
+    }
+
+    fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    fn set_identity(&mut self, identity: &Identity) -> Result<(), Error> {
+        self.identity = Some(identity.clone());
+        Ok(())
+    }
+
+    fn config_number(&self) -> u8 {
+        self.config_number
+    }
+
+    fn set_config_number(&mut self, config_number: u8) -> Result<(), Error> {
+        self.config_number = config_number;
+        Ok(())
+    }
+
+    fn global_state_number(&self) -> u16 {
+        self.global_state_number
+    }
+
+    fn set_global_state_number(&mut self, gsn: u16) -> Result<(), Error> {
+        self.global_state_number = gsn;
+        Ok(())
+    }
+
+    fn failed_setup_attempts(&self) -> u8 {
+        self.failed_setup_attempts
+    }
+
+    fn set_failed_setup_attempts(&mut self, attempts: u8) -> Result<(), Error> {
+        self.failed_setup_attempts = attempts;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        pairing::{pairing, TestRng},
+        tlv::TlvWriter,
+    };
+
+    #[test]
+    fn save_and_remove_pairings() {
+        let mut store = MemoryStore::new();
+        assert!(!store.is_paired());
+
+        store.save_pairing(pairing(b"admin", 1, true)).unwrap();
+        store.save_pairing(pairing(b"user", 2, false)).unwrap();
+
+        // Saving a pairing with the same identifier replaces it
+        store.save_pairing(pairing(b"user", 3, true)).unwrap();
+
+        assert_eq!(store.pairings().len(), 2);
+        assert_eq!(
+            store.find_pairing(b"user"),
+            Some(&pairing(b"user", 3, true))
+        );
+
+        assert_eq!(
+            store.remove_pairing(b"admin").unwrap(),
+            Some(pairing(b"admin", 1, true))
+        );
+        assert!(store.is_paired());
+
+        store.remove_all_pairings().unwrap();
+        assert!(!store.is_paired());
+    }
+
+    #[test]
+    fn save_too_many_pairings() {
+        let mut store = MemoryStore::new();
+
+        for i in 0..MAX_PAIRINGS as u8 {
+            store.save_pairing(pairing(&[i], i, true)).unwrap();
+        }
+
+        assert!(matches!(
+            store.save_pairing(pairing(b"new", 0, true)),
+            Err(Error::StoreFull)
+        ));
+
+        // Replacing an existing pairing still works
+        store.save_pairing(pairing(&[0], 0xff, true)).unwrap();
+    }
+
+    #[test]
+    fn increment_numbers() {
+        let mut store = MemoryStore::new();
+
+        assert_eq!(store.increment_config_number().unwrap(), 2);
+        assert_eq!(store.increment_global_state_number().unwrap(), 2);
+
+        store.set_config_number(u8::MAX).unwrap();
+        store.set_global_state_number(u16::MAX).unwrap();
+
+        assert_eq!(store.increment_config_number().unwrap(), 1);
+        assert_eq!(store.increment_global_state_number().unwrap(), 1);
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let mut store = MemoryStore::new();
+
+        let identity = Identity::new(
+            DeviceId([0x44, 0x55, 0x66, 0x44, 0x55, 0x66]),
+            SigningKey::from_bytes(&[0x42; SECRET_KEY_LENGTH]),
+        );
+
+        store.set_identity(&identity).unwrap();
+        store.set_config_number(7).unwrap();
+        store.set_global_state_number(0x1234).unwrap();
+        store.set_failed_setup_attempts(42).unwrap();
+
+        for i in 0..MAX_PAIRINGS as u8 {
+            let identifier = [b'a' + i; MAX_PAIRING_ID_LEN];
+            store.save_pairing(pairing(&identifier, i, i == 0)).unwrap();
+        }
+
+        let mut buffer = [0u8; MAX_ENCODED_LEN];
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        store.encode(&mut writer).unwrap();
+
+        // The maximum length is reached with the longest identifiers
+        assert_eq!(writer.len(), MAX_ENCODED_LEN);
+
+        let decoded = MemoryStore::decode(writer.finish()).unwrap();
+
+        let decoded_identity = decoded.identity().unwrap();
+        assert_eq!(decoded_identity.device_id(), identity.device_id());
+        assert_eq!(decoded_identity.public_key(), identity.public_key());
+        assert_eq!(decoded.config_number(), 7);
+        assert_eq!(decoded.global_state_number(), 0x1234);
+        assert_eq!(decoded.failed_setup_attempts(), 42);
+        assert!(decoded.pairings().iter().eq(store.pairings().iter()));
+    }
+
+    #[test]
+    fn factory_reset_replaces_identity() {
+        let mut store = MemoryStore::new();
+
+        let device_id = DeviceId([0x44, 0x55, 0x66, 0x0a, 0xbc, 0xff]);
+
+        let identity = Identity::load_or_provision(&mut store, device_id, &mut TestRng(1)).unwrap();
+        store.save_pairing(pairing(b"admin", 1, true)).unwrap();
+        store.set_failed_setup_attempts(100).unwrap();
+
+        let new_identity = factory_reset(&mut store, &mut TestRng(2)).unwrap();
+
+        assert!(!store.is_paired());
+        assert_eq!(store.failed_setup_attempts(), 0);
+        assert_ne!(new_identity.device_id(), identity.device_id());
+        assert_ne!(new_identity.public_key(), identity.public_key());
+        assert_eq!(
+            store.identity().unwrap().device_id(),
+            new_identity.device_id()
+        );
+    }
+
+    #[test]
+    fn decode_empty() {
+        let store = MemoryStore::decode(&[]).unwrap();
+
+        assert!(!store.is_paired());
+        assert!(store.identity().is_none());
+        assert_eq!(store.config_number(), 1);
+        assert_eq!(store.global_state_number(), 1);
+        assert_eq!(store.failed_setup_attempts(), 0);
+    }
+}
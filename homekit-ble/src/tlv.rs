@@ -1,11 +1,45 @@
 //! Support for TLV8 data structures
 //!
+//! Lists are encoded by separating groups of items with
+//! an empty separator item (type 0xFF).
 
+use crate::{Error, IidSize, InstanceId};
+
+mod codec;
+mod reader;
+mod writer;
+
+pub use codec::{
+    FromTlvItem, TlvDecode, TlvEncode, TlvList, TlvListGroups, TlvListIter, ToTlvValue,
+};
+pub use reader::{TlvGroups, TlvItem, TlvIter, TlvReader};
+pub use writer::{encoded_len, TlvVec, TlvWrite, TlvWriter};
+
+#[cfg(feature = "derive")]
+pub use homekit_ble_derive::{TlvDecode, TlvEncode};
+
+/// Maximum length of the value of a single TLV item.
+///
+/// Longer values are split into multiple items with the same type.
+const MAX_FRAGMENT_LEN: usize = 0xff;
+
+/// Type of the separator item, which divides the groups of a list.
+pub const SEPARATOR_TYPE: u8 = 0xff;
+
+/// Value of a TLV item
+///
+/// Numbers are encoded in little endian byte order, booleans
+/// as a single byte with the value 0 or 1.
 pub enum Value<'a> {
+    Empty,
     Bytes(&'a [u8]),
+    Bool(bool),
     Integer8(u8),
     Integer16(u16),
     Integer32(u32),
+    Integer64(u64),
+    SignedInteger32(i32),
+    Float32(f32),
     String(&'a str),
 }
 
@@ -15,6 +49,18 @@ impl<'a> From<&'a [u8]> for Value<'a> {
     }
 }
 
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(data: &'a str) -> Self {
+        Value::String(data)
+    }
+}
+
+impl From<bool> for Value<'_> {
+    fn from(data: bool) -> Self {
+        Value::Bool(data)
+    }
+}
+
 impl From<u8> for Value<'_> {
     fn from(data: u8) -> Self {
         Value::Integer8(data)
@@ -26,56 +72,110 @@ impl From<u16> for Value<'_> {
         Value::Integer16(data)
     }
 }
+
 impl From<u32> for Value<'_> {
     fn from(data: u32) -> Self {
         Value::Integer32(data)
     }
 }
 
+impl From<u64> for Value<'_> {
+    fn from(data: u64) -> Self {
+        Value::Integer64(data)
+    }
+}
+
+impl From<i32> for Value<'_> {
+    fn from(data: i32) -> Self {
+        Value::SignedInteger32(data)
+    }
+}
+
+impl From<f32> for Value<'_> {
+    fn from(data: f32) -> Self {
+        Value::Float32(data)
+    }
+}
+
+/// Instance IDs are encoded using 16 bits, unless
+/// they are too large and require 64 bits.
+impl From<InstanceId> for Value<'_> {
+    fn from(id: InstanceId) -> Self {
+        match id.min_size() {
+            IidSize::Bit16 => Value::Integer16(id.value() as u16),
+            IidSize::Bit64 => Value::Integer64(id.value()),
+        }
+    }
+}
+
 pub struct Tlv<'a> {
     tlv_type: u8,
     value: Value<'a>,
 }
 
 impl<'a> Tlv<'a> {
-    pub fn new(tlv_type: u8, value: impl Into<Value<'a>>) -> Self {
+    /// Create a TLV item.
+    ///
+    /// The type can be given as `u8`, or using one of the
+    /// typed registries, like `ParamType` or `pairing::TlvType`.
+    pub fn new(tlv_type: impl Into<u8>, value: impl Into<Value<'a>>) -> Self {
         Tlv {
-            tlv_type,
+            tlv_type: tlv_type.into(),
             value: value.into(),
         }
     }
 
-    pub fn write_into(&self, buffer: &mut [u8]) -> usize {
+    /// Create a separator item, used between the groups of a list.
+    pub fn separator() -> Self {
+        Tlv {
+            tlv_type: SEPARATOR_TYPE,
+            value: Value::Empty,
+        }
+    }
+
+    /// Write the TLV into a buffer, returning the number of bytes written.
+    ///
+    /// Values longer than 255 bytes are split into multiple items.
+    pub fn write_into(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.with_raw_data(|data| self.write_raw_data(data, buffer))
+    }
+
+    /// Calculate the size of the encoded TLV in bytes
+    pub fn size(&self) -> usize {
+        self.with_raw_data(|data| encoded_len(data.len()))
+    }
+
+    fn with_raw_data<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
         match self.value {
-            Value::Bytes(data) => self.write_raw_data(data, buffer),
-            Value::Integer8(i) => {
-                let data = i.to_le_bytes();
-                self.write_raw_data(&data, buffer)
-            }
-            Value::Integer16(i) => {
-                let data = i.to_le_bytes();
-                self.write_raw_data(&data, buffer)
-            }
-            Value::Integer32(i) => {
-                let data = i.to_le_bytes();
-                self.write_raw_data(&data, buffer)
-            }
-            Value::String(s) => self.write_raw_data(s.as_bytes(), buffer),
+            Value::Empty => f(&[]),
+            Value::Bytes(data) => f(data),
+            Value::Bool(b) => f(&[b as u8]),
+            Value::Integer8(i) => f(&i.to_le_bytes()),
+            Value::Integer16(i) => f(&i.to_le_bytes()),
+            Value::Integer32(i) => f(&i.to_le_bytes()),
+            Value::Integer64(i) => f(&i.to_le_bytes()),
+            Value::SignedInteger32(i) => f(&i.to_le_bytes()),
+            Value::Float32(v) => f(&v.to_le_bytes()),
+            Value::String(s) => f(s.as_bytes()),
         }
     }
 
-    fn write_raw_data(&self, data: &[u8], buffer: &mut [u8]) -> usize {
-        if data.len() < 0xff {
+    fn write_raw_data(&self, data: &[u8], buffer: &mut [u8]) -> Result<usize, Error> {
+        if self.size() > buffer.len() {
+            return Err(Error::InsufficientBuffer);
+        }
+
+        if data.len() < MAX_FRAGMENT_LEN {
             buffer[0] = self.tlv_type;
             buffer[1] = data.len() as u8;
             buffer[2..(2 + data.len())].copy_from_slice(data);
 
-            2 + data.len()
+            Ok(2 + data.len())
         } else {
             // PDU needs fragmentation
             let mut index = 0;
 
-            for chunk in data.chunks(0xff) {
+            for chunk in data.chunks(MAX_FRAGMENT_LEN) {
                 buffer[index] = self.tlv_type;
                 buffer[index + 1] = chunk.len() as u8;
                 buffer[index + 2..index + 2 + chunk.len()].copy_from_slice(chunk);
@@ -83,7 +183,7 @@ impl<'a> Tlv<'a> {
                 index += 2 + chunk.len();
             }
 
-            index
+            Ok(index)
         }
     }
 }
@@ -91,6 +191,7 @@ impl<'a> Tlv<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use proptest::{collection::vec, prelude::*};
 
     #[test]
     fn write_bytes() {
@@ -101,7 +202,7 @@ mod test {
 
         let mut buff = [0u8; 4];
 
-        tlv_value.write_into(&mut buff);
+        tlv_value.write_into(&mut buff).unwrap();
 
         assert_eq!(buff, [12, 0x2, 0x12, 0x42]);
     }
@@ -115,8 +216,94 @@ mod test {
 
         let mut buff = [0u8; 4];
 
-        tlv_value.write_into(&mut buff);
+        tlv_value.write_into(&mut buff).unwrap();
 
         assert_eq!(buff, [12, 0x2, 0x23, 0x01]);
     }
+
+    #[test]
+    fn write_instance_id() {
+        let mut buff = [0u8; 10];
+
+        let len = Tlv::new(7, InstanceId::new(0x10))
+            .write_into(&mut buff)
+            .unwrap();
+        assert_eq!(&buff[..len], &[7, 0x2, 0x10, 0x00]);
+
+        let len = Tlv::new(7, InstanceId::new(0x1_0010))
+            .write_into(&mut buff)
+            .unwrap();
+        assert_eq!(&buff[..len], &[7, 0x8, 0x10, 0x00, 0x01, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn write_signed_values() {
+        let mut buff = [0u8; 6];
+
+        let len = Tlv::new(1, true).write_into(&mut buff).unwrap();
+        assert_eq!(&buff[..len], &[1, 1, 1]);
+
+        let len = Tlv::new(1, -2i32).write_into(&mut buff).unwrap();
+        assert_eq!(&buff[..len], &[1, 4, 0xfe, 0xff, 0xff, 0xff]);
+
+        let len = Tlv::new(1, 1.0f32).write_into(&mut buff).unwrap();
+        assert_eq!(&buff[..len], &[1, 4, 0x00, 0x00, 0x80, 0x3f]);
+
+        let len = Tlv::new(1, "ab").write_into(&mut buff).unwrap();
+        assert_eq!(&buff[..len], &[1, 2, b'a', b'b']);
+    }
+
+    #[test]
+    fn write_separator() {
+        let mut buff = [0u8; 4];
+
+        let separator = Tlv::separator();
+
+        assert_eq!(separator.size(), 2);
+        assert_eq!(separator.write_into(&mut buff).unwrap(), 2);
+        assert_eq!(&buff[..2], &[0xff, 0]);
+    }
+
+    #[test]
+    fn write_fragmented_bytes() {
+        let data = [0xaa; 300];
+        let tlv_value = Tlv::new(1, &data[..]);
+
+        let mut buff = [0u8; 304];
+
+        assert_eq!(tlv_value.size(), 304);
+        assert_eq!(tlv_value.write_into(&mut buff).unwrap(), 304);
+
+        assert_eq!(&buff[..2], &[1, 0xff]);
+        assert_eq!(&buff[257..259], &[1, 45]);
+    }
+
+    #[test]
+    fn write_into_short_buffer() {
+        let tlv_value = Tlv::new(12, 0x1234_5678u32);
+
+        let mut buff = [0u8; 5];
+
+        assert!(matches!(
+            tlv_value.write_into(&mut buff),
+            Err(Error::InsufficientBuffer)
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn write_arbitrary_bytes(
+            data in vec(any::<u8>(), 0..600),
+            buffer_len in 0usize..620,
+        ) {
+            let tlv_value = Tlv::new(1, &data[..]);
+
+            let mut buff = vec![0u8; buffer_len];
+
+            match tlv_value.write_into(&mut buff) {
+                Ok(len) => prop_assert_eq!(len, tlv_value.size()),
+                Err(_) => prop_assert!(buffer_len < tlv_value.size()),
+            }
+        }
+    }
 }
@@ -0,0 +1,479 @@
+//! Encoding and decoding of structs as TLV8 data
+//!
+//! The `TlvEncode` and `TlvDecode` traits are usually implemented using
+//! the derive macros from the `homekit-ble-derive` crate, which are
+//! re-exported when the `derive` feature is enabled:
+//!
+//! ```ignore
+//! #[derive(TlvEncode, TlvDecode)]
+//! struct PairSetupM1 {
+//!     #[tlv(type = TlvType::State)]
+//!     state: u8,
+//!     #[tlv(type = TlvType::Method)]
+//!     method: u8,
+//!     #[tlv(type = TlvType::Flags)]
+//!     flags: Option<u32>,
+//! }
+//! ```
+//!
+//! Every field needs a `type` attribute with the TLV type. Fields of type
+//! `Option` are skipped when encoding if they are `None`, and are `None`
+//! after decoding if no item with the type is present. A field marked with
+//! `#[tlv(list)]` must be a `TlvList`, which contains the groups of a
+//! separated list.
+
+use core::slice;
+
+use super::{TlvGroups, TlvItem, TlvReader, TlvWrite, Value};
+use crate::{Error, InstanceId};
+
+/// A type which can be encoded as a sequence of TLV items
+pub trait TlvEncode {
+    fn encode<W: TlvWrite>(&self, writer: &mut W) -> Result<(), Error>;
+}
+
+/// A type which can be decoded from a sequence of TLV items
+pub trait TlvDecode<'a>: Sized {
+    fn decode(reader: TlvReader<'a>) -> Result<Self, Error>;
+}
+
+/// A type which can be used as the value of a TLV item
+pub trait ToTlvValue {
+    fn to_tlv_value(&self) -> Value<'_>;
+}
+
+/// A type which can be read from the value of a TLV item
+pub trait FromTlvItem<'a>: Sized {
+    fn from_tlv_item(item: TlvItem<'a>) -> Result<Self, Error>;
+}
+
+impl<T: ToTlvValue + ?Sized> ToTlvValue for &T {
+    fn to_tlv_value(&self) -> Value<'_> {
+        (**self).to_tlv_value()
+    }
+}
+
+impl ToTlvValue for bool {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::Bool(*self)
+    }
+}
+
+impl ToTlvValue for u8 {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::Integer8(*self)
+    }
+}
+
+impl ToTlvValue for u16 {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::Integer16(*self)
+    }
+}
+
+impl ToTlvValue for u32 {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::Integer32(*self)
+    }
+}
+
+impl ToTlvValue for u64 {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::Integer64(*self)
+    }
+}
+
+impl ToTlvValue for i32 {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::SignedInteger32(*self)
+    }
+}
+
+impl ToTlvValue for f32 {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::Float32(*self)
+    }
+}
+
+impl ToTlvValue for InstanceId {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::from(*self)
+    }
+}
+
+impl ToTlvValue for [u8] {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::Bytes(self)
+    }
+}
+
+impl<const N: usize> ToTlvValue for [u8; N] {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::Bytes(&self[..])
+    }
+}
+
+impl ToTlvValue for str {
+    fn to_tlv_value(&self) -> Value<'_> {
+        Value::String(self)
+    }
+}
+
+impl FromTlvItem<'_> for bool {
+    fn from_tlv_item(item: TlvItem<'_>) -> Result<Self, Error> {
+        item.as_bool()
+    }
+}
+
+impl FromTlvItem<'_> for u8 {
+    fn from_tlv_item(item: TlvItem<'_>) -> Result<Self, Error> {
+        item.as_u8()
+    }
+}
+
+impl FromTlvItem<'_> for u16 {
+    fn from_tlv_item(item: TlvItem<'_>) -> Result<Self, Error> {
+        item.as_u16()
+    }
+}
+
+impl FromTlvItem<'_> for u32 {
+    fn from_tlv_item(item: TlvItem<'_>) -> Result<Self, Error> {
+        item.as_u32()
+    }
+}
+
+impl FromTlvItem<'_> for u64 {
+    fn from_tlv_item(item: TlvItem<'_>) -> Result<Self, Error> {
+        item.as_u64()
+    }
+}
+
+impl FromTlvItem<'_> for i32 {
+    fn from_tlv_item(item: TlvItem<'_>) -> Result<Self, Error> {
+        item.as_i32()
+    }
+}
+
+impl FromTlvItem<'_> for f32 {
+    fn from_tlv_item(item: TlvItem<'_>) -> Result<Self, Error> {
+        item.as_f32()
+    }
+}
+
+impl FromTlvItem<'_> for InstanceId {
+    fn from_tlv_item(item: TlvItem<'_>) -> Result<Self, Error> {
+        item.as_u64().map(InstanceId::new)
+    }
+}
+
+impl<'a> FromTlvItem<'a> for &'a [u8] {
+    fn from_tlv_item(item: TlvItem<'a>) -> Result<Self, Error> {
+        item.as_slice()
+            .ok_or_else(|| Error::FragmentedTlv(item.tlv_type()))
+    }
+}
+
+/// Arrays are copied, so they can be used for fragmented values.
+impl<const N: usize> FromTlvItem<'_> for [u8; N] {
+    fn from_tlv_item(item: TlvItem<'_>) -> Result<Self, Error> {
+        if item.len() != N {
+            return Err(Error::InvalidTlvValue(item.tlv_type()));
+        }
+
+        let mut value = [0u8; N];
+        item.copy_into(&mut value)?;
+
+        Ok(value)
+    }
+}
+
+impl<'a> FromTlvItem<'a> for &'a str {
+    fn from_tlv_item(item: TlvItem<'a>) -> Result<Self, Error> {
+        item.as_str()
+    }
+}
+
+/// A list of groups, divided by separator items
+///
+/// A list is either created from a slice of items for encoding,
+/// or references encoded data, which is decoded when iterating over it.
+///
+/// The encoded data can contain the other fields of the enclosing struct,
+/// which end up in the first group of the list. A group which only consists
+/// of such fields is skipped, so that an empty list is decoded correctly.
+#[derive(Debug, Clone, Copy)]
+pub enum TlvList<'a, T> {
+    Items(&'a [T]),
+    Encoded {
+        reader: TlvReader<'a>,
+        /// Check if an item belongs to the enclosing struct
+        is_field: fn(u8) -> bool,
+    },
+}
+
+impl<'a, T> TlvList<'a, T> {
+    /// Iterate over the items of the list, decoding them if necessary.
+    pub fn iter(&self) -> TlvListIter<'a, T> {
+        match self {
+            TlvList::Items(items) => TlvListIter::Items(items.iter()),
+            TlvList::Encoded { reader, is_field } => {
+                TlvListIter::Encoded(list_groups(reader, *is_field))
+            }
+        }
+    }
+}
+
+impl<'a, T: TlvEncode + TlvDecode<'a>> TlvList<'a, T> {
+    /// Write the list, with a separator between the groups.
+    ///
+    /// An encoded list is decoded and encoded again, so that only
+    /// the items belonging to the groups are written.
+    pub fn encode<W: TlvWrite>(&self, writer: &mut W) -> Result<(), Error> {
+        match self {
+            TlvList::Items(items) => {
+                writer.push_tlv_list(items.iter(), |writer, item| item.encode(writer))
+            }
+            TlvList::Encoded { reader, is_field } => writer
+                .push_tlv_list(list_groups(reader, *is_field), |writer, group| {
+                    T::decode(group?)?.encode(writer)
+                }),
+        }
+    }
+}
+
+impl<'a, T> From<&'a [T]> for TlvList<'a, T> {
+    fn from(items: &'a [T]) -> Self {
+        TlvList::Items(items)
+    }
+}
+
+/// Iterator over the groups of an encoded list
+///
+/// Groups which only contain fields of the enclosing struct are skipped.
+#[derive(Debug, Clone)]
+pub struct TlvListGroups<'a> {
+    groups: TlvGroups<'a>,
+    is_field: fn(u8) -> bool,
+}
+
+impl<'a> Iterator for TlvListGroups<'a> {
+    type Item = Result<TlvReader<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let group = self.groups.next()?;
+
+            if let Ok(reader) = &group {
+                let is_field = self.is_field;
+
+                // Errors are returned when decoding the group
+                if reader
+                    .iter()
+                    .all(|item| item.is_ok_and(|item| is_field(item.tlv_type())))
+                {
+                    continue;
+                }
+            }
+
+            return Some(group);
+        }
+    }
+}
+
+fn list_groups<'a>(reader: &TlvReader<'a>, is_field: fn(u8) -> bool) -> TlvListGroups<'a> {
+    TlvListGroups {
+        groups: reader.groups(),
+        is_field,
+    }
+}
+
+/// Iterator over the items of a `TlvList`
+#[derive(Debug, Clone)]
+pub enum TlvListIter<'a, T> {
+    Items(slice::Iter<'a, T>),
+    Encoded(TlvListGroups<'a>),
+}
+
+impl<'a, T: TlvDecode<'a> + Clone> Iterator for TlvListIter<'a, T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            TlvListIter::Items(items) => items.next().cloned().map(Ok),
+            TlvListIter::Encoded(groups) => groups.next().map(|group| group.and_then(T::decode)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pairing::TlvType;
+    use crate::tlv::TlvWriter;
+    use homekit_ble_derive::{TlvDecode, TlvEncode};
+
+    #[derive(Debug, Clone, PartialEq, TlvEncode, TlvDecode)]
+    struct Pairing<'a> {
+        #[tlv(type = TlvType::Identifier)]
+        identifier: &'a str,
+        #[tlv(type = TlvType::PublicKey)]
+        public_key: [u8; 4],
+        #[tlv(type = TlvType::Permissions)]
+        permissions: u8,
+    }
+
+    #[derive(Debug, TlvEncode, TlvDecode)]
+    struct ListPairingsResponse<'a> {
+        #[tlv(type = TlvType::State)]
+        state: u8,
+        #[tlv(type = TlvType::Error)]
+        error: Option<u8>,
+        #[tlv(list)]
+        pairings: TlvList<'a, Pairing<'a>>,
+    }
+
+    #[derive(Debug, PartialEq, TlvEncode, TlvDecode)]
+    struct Signature {
+        #[tlv(type = 0x04)]
+        characteristic_type: [u8; 16],
+        #[tlv(type = 0x07)]
+        service_id: InstanceId,
+        #[tlv(type = 0x0a)]
+        properties: Option<u16>,
+    }
+
+    #[test]
+    fn encode_struct() {
+        let signature = Signature {
+            characteristic_type: [0xaa; 16],
+            service_id: InstanceId::new(0x10),
+            properties: None,
+        };
+
+        let mut buffer = [0u8; 32];
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        signature.encode(&mut writer).unwrap();
+
+        let data = writer.finish();
+        assert_eq!(data.len(), 22);
+        assert_eq!(&data[..2], &[0x04, 16]);
+        assert_eq!(&data[18..], &[0x07, 2, 0x10, 0]);
+
+        assert_eq!(Signature::decode(TlvReader::new(data)).unwrap(), signature);
+    }
+
+    #[test]
+    fn encode_into_short_buffer() {
+        let signature = Signature {
+            characteristic_type: [0xaa; 16],
+            service_id: InstanceId::new(0x10),
+            properties: Some(3),
+        };
+
+        let mut buffer = [0u8; 24];
+
+        assert!(matches!(
+            signature.encode(&mut TlvWriter::new(&mut buffer)),
+            Err(Error::InsufficientBuffer)
+        ));
+    }
+
+    #[test]
+    fn decode_missing_field() {
+        let data = [0x07, 1, 0x10];
+
+        assert!(matches!(
+            Signature::decode(TlvReader::new(&data)),
+            Err(Error::TlvNotFound(0x04))
+        ));
+    }
+
+    #[test]
+    fn encode_and_decode_list() {
+        let pairings = [
+            Pairing {
+                identifier: "first",
+                public_key: [1; 4],
+                permissions: 1,
+            },
+            Pairing {
+                identifier: "second",
+                public_key: [2; 4],
+                permissions: 0,
+            },
+        ];
+
+        let response = ListPairingsResponse {
+            state: 2,
+            error: None,
+            pairings: TlvList::from(&pairings[..]),
+        };
+
+        let mut buffer = [0u8; 64];
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        response.encode(&mut writer).unwrap();
+
+        let data = writer.finish();
+        assert_eq!(&data[..3], &[0x06, 1, 2]);
+        assert_eq!(data.iter().filter(|&&b| b == 0xff).count(), 1);
+
+        let decoded = ListPairingsResponse::decode(TlvReader::new(data)).unwrap();
+
+        assert_eq!(decoded.state, 2);
+        assert_eq!(decoded.error, None);
+
+        let decoded_pairings: Result<Vec<Pairing>, _> = decoded.pairings.iter().collect();
+        assert_eq!(decoded_pairings.unwrap(), pairings);
+
+        // Encoding the decoded list results in the same data
+        let mut reencoded = [0u8; 64];
+        let mut writer = TlvWriter::new(&mut reencoded);
+
+        decoded.encode(&mut writer).unwrap();
+        assert_eq!(writer.finish(), data);
+    }
+
+    #[test]
+    fn encode_and_decode_empty_list() {
+        let response = ListPairingsResponse {
+            state: 2,
+            error: None,
+            pairings: TlvList::from(&[][..]),
+        };
+
+        let mut buffer = [0u8; 16];
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        response.encode(&mut writer).unwrap();
+
+        let data = writer.finish();
+        assert_eq!(data, &[0x06, 1, 2]);
+
+        let decoded = ListPairingsResponse::decode(TlvReader::new(data)).unwrap();
+
+        assert_eq!(decoded.state, 2);
+        assert_eq!(decoded.pairings.iter().count(), 0);
+
+        let mut reencoded = [0u8; 16];
+        let mut writer = TlvWriter::new(&mut reencoded);
+
+        decoded.encode(&mut writer).unwrap();
+        assert_eq!(writer.finish(), data);
+    }
+
+    #[test]
+    fn decode_list_with_fields_after_list() {
+        // The error follows the last group of the list
+        let data = [
+            0x06, 1, 2, 0x01, 1, b'a', 0x03, 4, 1, 1, 1, 1, 0x0b, 1, 1, 0xff, 0, 0x07, 1, 2,
+        ];
+
+        let decoded = ListPairingsResponse::decode(TlvReader::new(&data)).unwrap();
+
+        assert_eq!(decoded.error, Some(2));
+        assert_eq!(decoded.pairings.iter().count(), 1);
+    }
+}
@@ -0,0 +1,584 @@
+//! Zero-copy reader for TLV8 data
+//!
+//! Values longer than 255 bytes are encoded as consecutive items with
+//! the same type, where all but the last item have the maximum length.
+//! The reader merges these fragments into a single `TlvItem`.
+//!
+//! Lists are read with `TlvReader::groups`, which splits the data
+//! at separator items.
+
+use core::str;
+
+use super::{MAX_FRAGMENT_LEN, SEPARATOR_TYPE};
+use crate::Error;
+
+/// Reader for a buffer containing TLV8 encoded data
+#[derive(Debug, Clone, Copy)]
+pub struct TlvReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> TlvReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        TlvReader { data }
+    }
+
+    /// Iterate over all items, merging fragmented values.
+    pub fn iter(&self) -> TlvIter<'a> {
+        TlvIter { data: self.data }
+    }
+
+    /// Iterate over the groups of a list, which are divided by separator items.
+    ///
+    /// Data without any separator is a list with a single group.
+    pub fn groups(&self) -> TlvGroups<'a> {
+        TlvGroups { data: self.data }
+    }
+
+    /// Find the first item with the given type.
+    ///
+    /// Fails if the data before the item, or the item itself, is malformed.
+    pub fn find(&self, tlv_type: impl Into<u8>) -> Result<Option<TlvItem<'a>>, Error> {
+        let tlv_type = tlv_type.into();
+
+        for item in self.iter() {
+            let item = item?;
+
+            if item.tlv_type() == tlv_type {
+                return Ok(Some(item));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Get the item with the given type, failing if it is not present.
+    pub fn get(&self, tlv_type: impl Into<u8>) -> Result<TlvItem<'a>, Error> {
+        let tlv_type = tlv_type.into();
+
+        self.find(tlv_type)?.ok_or(Error::TlvNotFound(tlv_type))
+    }
+
+    pub fn get_bool(&self, tlv_type: impl Into<u8>) -> Result<bool, Error> {
+        self.get(tlv_type)?.as_bool()
+    }
+
+    pub fn get_u8(&self, tlv_type: impl Into<u8>) -> Result<u8, Error> {
+        self.get(tlv_type)?.as_u8()
+    }
+
+    pub fn get_u16(&self, tlv_type: impl Into<u8>) -> Result<u16, Error> {
+        self.get(tlv_type)?.as_u16()
+    }
+
+    pub fn get_u32(&self, tlv_type: impl Into<u8>) -> Result<u32, Error> {
+        self.get(tlv_type)?.as_u32()
+    }
+
+    pub fn get_u64(&self, tlv_type: impl Into<u8>) -> Result<u64, Error> {
+        self.get(tlv_type)?.as_u64()
+    }
+
+    pub fn get_i32(&self, tlv_type: impl Into<u8>) -> Result<i32, Error> {
+        self.get(tlv_type)?.as_i32()
+    }
+
+    pub fn get_f32(&self, tlv_type: impl Into<u8>) -> Result<f32, Error> {
+        self.get(tlv_type)?.as_f32()
+    }
+
+    /// Get the value of an item without copying it.
+    ///
+    /// Fails with `Error::FragmentedTlv` if the value is split over multiple
+    /// items, use `get_bytes_into` to read values of arbitrary length.
+    pub fn get_bytes(&self, tlv_type: impl Into<u8>) -> Result<&'a [u8], Error> {
+        let tlv_type = tlv_type.into();
+
+        self.get(tlv_type)?
+            .as_slice()
+            .ok_or(Error::FragmentedTlv(tlv_type))
+    }
+
+    /// Copy the merged value of an item into a buffer.
+    pub fn get_bytes_into<'b>(
+        &self,
+        tlv_type: impl Into<u8>,
+        buffer: &'b mut [u8],
+    ) -> Result<&'b [u8], Error> {
+        self.get(tlv_type)?.copy_into(buffer)
+    }
+
+    /// Get the value of an item as UTF-8 string, without copying it.
+    pub fn get_str(&self, tlv_type: impl Into<u8>) -> Result<&'a str, Error> {
+        self.get(tlv_type)?.as_str()
+    }
+}
+
+impl<'a> IntoIterator for TlvReader<'a> {
+    type Item = Result<TlvItem<'a>, Error>;
+    type IntoIter = TlvIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over the items of TLV8 encoded data.
+///
+/// Iteration stops after the first error.
+#[derive(Debug, Clone)]
+pub struct TlvIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = Result<TlvItem<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let tlv_type = self.data[0];
+
+        let mut raw_len = 0;
+        let mut value_len = 0;
+
+        loop {
+            let fragment = &self.data[raw_len..];
+
+            let fragment_len = match fragment {
+                [_, len, ..] => *len as usize,
+                _ => {
+                    self.data = &[];
+                    return Some(Err(Error::BadLength));
+                }
+            };
+
+            if fragment.len() < 2 + fragment_len {
+                self.data = &[];
+                return Some(Err(Error::Truncated));
+            }
+
+            raw_len += 2 + fragment_len;
+            value_len += fragment_len;
+
+            // Only a fragment with the maximum length can be followed
+            // by another fragment of the same value.
+            let continues = fragment_len == MAX_FRAGMENT_LEN
+                && self.data.get(raw_len).copied() == Some(tlv_type);
+
+            if !continues {
+                break;
+            }
+        }
+
+        let (raw, rest) = self.data.split_at(raw_len);
+
+        self.data = rest;
+
+        Some(Ok(TlvItem {
+            tlv_type,
+            raw,
+            len: value_len,
+        }))
+    }
+}
+
+/// Iterator over the groups of a list.
+///
+/// Iteration stops after the first error.
+#[derive(Debug, Clone)]
+pub struct TlvGroups<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for TlvGroups<'a> {
+    type Item = Result<TlvReader<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let mut group_len = 0;
+        let mut rest: &'a [u8] = &[];
+
+        for item in (TlvIter { data: self.data }) {
+            match item {
+                Ok(item) if item.is_separator() => {
+                    rest = &self.data[group_len + item.raw.len()..];
+                    break;
+                }
+                Ok(item) => group_len += item.raw.len(),
+                Err(e) => {
+                    self.data = &[];
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let group = &self.data[..group_len];
+
+        self.data = rest;
+
+        Some(Ok(TlvReader::new(group)))
+    }
+}
+
+/// A single TLV8 item, which can consist of multiple fragments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TlvItem<'a> {
+    tlv_type: u8,
+
+    /// Encoded fragments of the item, including their headers
+    raw: &'a [u8],
+
+    /// Length of the merged value
+    len: usize,
+}
+
+impl<'a> TlvItem<'a> {
+    pub fn tlv_type(&self) -> u8 {
+        self.tlv_type
+    }
+
+    /// Length of the merged value
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Check if this is a separator item between the groups of a list
+    pub fn is_separator(&self) -> bool {
+        self.tlv_type == SEPARATOR_TYPE
+    }
+
+    /// Check if the value is split over multiple items
+    pub fn is_fragmented(&self) -> bool {
+        self.raw.len() != self.len + 2
+    }
+
+    /// Iterate over the value of each fragment.
+    pub fn chunks(&self) -> impl Iterator<Item = &'a [u8]> {
+        let mut raw = self.raw;
+
+        core::iter::from_fn(move || {
+            // The fragments have been validated when creating the item
+            let len = *raw.get(1)? as usize;
+
+            let (fragment, rest) = raw.split_at(2 + len);
+
+            raw = rest;
+
+            Some(&fragment[2..])
+        })
+    }
+
+    /// The value, if it is contained in a single fragment.
+    pub fn as_slice(&self) -> Option<&'a [u8]> {
+        if self.is_fragmented() {
+            None
+        } else {
+            Some(&self.raw[2..])
+        }
+    }
+
+    /// Copy the merged value into a buffer.
+    pub fn copy_into<'b>(&self, buffer: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        let buffer = buffer
+            .get_mut(..self.len)
+            .ok_or(Error::InsufficientBuffer)?;
+
+        let mut offset = 0;
+
+        for chunk in self.chunks() {
+            buffer[offset..offset + chunk.len()].copy_from_slice(chunk);
+            offset += chunk.len();
+        }
+
+        Ok(buffer)
+    }
+
+    /// Decode a little endian unsigned integer with at most `N` bytes.
+    ///
+    /// Shorter values are zero-extended.
+    fn as_le_bytes<const N: usize>(&self) -> Result<[u8; N], Error> {
+        let value = self
+            .as_slice()
+            .filter(|value| !value.is_empty() && value.len() <= N)
+            .ok_or(Error::InvalidTlvValue(self.tlv_type))?;
+
+        let mut bytes = [0u8; N];
+        bytes[..value.len()].copy_from_slice(value);
+
+        Ok(bytes)
+    }
+
+    /// Decode a boolean, which has to be a single byte with the value 0 or 1.
+    pub fn as_bool(&self) -> Result<bool, Error> {
+        match self.as_slice() {
+            Some([0]) => Ok(false),
+            Some([1]) => Ok(true),
+            _ => Err(Error::InvalidTlvValue(self.tlv_type)),
+        }
+    }
+
+    pub fn as_u8(&self) -> Result<u8, Error> {
+        self.as_le_bytes().map(u8::from_le_bytes)
+    }
+
+    pub fn as_u16(&self) -> Result<u16, Error> {
+        self.as_le_bytes().map(u16::from_le_bytes)
+    }
+
+    pub fn as_u32(&self) -> Result<u32, Error> {
+        self.as_le_bytes().map(u32::from_le_bytes)
+    }
+
+    pub fn as_u64(&self) -> Result<u64, Error> {
+        self.as_le_bytes().map(u64::from_le_bytes)
+    }
+
+    /// Decode a signed integer with at most 4 bytes.
+    ///
+    /// Shorter values are sign-extended.
+    pub fn as_i32(&self) -> Result<i32, Error> {
+        let mut bytes = self.as_le_bytes::<4>()?;
+
+        // Unwrap is safe, the value has at least one byte
+        let len = self.as_slice().unwrap().len();
+
+        if bytes[len - 1] & 0x80 != 0 {
+            bytes[len..].fill(0xff);
+        }
+
+        Ok(i32::from_le_bytes(bytes))
+    }
+
+    /// Decode a 32-bit floating point number, which has to use exactly 4 bytes.
+    pub fn as_f32(&self) -> Result<f32, Error> {
+        match self.as_slice() {
+            Some(&[b0, b1, b2, b3]) => Ok(f32::from_le_bytes([b0, b1, b2, b3])),
+            _ => Err(Error::InvalidTlvValue(self.tlv_type)),
+        }
+    }
+
+    /// The value as UTF-8 string, if it is contained in a single fragment.
+    pub fn as_str(&self) -> Result<&'a str, Error> {
+        let value = self.as_slice().ok_or(Error::FragmentedTlv(self.tlv_type))?;
+
+        str::from_utf8(value).map_err(|_| Error::InvalidTlvValue(self.tlv_type))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tlv::{Tlv, TlvWriter};
+    use proptest::{collection::vec, prelude::*};
+
+    #[test]
+    fn read_items() {
+        let data = [6, 1, 2, 1, 3, b'a', b'b', b'c', 0x0a, 2, 0x34, 0x12];
+
+        let reader = TlvReader::new(&data);
+
+        assert_eq!(reader.get_u8(6).unwrap(), 2);
+        assert_eq!(reader.get_str(1).unwrap(), "abc");
+        assert_eq!(reader.get_u16(0x0a).unwrap(), 0x1234);
+        assert_eq!(reader.get_u32(0x0a).unwrap(), 0x1234);
+        assert_eq!(reader.get_bytes(1).unwrap(), b"abc");
+
+        assert!(matches!(
+            reader.get_u8(0x0a),
+            Err(Error::InvalidTlvValue(0x0a))
+        ));
+        assert!(matches!(reader.get(7), Err(Error::TlvNotFound(7))));
+
+        let types: Vec<u8> = reader.iter().map(|item| item.unwrap().tlv_type()).collect();
+        assert_eq!(types, vec![6, 1, 0x0a]);
+    }
+
+    #[test]
+    fn merge_fragments() {
+        let value: Vec<u8> = (0..600).map(|i| i as u8).collect();
+
+        let mut data = vec![0u8; 700];
+        let mut len = Tlv::new(3, &value[..]).write_into(&mut data).unwrap();
+        len += Tlv::new(6, 4u8).write_into(&mut data[len..]).unwrap();
+
+        let reader = TlvReader::new(&data[..len]);
+
+        let item = reader.get(3).unwrap();
+        assert!(item.is_fragmented());
+        assert_eq!(item.len(), 600);
+        assert_eq!(item.chunks().count(), 3);
+
+        assert!(matches!(reader.get_bytes(3), Err(Error::FragmentedTlv(3))));
+
+        let mut buffer = [0u8; 600];
+        assert_eq!(reader.get_bytes_into(3, &mut buffer).unwrap(), &value[..]);
+
+        let mut short_buffer = [0u8; 599];
+        assert!(matches!(
+            reader.get_bytes_into(3, &mut short_buffer),
+            Err(Error::InsufficientBuffer)
+        ));
+
+        assert_eq!(reader.get_u8(6).unwrap(), 4);
+        assert_eq!(reader.iter().count(), 2);
+    }
+
+    #[test]
+    fn maximum_length_item_followed_by_other_type() {
+        let mut data = vec![1, 0xff];
+        data.extend_from_slice(&[0xaa; 0xff]);
+        data.extend_from_slice(&[2, 1, 0x42]);
+
+        let reader = TlvReader::new(&data);
+
+        let item = reader.get(1).unwrap();
+        assert!(!item.is_fragmented());
+        assert_eq!(item.len(), 0xff);
+
+        assert_eq!(reader.get_u8(2).unwrap(), 0x42);
+    }
+
+    #[test]
+    fn read_truncated_item() {
+        let data = [6, 1, 2, 1, 4, b'a'];
+
+        let reader = TlvReader::new(&data);
+
+        assert_eq!(reader.get_u8(6).unwrap(), 2);
+        assert!(matches!(reader.get(1), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn read_signed_values() {
+        let data = [
+            1, 1, 1, 2, 4, 0xfe, 0xff, 0xff, 0xff, 3, 1, 0xfe, 4, 4, 0, 0, 0x80, 0x3f,
+        ];
+
+        let reader = TlvReader::new(&data);
+
+        assert!(reader.get_bool(1).unwrap());
+        assert_eq!(reader.get_i32(2).unwrap(), -2);
+        assert_eq!(reader.get_i32(3).unwrap(), -2);
+        assert_eq!(reader.get_u8(3).unwrap(), 0xfe);
+        assert_eq!(reader.get_f32(4).unwrap(), 1.0);
+
+        assert!(matches!(reader.get_bool(3), Err(Error::InvalidTlvValue(3))));
+        assert!(matches!(reader.get_f32(3), Err(Error::InvalidTlvValue(3))));
+    }
+
+    #[test]
+    fn read_groups() {
+        let data = [1, 1, 0x0a, 2, 1, 0, 0xff, 0, 1, 1, 0x0b, 0xff, 0, 0xff, 0];
+
+        let groups: Vec<TlvReader> = TlvReader::new(&data)
+            .groups()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0].get_u8(1).unwrap(), 0x0a);
+        assert_eq!(groups[0].get_u8(2).unwrap(), 0);
+        assert_eq!(groups[1].get_u8(1).unwrap(), 0x0b);
+        assert_eq!(groups[2].iter().count(), 0);
+    }
+
+    #[test]
+    fn read_groups_without_separator() {
+        let data = [1, 1, 0x0a];
+
+        let mut groups = TlvReader::new(&data).groups();
+
+        assert_eq!(groups.next().unwrap().unwrap().get_u8(1).unwrap(), 0x0a);
+        assert!(groups.next().is_none());
+
+        assert!(TlvReader::new(&[]).groups().next().is_none());
+    }
+
+    #[test]
+    fn read_truncated_group() {
+        let data = [1, 1, 0x0a, 0xff, 0, 1, 4, 0x0b];
+
+        let mut groups = TlvReader::new(&data).groups();
+
+        assert!(groups.next().unwrap().is_ok());
+        assert!(matches!(groups.next(), Some(Err(Error::Truncated))));
+        assert!(groups.next().is_none());
+    }
+
+    proptest! {
+        #[test]
+        fn write_and_read_list(values in vec(vec(any::<u8>(), 0..300), 1..4)) {
+            let mut data = vec![0u8; 1300];
+            let mut writer = TlvWriter::new(&mut data);
+
+            writer.push_list(&values, |writer, value| {
+                writer.push(Tlv::new(1, &value[..]))?;
+                writer.push(Tlv::new(2, value.len() as u16)).map(|_| ())
+            }).unwrap();
+
+            let mut buffer = [0u8; 300];
+            let mut groups = 0;
+
+            for (group, value) in TlvReader::new(writer.finish()).groups().zip(&values) {
+                let group = group.unwrap();
+
+                prop_assert_eq!(group.get_bytes_into(1, &mut buffer).unwrap(), &value[..]);
+                prop_assert_eq!(group.get_u16(2).unwrap() as usize, value.len());
+
+                groups += 1;
+            }
+
+            prop_assert_eq!(groups, values.len());
+        }
+
+        #[test]
+        fn write_and_read_numbers(signed in any::<i32>(), float in any::<f32>(), boolean in any::<bool>()) {
+            let mut data = [0u8; 64];
+            let mut writer = TlvWriter::new(&mut data);
+
+            writer.push(Tlv::new(1, signed)).unwrap();
+            writer.push(Tlv::new(2, float)).unwrap();
+            writer.push(Tlv::new(3, boolean)).unwrap();
+
+            let reader = TlvReader::new(writer.finish());
+
+            prop_assert_eq!(reader.get_i32(1).unwrap(), signed);
+            prop_assert_eq!(reader.get_f32(2).unwrap().to_bits(), float.to_bits());
+            prop_assert_eq!(reader.get_bool(3).unwrap(), boolean);
+        }
+
+        #[test]
+        fn read_arbitrary_data(data in vec(any::<u8>(), 0..600)) {
+            let reader = TlvReader::new(&data);
+
+            let mut buffer = [0u8; 600];
+
+            for item in reader.iter().flatten() {
+                let value = item.copy_into(&mut buffer).unwrap();
+                prop_assert_eq!(value.len(), item.len());
+            }
+        }
+
+        #[test]
+        fn write_and_read_value(value in vec(any::<u8>(), 0..1000)) {
+            let mut data = vec![0u8; 1100];
+            let len = Tlv::new(1, &value[..]).write_into(&mut data).unwrap();
+
+            let reader = TlvReader::new(&data[..len]);
+
+            let mut buffer = [0u8; 1000];
+            prop_assert_eq!(reader.get_bytes_into(1, &mut buffer).unwrap(), &value[..]);
+            prop_assert_eq!(reader.iter().count(), 1);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Bounds-checked writers for TLV8 data
+//!
+//! The writers append complete items to a buffer, splitting long values
+//! into multiple items. An item which does not fit into the remaining
+//! space is rejected with `Error::InsufficientBuffer`, and the buffer
+//! is left unchanged.
+//!
+//! Lists are written with `push_list`, which adds a separator item
+//! between the groups of the list.
+//!
+//! Code which should work with both writers can use the `TlvWrite` trait.
+
+use core::ops::Deref;
+
+use heapless::{ArrayLength, Vec};
+
+use super::{Tlv, MAX_FRAGMENT_LEN};
+use crate::Error;
+
+/// Calculate the encoded size of an item with a value of `value_len` bytes.
+///
+/// This can be used to size buffers at compile time.
+pub const fn encoded_len(value_len: usize) -> usize {
+    // Every item has a 2 byte header, and an empty value
+    // still requires one item.
+    let num_items = if value_len == 0 {
+        1
+    } else {
+        value_len.div_ceil(MAX_FRAGMENT_LEN)
+    };
+
+    value_len + 2 * num_items
+}
+
+/// Writer for TLV8 data into a borrowed buffer
+#[derive(Debug)]
+pub struct TlvWriter<'a> {
+    buffer: &'a mut [u8],
+
+    /// Number of bytes written
+    len: usize,
+}
+
+impl<'a> TlvWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        TlvWriter { buffer, len: 0 }
+    }
+
+    /// Append an item to the buffer.
+    pub fn push(&mut self, tlv: Tlv) -> Result<&mut Self, Error> {
+        self.len += tlv.write_into(&mut self.buffer[self.len..])?;
+
+        Ok(self)
+    }
+
+    /// Append a list of groups, separated by separator items.
+    ///
+    /// Each group is written by calling `push_group`. If writing any
+    /// of the groups fails, the complete list is removed again.
+    pub fn push_list<T>(
+        &mut self,
+        groups: impl IntoIterator<Item = T>,
+        mut push_group: impl FnMut(&mut Self, T) -> Result<(), Error>,
+    ) -> Result<&mut Self, Error> {
+        let start = self.len;
+
+        for (index, group) in groups.into_iter().enumerate() {
+            let result = if index > 0 {
+                self.push(Tlv::separator()).map(|_| ())
+            } else {
+                Ok(())
+            };
+
+            if let Err(e) = result.and_then(|_| push_group(self, group)) {
+                self.len = start;
+                return Err(e);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Number of bytes written
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bytes which can still be written
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.len
+    }
+
+    /// The data written so far
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// Consume the writer, returning the data written.
+    pub fn finish(self) -> &'a [u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+/// Writer for TLV8 data which owns its buffer
+///
+/// The data can be accessed through `Deref<Target = [u8]>`.
+#[derive(Debug)]
+pub struct TlvVec<N: ArrayLength<u8>> {
+    data: Vec<u8, N>,
+}
+
+impl<N: ArrayLength<u8>> TlvVec<N> {
+    pub fn new() -> Self {
+        TlvVec { data: Vec::new() }
+    }
+
+    /// Append an item to the buffer.
+    pub fn push(&mut self, tlv: Tlv) -> Result<&mut Self, Error> {
+        let len = self.data.len();
+        let size = tlv.size();
+
+        if len + size > self.data.capacity() {
+            return Err(Error::InsufficientBuffer);
+        }
+
+        // Resizing can't fail, the capacity has been checked above
+        let _ = self.data.resize(len + size, 0);
+
+        tlv.write_into(&mut self.data[len..])?;
+
+        Ok(self)
+    }
+
+    /// Append a list of groups, separated by separator items.
+    ///
+    /// Each group is written by calling `push_group`. If writing any
+    /// of the groups fails, the complete list is removed again.
+    pub fn push_list<T>(
+        &mut self,
+        groups: impl IntoIterator<Item = T>,
+        mut push_group: impl FnMut(&mut Self, T) -> Result<(), Error>,
+    ) -> Result<&mut Self, Error> {
+        let start = self.data.len();
+
+        for (index, group) in groups.into_iter().enumerate() {
+            let result = if index > 0 {
+                self.push(Tlv::separator()).map(|_| ())
+            } else {
+                Ok(())
+            };
+
+            if let Err(e) = result.and_then(|_| push_group(self, group)) {
+                while self.data.len() > start {
+                    self.data.pop();
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Remove all items.
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    /// Consume the writer, returning the underlying vector.
+    pub fn into_inner(self) -> Vec<u8, N> {
+        self.data
+    }
+}
+
+impl<N: ArrayLength<u8>> Default for TlvVec<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N: ArrayLength<u8>> Deref for TlvVec<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Common interface of the TLV8 writers
+pub trait TlvWrite {
+    /// Append an item to the buffer.
+    fn push_tlv(&mut self, tlv: Tlv) -> Result<(), Error>;
+
+    /// Append a list of groups, separated by separator items.
+    ///
+    /// If writing any of the groups fails, the complete list is removed again.
+    fn push_tlv_list<T, F>(
+        &mut self,
+        groups: impl IntoIterator<Item = T>,
+        push_group: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&mut Self, T) -> Result<(), Error>;
+}
+
+impl TlvWrite for TlvWriter<'_> {
+    fn push_tlv(&mut self, tlv: Tlv) -> Result<(), Error> {
+        self.push(tlv).map(|_| ())
+    }
+
+    fn push_tlv_list<T, F>(
+        &mut self,
+        groups: impl IntoIterator<Item = T>,
+        push_group: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&mut Self, T) -> Result<(), Error>,
+    {
+        self.push_list(groups, push_group).map(|_| ())
+    }
+}
+
+impl<N: ArrayLength<u8>> TlvWrite for TlvVec<N> {
+    fn push_tlv(&mut self, tlv: Tlv) -> Result<(), Error> {
+        self.push(tlv).map(|_| ())
+    }
+
+    fn push_tlv_list<T, F>(
+        &mut self,
+        groups: impl IntoIterator<Item = T>,
+        push_group: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&mut Self, T) -> Result<(), Error>,
+    {
+        self.push_list(groups, push_group).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tlv::TlvReader;
+    use heapless::consts::{U16, U600};
+    use proptest::{collection::vec, prelude::*};
+
+    #[test]
+    fn write_items() {
+        let mut buffer = [0u8; 16];
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        writer
+            .push(Tlv::new(6, 1u8))
+            .and_then(|writer| writer.push(Tlv::new(1, &b"abc"[..])))
+            .unwrap();
+
+        assert_eq!(writer.len(), 8);
+        assert_eq!(writer.remaining(), 8);
+        assert_eq!(writer.finish(), &[6, 1, 1, 1, 3, b'a', b'b', b'c']);
+    }
+
+    #[test]
+    fn write_into_short_buffer() {
+        let mut buffer = [0u8; 7];
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        writer.push(Tlv::new(6, 1u8)).unwrap();
+
+        assert!(matches!(
+            writer.push(Tlv::new(1, 0x1234_5678u32)),
+            Err(Error::InsufficientBuffer)
+        ));
+
+        // A failed write doesn't change the written data
+        assert_eq!(writer.as_slice(), &[6, 1, 1]);
+
+        writer.push(Tlv::new(2, 0x1234u16)).unwrap();
+        assert_eq!(writer.remaining(), 0);
+    }
+
+    #[test]
+    fn write_into_vec() {
+        let mut writer = TlvVec::<U16>::new();
+
+        writer.push(Tlv::new(6, 1u8)).unwrap();
+
+        assert!(matches!(
+            writer.push(Tlv::new(1, &[0u8; 14][..])),
+            Err(Error::InsufficientBuffer)
+        ));
+        assert_eq!(&writer[..], &[6, 1, 1]);
+
+        writer.push(Tlv::new(1, &[0u8; 11][..])).unwrap();
+        assert_eq!(writer.len(), 16);
+    }
+
+    #[test]
+    fn write_list() {
+        let mut buffer = [0u8; 16];
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        writer
+            .push_list(&[1u8, 2, 3], |writer, value| {
+                writer.push(Tlv::new(1, *value)).map(|_| ())
+            })
+            .unwrap();
+
+        assert_eq!(
+            writer.as_slice(),
+            &[1, 1, 1, 0xff, 0, 1, 1, 2, 0xff, 0, 1, 1, 3]
+        );
+    }
+
+    #[test]
+    fn write_list_into_short_buffer() {
+        let mut writer = TlvVec::<U16>::new();
+
+        writer.push(Tlv::new(6, 1u8)).unwrap();
+
+        assert!(matches!(
+            writer.push_list(&[1u8, 2, 3, 4], |writer, value| {
+                writer.push(Tlv::new(1, *value)).map(|_| ())
+            }),
+            Err(Error::InsufficientBuffer)
+        ));
+
+        // The partially written list is removed
+        assert_eq!(&writer[..], &[6, 1, 1]);
+    }
+
+    #[test]
+    fn compile_time_size() {
+        const SIZE: usize = encoded_len(16) + encoded_len(2);
+
+        let buffer = [0u8; SIZE];
+        assert_eq!(buffer.len(), 22);
+
+        assert_eq!(encoded_len(0), 2);
+        assert_eq!(encoded_len(255), 257);
+        assert_eq!(encoded_len(256), 260);
+    }
+
+    proptest! {
+        #[test]
+        fn encoded_len_matches_size(value in vec(any::<u8>(), 0..1000)) {
+            prop_assert_eq!(encoded_len(value.len()), Tlv::new(1, &value[..]).size());
+        }
+
+        #[test]
+        fn write_and_read_items(values in vec(vec(any::<u8>(), 0..300), 0..4)) {
+            let mut writer = TlvVec::<U600>::new();
+
+            let mut written = 0;
+
+            for (index, value) in values.iter().enumerate() {
+                match writer.push(Tlv::new(index as u8, &value[..])) {
+                    Ok(_) => written += 1,
+                    Err(Error::InsufficientBuffer) => break,
+                    Err(e) => panic!("Unexpected error: {:?}", e),
+                }
+            }
+
+            let reader = TlvReader::new(&writer);
+            prop_assert_eq!(reader.iter().count(), written);
+
+            let mut buffer = [0u8; 300];
+
+            for (index, value) in values.iter().take(written).enumerate() {
+                prop_assert_eq!(reader.get_bytes_into(index as u8, &mut buffer).unwrap(), &value[..]);
+            }
+        }
+    }
+}
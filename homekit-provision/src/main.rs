@@ -0,0 +1,488 @@
+//! Provisioning of accessories during manufacturing
+//!
+//! Generates the manufacturing data record of an accessory, which contains
+//! the information shown in the Home app and the SRP verifier of the setup
+//! code instead of the code itself, and prints the setup payload for the QR
+//! code on the label of the accessory.
+//!
+//! The record has to be written to the `PROVISIONING` page in `memory.x`,
+//! for example with
+//!
+//! ```text
+//! probe-rs download --chip STM32WB55CCUx --binary-format bin --base-address 0x08034000 accessory.bin
+//! ```
+
+use std::{env, fs, process, str};
+
+use homekit_ble::{
+    identity::{DeviceId, DEVICE_ID_LEN},
+    provisioning::{
+        ManufacturingData, SetupMaterial, MAX_HARDWARE_REVISION_LEN, MAX_MANUFACTURER_LEN,
+        MAX_MODEL_LEN, MAX_NAME_LEN, MAX_RECORD_LEN, MAX_SERIAL_NUMBER_LEN,
+    },
+    qr::{EccLevel, QrCode},
+    setup_code::{setup_payload, SetupCode, SetupFlags, SetupId},
+};
+use rand_core::OsRng;
+
+const USAGE: &str = "\
+Usage: homekit-provision [OPTIONS] --manufacturer <NAME> --model <MODEL> --serial-number <SERIAL> <OUTPUT>
+
+Writes the manufacturing data record of an accessory to OUTPUT.
+
+Options:
+    --manufacturer <NAME>              Manufacturer of the accessory
+    --model <MODEL>                    Model of the accessory
+    --serial-number <SERIAL>           Serial number of the accessory
+    --hardware-revision <REVISION>     Hardware revision [default: 1.0.0]
+    --name <NAME>                      Name of the accessory, also advertised [default: model]
+    --setup-code <XXX-XX-XXX>          Setup code, generated if not given
+    --setup-id <XXXX>                  Setup ID, generated if not given
+    --device-id <XX:XX:XX:XX:XX:XX>    Device ID, generated if not given
+    --category <CATEGORY>              Accessory category [default: 10 (Sensor)]";
+
+/// Accessory category used if none is given
+const DEFAULT_CATEGORY: u8 = 10;
+
+/// Hardware revision used if none is given
+const DEFAULT_HARDWARE_REVISION: &str = "1.0.0";
+
+struct Options {
+    manufacturer: String,
+
+    model: String,
+
+    serial_number: String,
+
+    hardware_revision: String,
+
+    name: Option<String>,
+
+    setup_code: Option<SetupCode>,
+
+    setup_id: Option<SetupId>,
+
+    device_id: Option<DeviceId>,
+
+    category: u8,
+
+    output: String,
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1)).unwrap_or_else(|message| {
+        eprintln!("{}\n\n{}", message, USAGE);
+        process::exit(2);
+    });
+
+    let setup_code = options
+        .setup_code
+        .unwrap_or_else(|| SetupCode::generate(&mut OsRng));
+    let setup_id = options
+        .setup_id
+        .unwrap_or_else(|| SetupId::generate(&mut OsRng));
+    let device_id = options
+        .device_id
+        .unwrap_or_else(|| DeviceId::generate(&mut OsRng));
+
+    let record = ManufacturingData {
+        manufacturer: &options.manufacturer,
+        model: &options.model,
+        serial_number: &options.serial_number,
+        hardware_revision: &options.hardware_revision,
+        name: options.name.as_deref().unwrap_or(&options.model),
+        category: options.category,
+        setup: SetupMaterial::generate(&setup_code, setup_id, device_id, &mut OsRng),
+    };
+
+    let mut buffer = [0u8; MAX_RECORD_LEN];
+
+    // The lengths are checked while parsing the arguments
+    let encoded = record
+        .encode(&mut buffer)
+        .expect("Failed to encode manufacturing data");
+
+    if let Err(e) = fs::write(&options.output, encoded) {
+        eprintln!("Failed to write {}: {}", options.output, e);
+        process::exit(1);
+    }
+
+    let payload = setup_payload(
+        &setup_code,
+        &setup_id,
+        options.category,
+        SetupFlags {
+            ble: true,
+            ..SetupFlags::default()
+        },
+    );
+
+    println!("Name:          {}", record.name);
+    println!("Serial number: {}", record.serial_number);
+    println!("Setup code:    {}", ascii(&setup_code.to_bytes()));
+    println!("Setup ID:      {}", ascii(setup_id.as_bytes()));
+    println!("Device ID:     {}", ascii(&device_id.pairing_id()));
+    println!("Setup payload: {}", ascii(&payload));
+
+    match QrCode::encode(&payload, EccLevel::Medium) {
+        Ok(code) => code.render_ascii(|line| println!("{}", line)),
+        Err(e) => eprintln!("Failed to encode setup payload: {:?}", e),
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        manufacturer: String::new(),
+        model: String::new(),
+        serial_number: String::new(),
+        hardware_revision: DEFAULT_HARDWARE_REVISION.into(),
+        name: None,
+        setup_code: None,
+        setup_id: None,
+        device_id: None,
+        category: DEFAULT_CATEGORY,
+        output: String::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if !options.output.is_empty() {
+                return Err(format!("Unexpected argument '{}'", arg));
+            }
+
+            options.output = arg;
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for '{}'", arg))?;
+
+        let invalid = || format!("Invalid value for '{}': '{}'", arg, value);
+
+        // Strings have to fit into the characteristics of the Accessory Information service
+        let string = |max_len: usize| {
+            if value.is_empty() || value.len() > max_len {
+                Err(format!(
+                    "Invalid value for '{}': must be 1 to {} bytes long",
+                    arg, max_len
+                ))
+            } else {
+                Ok(value.clone())
+            }
+        };
+
+        match arg.as_str() {
+            "--manufacturer" => options.manufacturer = string(MAX_MANUFACTURER_LEN)?,
+            "--model" => options.model = string(MAX_MODEL_LEN)?,
+            "--serial-number" => options.serial_number = string(MAX_SERIAL_NUMBER_LEN)?,
+            "--hardware-revision" => {
+                options.hardware_revision = string(MAX_HARDWARE_REVISION_LEN)?;
+            }
+            "--name" => options.name = Some(string(MAX_NAME_LEN)?),
+            "--setup-code" => {
+                options.setup_code =
+                    Some(SetupCode::parse(value.as_bytes()).map_err(|_| invalid())?);
+            }
+            "--setup-id" => {
+                options.setup_id = Some(SetupId::new(value.as_bytes()).map_err(|_| invalid())?);
+            }
+            "--device-id" => {
+                options.device_id = Some(parse_device_id(&value).ok_or_else(invalid)?);
+            }
+            "--category" => {
+                options.category = value.parse().map_err(|_| invalid())?;
+            }
+            _ => return Err(format!("Unknown option '{}'", arg)),
+        }
+    }
+
+    for (value, option) in [
+        (&options.manufacturer, "--manufacturer"),
+        (&options.model, "--model"),
+        (&options.serial_number, "--serial-number"),
+    ] {
+        if value.is_empty() {
+            return Err(format!("Missing option '{}'", option));
+        }
+    }
+
+    // The model is used as name, which is shorter
+    if options.name.is_none() && options.model.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "Model is longer than {} bytes, a shorter '--name' is required",
+            MAX_NAME_LEN
+        ));
+    }
+
+    if options.output.is_empty() {
+        return Err("Missing output file".into());
+    }
+
+    Ok(options)
+}
+
+/// Parse a Device ID formatted as `XX:XX:XX:XX:XX:XX`.
+fn parse_device_id(value: &str) -> Option<DeviceId> {
+    let mut device_id = [0u8; DEVICE_ID_LEN];
+
+    let mut parts = value.split(':');
+
+    for byte in device_id.iter_mut() {
+        // `from_str_radix` would also accept a sign
+        let part = parts
+            .next()
+            .filter(|part| part.len() == 2 && part.bytes().all(|c| c.is_ascii_hexdigit()))?;
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(DeviceId(device_id))
+}
+
+fn ascii(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).unwrap_or("")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse_error(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("Arguments {:?} should be rejected", args),
+            Err(message) => message,
+        }
+    }
+
+    const REQUIRED: [&str; 7] = [
+        "--manufacturer",
+        "Dominik Corp.",
+        "--model",
+        "M001",
+        "--serial-number",
+        "S12345",
+        "accessory.bin",
+    ];
+
+    /// Required arguments, with the option `option` set to `value`
+    fn with_option(option: &str, value: &str) -> Vec<String> {
+        let mut args: Vec<String> = vec![option.into(), value.into()];
+        args.extend(REQUIRED.iter().map(|arg| arg.to_string()));
+        args
+    }
+
+    fn parse_option_error(option: &str, value: &str) -> String {
+        let args = with_option(option, value);
+
+        match parse_args(args.into_iter()) {
+            Ok(_) => panic!("Value '{}' for '{}' should be rejected", value, option),
+            Err(message) => message,
+        }
+    }
+
+    #[test]
+    fn required_options() {
+        let options = parse(&REQUIRED).unwrap();
+
+        assert_eq!(options.manufacturer, "Dominik Corp.");
+        assert_eq!(options.model, "M001");
+        assert_eq!(options.serial_number, "S12345");
+        assert_eq!(options.hardware_revision, DEFAULT_HARDWARE_REVISION);
+        assert_eq!(options.name, None);
+        assert!(options.setup_code.is_none());
+        assert!(options.setup_id.is_none());
+        assert!(options.device_id.is_none());
+        assert_eq!(options.category, DEFAULT_CATEGORY);
+        assert_eq!(options.output, "accessory.bin");
+    }
+
+    #[test]
+    fn all_options() {
+        let mut args: Vec<String> = [
+            "--hardware-revision",
+            "2.1.0",
+            "--name",
+            "hokt",
+            "--setup-code",
+            "518-08-582",
+            "--setup-id",
+            "1QJ8",
+            "--device-id",
+            "C8:D8:3d:0a:B0:4b",
+            "--category",
+            "5",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        args.extend(REQUIRED.iter().map(|arg| arg.to_string()));
+
+        let options = parse_args(args.into_iter()).unwrap();
+
+        assert_eq!(options.hardware_revision, "2.1.0");
+        assert_eq!(options.name.as_deref(), Some("hokt"));
+        assert_eq!(&options.setup_code.unwrap().to_bytes(), b"518-08-582");
+        assert_eq!(options.setup_id.unwrap().as_bytes(), b"1QJ8");
+        assert_eq!(
+            options.device_id,
+            Some(DeviceId([0xc8, 0xd8, 0x3d, 0x0a, 0xb0, 0x4b]))
+        );
+        assert_eq!(options.category, 5);
+    }
+
+    #[test]
+    fn missing_required_option() {
+        for i in (0..6).step_by(2) {
+            let mut args = REQUIRED.to_vec();
+            let option = args.remove(i);
+            args.remove(i);
+
+            assert_eq!(parse_error(&args), format!("Missing option '{}'", option));
+        }
+    }
+
+    #[test]
+    fn missing_output_file() {
+        assert_eq!(parse_error(&REQUIRED[..6]), "Missing output file");
+    }
+
+    #[test]
+    fn missing_value() {
+        let mut args = REQUIRED.to_vec();
+        args.push("--category");
+
+        assert_eq!(parse_error(&args), "Missing value for '--category'");
+    }
+
+    #[test]
+    fn unexpected_arguments() {
+        let mut args = REQUIRED.to_vec();
+        args.push("other.bin");
+
+        assert_eq!(parse_error(&args), "Unexpected argument 'other.bin'");
+
+        assert_eq!(
+            parse_option_error("--unknown", "value"),
+            "Unknown option '--unknown'"
+        );
+    }
+
+    #[test]
+    fn string_too_long() {
+        for (option, max_len) in [
+            ("--manufacturer", MAX_MANUFACTURER_LEN),
+            ("--model", MAX_MODEL_LEN),
+            ("--serial-number", MAX_SERIAL_NUMBER_LEN),
+            ("--hardware-revision", MAX_HARDWARE_REVISION_LEN),
+            ("--name", MAX_NAME_LEN),
+        ] {
+            let message = format!(
+                "Invalid value for '{}': must be 1 to {} bytes long",
+                option, max_len
+            );
+
+            assert_eq!(
+                parse_option_error(option, &"x".repeat(max_len + 1)),
+                message
+            );
+            assert_eq!(parse_option_error(option, ""), message);
+        }
+    }
+
+    #[test]
+    fn long_model_requires_name() {
+        let model = "x".repeat(MAX_NAME_LEN + 1);
+
+        let mut args = REQUIRED.to_vec();
+        args[3] = &model;
+
+        assert_eq!(
+            parse_error(&args),
+            format!(
+                "Model is longer than {} bytes, a shorter '--name' is required",
+                MAX_NAME_LEN
+            )
+        );
+
+        args.extend_from_slice(&["--name", "hokt"]);
+
+        let options = parse(&args).unwrap();
+
+        assert_eq!(options.model, model);
+        assert_eq!(options.name.as_deref(), Some("hokt"));
+    }
+
+    #[test]
+    fn invalid_setup_code() {
+        for value in ["518-08-58", "518-08-58x", "518+08+582", "123-45-678"] {
+            assert_eq!(
+                parse_option_error("--setup-code", value),
+                format!("Invalid value for '--setup-code': '{}'", value)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_setup_id() {
+        for value in ["1QJ", "1QJ89", "1qj8", "1QJ-"] {
+            assert_eq!(
+                parse_option_error("--setup-id", value),
+                format!("Invalid value for '--setup-id': '{}'", value)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_category() {
+        assert_eq!(
+            parse_option_error("--category", "256"),
+            "Invalid value for '--category': '256'"
+        );
+    }
+
+    #[test]
+    fn device_id() {
+        assert_eq!(
+            parse_device_id("00:11:22:aa:BB:ff"),
+            Some(DeviceId([0x00, 0x11, 0x22, 0xaa, 0xbb, 0xff]))
+        );
+    }
+
+    #[test]
+    fn device_id_wrong_segment_count() {
+        assert_eq!(parse_device_id(""), None);
+        assert_eq!(parse_device_id("00:11:22:33:44"), None);
+        assert_eq!(parse_device_id("00:11:22:33:44:55:66"), None);
+        assert_eq!(parse_device_id("00:11:22:33:44:55:"), None);
+    }
+
+    #[test]
+    fn device_id_wrong_segment_length() {
+        assert_eq!(parse_device_id("0:11:22:33:44:55"), None);
+        assert_eq!(parse_device_id("000:11:22:33:44:55"), None);
+        assert_eq!(parse_device_id("001122:33:44:55"), None);
+    }
+
+    #[test]
+    fn device_id_non_hex_digits() {
+        assert_eq!(parse_device_id("0g:11:22:33:44:55"), None);
+        assert_eq!(parse_device_id("+1:11:22:33:44:55"), None);
+        assert_eq!(parse_device_id("00:11:22:33:44: 5"), None);
+    }
+
+    #[test]
+    fn invalid_device_id_option() {
+        assert_eq!(
+            parse_option_error("--device-id", "00:11:22:33:44"),
+            "Invalid value for '--device-id': '00:11:22:33:44'"
+        );
+    }
+}
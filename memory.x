@@ -1,6 +1,11 @@
 MEMORY
 {
-    FLASH (rx)                 : ORIGIN = 0x08000000, LENGTH = 256K 
+    FLASH (rx)                 : ORIGIN = 0x08000000, LENGTH = 192K
+    /* Key-value store, see src/store.rs */
+    STORE (rw)                 : ORIGIN = 0x08030000, LENGTH = 16K
+    /* Manufacturing data written by homekit-provision, see src/provisioning.rs.
+       The wireless stack of CPU2 has to be installed above it. */
+    PROVISIONING (r)           : ORIGIN = 0x08034000, LENGTH = 4K
     RAM (xrw)                  : ORIGIN = 0x20000004, LENGTH = 191K
     RAM_SHARED (xrw)           : ORIGIN = 0x20030000, LENGTH = 10K
 }
@@ -0,0 +1,60 @@
+//! Factory reset button
+//!
+//! SW1 of the USB dongle is connected to PA10, and pulls it low when pressed.
+//! Holding it at boot removes all pairings, so that an accessory which
+//! refuses Pair Setup after too many failed attempts can be paired again.
+
+use core::time::Duration;
+
+use hal::device::{GPIOA, RCC};
+use rtt_target::rprintln;
+
+use crate::clock;
+
+/// Time the button has to be held at boot for a factory reset
+const FACTORY_RESET_HOLD_TIME: Duration = Duration::from_secs(5);
+
+/// Check if the button is held for a factory reset.
+///
+/// The pin is only read once at boot, so it is configured directly,
+/// without the HAL.
+pub fn factory_reset_requested(gpio: GPIOA) -> bool {
+    let rcc = unsafe { &*RCC::ptr() };
+
+    rcc.ahb2enr.modify(|_, w| w.gpioaen().set_bit());
+
+    // Input with pull-up
+    gpio.moder.modify(|_, w| unsafe { w.mode10().bits(0b00) });
+    gpio.pupdr.modify(|_, w| unsafe { w.pupd10().bits(0b01) });
+
+    // Wait for the pull-up, before the pin is read
+    wait(Duration::from_millis(1));
+
+    let is_pressed = || gpio.idr.read().id10().bit_is_clear();
+
+    if !is_pressed() {
+        return false;
+    }
+
+    rprintln!(
+        "Keep the button pressed for {} s to reset the accessory",
+        FACTORY_RESET_HOLD_TIME.as_secs()
+    );
+
+    let start = clock::now();
+
+    while clock::now() - start < FACTORY_RESET_HOLD_TIME {
+        if !is_pressed() {
+            rprintln!("Button released, no factory reset");
+            return false;
+        }
+    }
+
+    true
+}
+
+fn wait(duration: Duration) {
+    let start = clock::now();
+
+    while clock::now() - start < duration {}
+}
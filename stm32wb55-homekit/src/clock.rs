@@ -0,0 +1,37 @@
+//! Monotonic time since boot, counted by the SysTick timer
+
+use core::{cell::Cell, time::Duration};
+
+use cortex_m::{
+    interrupt::{self, Mutex},
+    peripheral::{syst::SystClkSource, SYST},
+};
+use cortex_m_rt::exception;
+
+/// Frequency of the CPU1 clock, which drives the SysTick timer
+const CPU1_FREQUENCY: u32 = 64_000_000;
+
+/// Milliseconds since the timer has been started
+static MILLIS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// Start counting, with an interrupt every millisecond.
+pub fn init(mut syst: SYST) {
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(CPU1_FREQUENCY / 1000 - 1);
+    syst.clear_current();
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+/// Time since the timer has been started
+pub fn now() -> Duration {
+    Duration::from_millis(interrupt::free(|cs| MILLIS.borrow(cs).get()))
+}
+
+#[exception]
+fn SysTick() {
+    interrupt::free(|cs| {
+        let millis = MILLIS.borrow(cs);
+        millis.set(millis.get() + 1);
+    });
+}
@@ -0,0 +1,246 @@
+//! Flash access while CPU2 is running
+//!
+//! CPU2 executes the wireless stack from the same flash, and is stalled
+//! while CPU1 programs or erases it. AN5289 describes how both CPUs share
+//! the flash:
+//!
+//! - The flash controller is owned by the CPU holding hardware semaphore 2.
+//! - CPU2 holds semaphore 7 while its radio timing doesn't allow a stall,
+//!   so an operation is only started while semaphore 7 is free.
+//! - Erasing a page takes long enough that CPU2 has to be told about it
+//!   with the SHCI command `C2_FLASH_ERASE_ACTIVITY`, so that it can
+//!   schedule its radio activity around the erase.
+//!
+//! The HAL supports neither the semaphores nor this SHCI command, so the
+//! registers and the system channel of the mailbox are accessed directly.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use cortex_m::{asm, interrupt};
+use hal::device::RCC;
+use homekit_ble::Error;
+use rtt_target::rprintln;
+
+use crate::clock;
+
+/// Longest time to wait for CPU2, after which the flash access fails
+/// instead of blocking the accessory
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Base address of the hardware semaphores
+const HSEM_BASE: usize = 0x5800_1400;
+
+/// Offset of the read lock registers, which take a semaphore when read
+const HSEM_RLR_OFFSET: usize = 0x80;
+
+/// Lock bit of the semaphore registers
+const HSEM_LOCK: u32 = 1 << 31;
+
+/// Core ID of CPU1, as written to the semaphore registers
+const HSEM_COREID_CPU1: u32 = 4 << 8;
+
+/// Semaphore of the flash controller
+const FLASH_SEMAPHORE: usize = 2;
+
+/// Semaphore held by CPU2 while the flash must not be programmed or erased
+const BLOCKED_BY_CPU2_SEMAPHORE: usize = 7;
+
+/// Base address of the IPCC
+const IPCC_BASE: usize = 0x5800_0c00;
+
+/// Offset of the CPU1 status set and clear register
+const IPCC_C1SCR_OFFSET: usize = 0x08;
+
+/// Offset of the CPU1 to CPU2 status register
+const IPCC_C1TOC2SR_OFFSET: usize = 0x0c;
+
+/// IPCC channel of system commands, which is channel 2
+const SYSTEM_CMD_CHANNEL: u32 = 1 << 1;
+
+/// Address of the system table in shared RAM, see `TL_SYS_TABLE` in `memory.x`
+///
+/// Its first field points to the system command buffer.
+const TL_SYS_TABLE: usize = 0x2003_0058;
+
+/// Packet type of system commands
+const SYSTEM_CMD_PACKET: u8 = 0x10;
+
+/// Opcode of the SHCI command `C2_FLASH_ERASE_ACTIVITY`
+const FLASH_ERASE_ACTIVITY: u16 = 0xfc69;
+
+/// Offset of the packet type in a command packet, after the list header
+const CMD_TYPE_OFFSET: usize = 8;
+
+/// Offset of the event code in the event which replaces the command in the buffer
+const RESPONSE_EVENT_CODE_OFFSET: usize = 9;
+
+/// Event code of the command complete event
+const COMMAND_COMPLETE_EVENT: u8 = 0x0e;
+
+/// Offset of the opcode in the command complete event
+const RESPONSE_OPCODE_OFFSET: usize = 12;
+
+/// Offset of the status in the command complete event
+const RESPONSE_STATUS_OFFSET: usize = 14;
+
+/// The system channel of the mailbox is ready for commands
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Enable the flash handshake, once CPU2 has sent its ready event.
+///
+/// Before, CPU2 doesn't access the flash and can't receive commands.
+pub fn set_ready() {
+    // The HAL doesn't support the semaphores, so the clock is enabled directly
+    let rcc = unsafe { &*RCC::ptr() };
+    rcc.ahb3enr.modify(|_, w| w.hsemen().set_bit());
+
+    READY.store(true, Ordering::SeqCst);
+}
+
+/// Program or erase the flash, without disturbing CPU2.
+///
+/// The operation has to be short, as interrupts are disabled while it runs.
+pub fn flash_operation<T>(operation: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    if !READY.load(Ordering::SeqCst) {
+        return operation();
+    }
+
+    wait_for("flash semaphore", || lock(FLASH_SEMAPHORE))?;
+
+    let start = clock::now();
+
+    let mut operation = Some(operation);
+    let mut result = None;
+
+    while result.is_none() {
+        if let Err(e) = timeout(start, "CPU2 to allow flash access") {
+            unlock(FLASH_SEMAPHORE);
+            return Err(e);
+        }
+
+        // CPU2 may take the semaphore at any time, so it is checked
+        // with interrupts disabled, right before the operation starts
+        interrupt::free(|_| {
+            if !is_locked(BLOCKED_BY_CPU2_SEMAPHORE) {
+                result = operation.take().map(|operation| operation());
+            }
+        });
+    }
+
+    unlock(FLASH_SEMAPHORE);
+
+    result.unwrap()
+}
+
+/// Tell CPU2 that CPU1 starts or stops erasing flash pages.
+pub fn set_erase_activity(active: bool) -> Result<(), Error> {
+    if !READY.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let status = send_system_command(FLASH_ERASE_ACTIVITY, &[active as u8])?;
+
+    if status != 0 {
+        rprintln!("Failed to set flash erase activity: status {}", status);
+        return Err(Error::Storage);
+    }
+
+    Ok(())
+}
+
+/// Send a system command and wait for its response, returning its status.
+///
+/// After CPU2 is initialized, the HAL doesn't use the system channel anymore.
+fn send_system_command(opcode: u16, parameters: &[u8]) -> Result<u8, Error> {
+    let c1scr = (IPCC_BASE + IPCC_C1SCR_OFFSET) as *mut u32;
+    let c1toc2sr = (IPCC_BASE + IPCC_C1TOC2SR_OFFSET) as *const u32;
+
+    // CPU2 clears the channel flag once the response is in the buffer
+    let channel_free = || unsafe { ptr::read_volatile(c1toc2sr) & SYSTEM_CMD_CHANNEL == 0 };
+
+    // A command which timed out before may still be processed
+    wait_for("system channel", channel_free)?;
+
+    unsafe {
+        let buffer = ptr::read_volatile(TL_SYS_TABLE as *const *mut u8);
+
+        let header = [
+            SYSTEM_CMD_PACKET,
+            opcode as u8,
+            (opcode >> 8) as u8,
+            parameters.len() as u8,
+        ];
+
+        for (i, byte) in header.iter().chain(parameters).enumerate() {
+            ptr::write_volatile(buffer.add(CMD_TYPE_OFFSET + i), *byte);
+        }
+
+        asm::dsb();
+
+        ptr::write_volatile(c1scr, SYSTEM_CMD_CHANNEL << 16);
+
+        wait_for("system command response", channel_free)?;
+
+        let event_code = ptr::read_volatile(buffer.add(RESPONSE_EVENT_CODE_OFFSET));
+        let response_opcode = u16::from_le_bytes([
+            ptr::read_volatile(buffer.add(RESPONSE_OPCODE_OFFSET)),
+            ptr::read_volatile(buffer.add(RESPONSE_OPCODE_OFFSET + 1)),
+        ]);
+
+        if event_code != COMMAND_COMPLETE_EVENT || response_opcode != opcode {
+            rprintln!(
+                "Unexpected response to system command {:#06x}: event {:#04x}, opcode {:#06x}",
+                opcode,
+                event_code,
+                response_opcode
+            );
+            return Err(Error::Storage);
+        }
+
+        Ok(ptr::read_volatile(buffer.add(RESPONSE_STATUS_OFFSET)))
+    }
+}
+
+/// Wait until the condition is met, failing after `TIMEOUT`.
+fn wait_for(what: &str, mut condition: impl FnMut() -> bool) -> Result<(), Error> {
+    let start = clock::now();
+
+    while !condition() {
+        timeout(start, what)?;
+    }
+
+    Ok(())
+}
+
+/// Fail if more than `TIMEOUT` has passed since `start`.
+fn timeout(start: Duration, what: &str) -> Result<(), Error> {
+    if clock::now() - start > TIMEOUT {
+        rprintln!("Timeout waiting for {}", what);
+        return Err(Error::Storage);
+    }
+
+    Ok(())
+}
+
+/// Take a semaphore with a 1-step lock, returning whether it was free.
+fn lock(semaphore: usize) -> bool {
+    let rlr = (HSEM_BASE + HSEM_RLR_OFFSET + 4 * semaphore) as *const u32;
+
+    unsafe { ptr::read_volatile(rlr) == HSEM_LOCK | HSEM_COREID_CPU1 }
+}
+
+fn unlock(semaphore: usize) {
+    let r = (HSEM_BASE + 4 * semaphore) as *mut u32;
+
+    unsafe { ptr::write_volatile(r, HSEM_COREID_CPU1) }
+}
+
+fn is_locked(semaphore: usize) -> bool {
+    let r = (HSEM_BASE + 4 * semaphore) as *const u32;
+
+    unsafe { ptr::read_volatile(r) & HSEM_LOCK != 0 }
+}
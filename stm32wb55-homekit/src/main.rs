@@ -1,4 +1,4 @@
-//! BLE Eddystone URL beacon example.
+//! HomeKit accessory using BLE on the STM32WB55
 #![no_main]
 #![no_std]
 #![allow(non_snake_case)]
@@ -40,14 +40,32 @@ use bluetooth_hci::{
         uart::{Hci as UartHci, Packet},
         AdvertisingFilterPolicy, EncryptionKey, Hci, OwnAddressType,
     },
-    BdAddr, Status,
+    BdAddr, ConnectionHandle, Status,
 };
 
-use homekit_ble::{tlv::Tlv, HapPdu, HapResponse, HapStatus, OpCode};
+use homekit_ble::{
+    fragment::{RequestReassembler, MIN_RESPONSE_FRAGMENT_LEN},
+    identity::Identity,
+    pairing::{
+        handle_pairings, handle_write, srp::Verifier, PairSetup, PairVerify, SetupRequest,
+        VerifiedSession,
+    },
+    param::ParamType,
+    provisioning::{
+        ManufacturingData, MAX_HARDWARE_REVISION_LEN, MAX_MANUFACTURER_LEN, MAX_MODEL_LEN,
+        MAX_NAME_LEN, MAX_SERIAL_NUMBER_LEN,
+    },
+    session::{SecureSession, TAG_LEN},
+    setup_code::{setup_hash, SetupId},
+    store::{factory_reset, PairingStore},
+    tlv::{Tlv, TlvEncode, TlvVec, TlvWriter, Value},
+    HapRequest, HapResponse, HapStatus, InstanceId, OpCode,
+};
+use rng::HardwareRng;
 use stm32wb55::{
     event::{
         command::GattCharacteristicDescriptor, AttReadPermitRequest, AttributeHandle,
-        GattAttributeModified, Stm32Wb5xEvent,
+        Stm32Wb5xEvent,
     },
     gap::{
         AdvertisingDataType, AdvertisingType, Commands as GapCommands, DiscoverableParameters,
@@ -63,6 +81,7 @@ use stm32wb55::{
     hal::{Commands as HalCommands, ConfigData, PowerLevel},
     RadioCoprocessor,
 };
+use store::FlashStore;
 use uuid::{
     UUID_ACCESSORY_INFORMATION, UUID_ACCESSORY_INFORMATION_FIRMWARE_REVISION,
     UUID_ACCESSORY_INFORMATION_HARDWARE_REVISION, UUID_ACCESSORY_INFORMATION_IDENTIFY,
@@ -73,6 +92,12 @@ use uuid::{
     UUID_SERVICE_SIGNATURE, UUID_VERSION_CHARACTERISTIC,
 };
 
+mod button;
+mod clock;
+mod cpu2;
+mod provisioning;
+mod rng;
+mod store;
 mod uuid;
 
 pub type HciCommandsQueue = Queue<
@@ -85,8 +110,21 @@ pub type HciCommandsQueue = Queue<
 /// Advertisement interval in milliseconds.
 const ADV_INTERVAL_MS: u64 = 250;
 
-const BT_NAME: &[u8] = b"hokt";
-const BLE_GAP_DEVICE_NAME_LENGTH: u8 = BT_NAME.len() as u8;
+/// Size of the buffer used to reassemble fragmented HAP requests.
+const HAP_REQUEST_BUFFER_LEN: usize = 512;
+
+/// Maximum size of the body of a HAP response, which has to fit
+/// a pairing message of `pairing::MAX_MESSAGE_LEN` in a Value parameter.
+type HapResponseBodyLen = heapless::consts::U2048;
+
+/// Maximum size of a single fragment of a HAP response.
+const HAP_FRAGMENT_BUFFER_LEN: usize = 256;
+
+/// ATT MTU used until a different MTU is negotiated with the controller.
+const DEFAULT_ATT_MTU: usize = 23;
+
+/// Maximum size of a fragment written to or read from a pairing characteristic.
+const PAIRING_CHARACTERISTIC_LEN: usize = 100;
 
 #[derive(Debug, Default)]
 pub struct BleContext {
@@ -145,10 +183,57 @@ fn run() {
         .rtc_src(RtcClkSrc::Lse)
         .rf_wkp_sel(RfWakeupClock::Lse);
 
-    let mut rcc = rcc.apply_clock_config(clock_config, &mut dp.FLASH.constrain().acr);
+    let mut flash = dp.FLASH.constrain();
+    let mut rcc = rcc.apply_clock_config(clock_config, &mut flash.acr);
+
+    let cp = cortex_m::Peripherals::take().unwrap();
+    clock::init(cp.SYST);
 
     rprintln!("Boot");
 
+    let mut rng = HardwareRng::new(dp.RNG);
+
+    let (manufacturing_data, setup_code) = provisioning::read_manufacturing_data()
+        .expect("Accessory is not provisioned, the manufacturing data is missing");
+
+    // A store which can't be loaded is only replaced by a factory reset
+    let mut store = if button::factory_reset_requested(dp.GPIOA) {
+        rprintln!("Factory reset, removing all pairings");
+
+        let mut store = FlashStore::empty(flash).expect("Failed to open pairing store");
+        factory_reset(&mut store, &mut rng).expect("Failed to reset accessory");
+
+        store
+    } else {
+        FlashStore::new(flash)
+            .map_err(|e| {
+                rprintln!("Failed to load pairing store, hold the button at boot to reset it");
+                e
+            })
+            .expect("Failed to load pairing store")
+    };
+
+    let identity =
+        Identity::load_or_provision(&mut store, manufacturing_data.setup.device_id, &mut rng)
+            .expect("Failed to load accessory identity");
+
+    rprintln!(
+        "Device ID: {}",
+        core::str::from_utf8(&identity.device_id().pairing_id()).unwrap_or("")
+    );
+
+    if !store.is_paired() {
+        rprintln!(
+            "Ready for pairing, Setup ID: {}",
+            core::str::from_utf8(manufacturing_data.setup.setup_id.as_bytes()).unwrap_or("")
+        );
+
+        // The setup code of a provisioned accessory is only on its label
+        if let Some(setup_code) = &setup_code {
+            provisioning::print_setup_payload(setup_code, &manufacturing_data);
+        }
+    }
+
     // RTC is required for proper operation of BLE stack
     let _rtc = hal::rtc::Rtc::rtc(dp.RTC, &mut rcc);
 
@@ -192,17 +277,31 @@ fn run() {
 
     rprintln!("Received packet: {:?}", ready_event);
 
+    // From now on, flash writes have to be coordinated with CPU2
+    cpu2::set_ready();
+
     rprintln!("Resetting processor...");
 
     let reset_response = perform_command(|rc| rc.reset()).expect("Failed to reset processor");
 
     rprintln!("Received packet: {:?}", reset_response);
 
-    let homekit_accessory = init_gap_and_gatt().expect("Failed to initialize GAP and GATT");
+    let mut request_buffer = [0u8; HAP_REQUEST_BUFFER_LEN];
+
+    let name = manufacturing_data.name;
+
+    let mut homekit_accessory = init_gap_and_gatt(
+        &mut request_buffer,
+        store,
+        identity,
+        rng,
+        manufacturing_data,
+    )
+    .expect("Failed to initialize GAP and GATT");
 
     rprintln!("Succesfully initialized GAP and GATT");
 
-    init_homekit().expect("Failed to initialize homekit setup");
+    init_homekit(&homekit_accessory, name).expect("Failed to initialize homekit setup");
 
     rprintln!("Succesfully initialized Homekit");
 
@@ -217,39 +316,395 @@ fn run() {
     }
 }
 
-struct HapAccessory {
+struct HapAccessory<'a> {
     protocol_service: ProtocolService,
+
+    pairing_service: PairingService,
+
+    /// Persistent pairing state
+    store: FlashStore,
+
+    /// Device ID and long-term key pair of the accessory
+    identity: Identity,
+
+    rng: HardwareRng,
+
+    /// Setup ID and category, which are advertised with the Device ID
+    setup_id: SetupId,
+
+    category: u8,
+
+    /// Reassembly of fragmented HAP requests
+    reassembler: RequestReassembler<'a>,
+
+    /// Response to the last HAP request, which is read by the controller
+    pending_response: Option<PendingResponse>,
+
+    /// ATT MTU negotiated with the controller
+    att_mtu: usize,
+
+    /// Secure session with the connected controller, after a successful pair verify
+    session: Option<SecureSession>,
 }
 
-impl HapAccessory {
-    fn handle_event(&self, event: &Event<Stm32Wb5xEvent>) {
-        if let Event::Vendor(stm_event) = event {
-            match stm_event {
+impl HapAccessory<'_> {
+    /// Update the HomeKit manufacturer data in the advertisement,
+    /// which contains the pairing state of the accessory.
+    fn update_advertising_data(&self) -> Result<(), ()> {
+        let device_id = self.identity.device_id();
+
+        let mut advertising_data = [
+            0x16, // Length
+            0xff, // Manufacturer Data
+            0x4c, 0x00, // Apple ID
+            0x06, // Type
+            0x31, // STL, subtype 1 and 17 bytes of data
+            0x01, // SF
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Device ID
+            0x00, 0x00, // ACID G
+            0x00, 0x00, // GSN
+            0x00, // Configuration number
+            0x2,  // CV
+            0x00, 0x00, 0x00, 0x00, // Setup Hash
+        ];
+
+        // The status flag is cleared once the accessory is paired
+        if self.store.is_paired() {
+            advertising_data[6] = 0x00;
+        }
+
+        advertising_data[7..13].copy_from_slice(device_id.as_bytes());
+        advertising_data[13..15].copy_from_slice(&u16::from(self.category).to_le_bytes());
+        advertising_data[15..17].copy_from_slice(&self.store.global_state_number().to_le_bytes());
+        advertising_data[17] = self.store.config_number();
+        advertising_data[19..23].copy_from_slice(&setup_hash(&self.setup_id, device_id));
+
+        perform_command(|rc| {
+            rc.update_advertising_data(&advertising_data[..])
+                .map_err(|_| nb::Error::Other(()))
+        })
+        .map(|_| ())
+    }
+
+    /// Encrypt all further communication with the controller.
+    ///
+    /// Called once pair verify has completed.
+    fn start_session(&mut self, verified: VerifiedSession) {
+        rprintln!("Starting secure session");
+
+        self.reassembler.reset();
+        self.pending_response = None;
+        self.session = Some(SecureSession::new(verified));
+    }
+
+    /// Keep a response until the controller reads it.
+    ///
+    /// The fragment length is fixed here, so that all fragments of the
+    /// response use the same layout.
+    fn queue_response(&mut self, mut response: PendingResponse) {
+        // Encrypted fragments are followed by the authentication tag
+        let tag_len = if self.session.is_some() { TAG_LEN } else { 0 };
+
+        // The ATT header takes up three bytes of the MTU. If the MTU is too
+        // small for a fragment, the controller has to use a long read.
+        response.max_fragment_len = self
+            .att_mtu
+            .saturating_sub(3)
+            .min(response.characteristic.max_len)
+            .min(HAP_FRAGMENT_BUFFER_LEN)
+            .saturating_sub(tag_len)
+            .max(MIN_RESPONSE_FRAGMENT_LEN);
+
+        self.pending_response = Some(response);
+    }
+
+    /// Decrypt a fragment written by the controller, if a secure session is active.
+    ///
+    /// The session is closed and the controller disconnected
+    /// if the fragment can't be decrypted.
+    fn decrypt_fragment<'b>(
+        &mut self,
+        conn_handle: ConnectionHandle,
+        data: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Result<&'b [u8], ()> {
+        let buffer = buffer
+            .get_mut(..data.len())
+            .ok_or_else(|| rprintln!("HAP PDU fragment is too large"))?;
+        buffer.copy_from_slice(data);
+
+        let session = match &mut self.session {
+            Some(session) => session,
+            None => return Ok(buffer),
+        };
+
+        match session.decrypt(buffer) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                rprintln!("Failed to decrypt HAP PDU, closing session: {:?}", e);
+
+                self.session = None;
+                self.reassembler.reset();
+                self.pending_response = None;
+
+                // The disconnection is reported with a command status event
+                let _ = perform_command(|rc| {
+                    rc.disconnect(conn_handle, Status::AuthFailure)
+                        .map_err(|_| nb::Error::Other(()))
+                });
+
+                Err(())
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: &Event<Stm32Wb5xEvent>) {
+        match event {
+            Event::DisconnectionComplete(disconnection) => {
+                // The MTU, the session and any ongoing HAP procedure
+                // are only valid for a single connection
+                self.reassembler.reset();
+                self.pending_response = None;
+                self.att_mtu = DEFAULT_ATT_MTU;
+                self.session = None;
+                self.pairing_service
+                    .setup
+                    .connection_closed(disconnection.conn_handle.0);
+                self.pairing_service.verify.reset();
+            }
+            Event::Vendor(stm_event) => match stm_event {
+                Stm32Wb5xEvent::AttExchangeMtuResponse(mtu_response) => {
+                    rprintln!("Negotiated ATT MTU: {}", mtu_response.server_rx_mtu);
+
+                    self.att_mtu = mtu_response.server_rx_mtu;
+                }
                 Stm32Wb5xEvent::GattAttributeModified(modified) => {
                     rprintln!("Handling write to attribute {:?}", modified.attr_handle);
 
+                    let mut buffer = [0u8; HAP_FRAGMENT_BUFFER_LEN];
+
+                    let data = match self.decrypt_fragment(
+                        modified.conn_handle,
+                        modified.data(),
+                        &mut buffer,
+                    ) {
+                        Ok(data) => data,
+                        Err(()) => return,
+                    };
+
                     if self.protocol_service.contains_handle(modified.attr_handle) {
-                        self.protocol_service
-                            .handle_attribute_modified(modified)
-                            .expect("Failed to handle AttributeModified event");
+                        let response = self
+                            .protocol_service
+                            .handle_attribute_modified(data, &mut self.reassembler)
+                            .unwrap_or_else(|()| {
+                                // The cause has already been logged
+                                rprintln!("Ignoring HAP request");
+                                None
+                            });
+
+                        if let Some(response) = response {
+                            self.queue_response(response);
+                        }
+                    } else if self.pairing_service.contains_handle(modified.attr_handle) {
+                        let was_paired = self.store.is_paired();
+
+                        let mut response = self
+                            .pairing_service
+                            .handle_attribute_modified(
+                                data,
+                                modified.conn_handle,
+                                &mut self.reassembler,
+                                self.session.as_ref(),
+                                &mut self.store,
+                                &self.identity,
+                                &mut self.rng,
+                            )
+                            .unwrap_or_else(|()| {
+                                // The cause has already been logged
+                                rprintln!("Ignoring HAP request");
+                                None
+                            });
+
+                        if was_paired && !self.store.is_paired() {
+                            rprintln!("Last admin has been removed, accessory is unpaired");
+                        }
+
+                        // Controllers discover unpaired accessories by the status flag
+                        if was_paired != self.store.is_paired()
+                            && self.update_advertising_data().is_err()
+                        {
+                            rprintln!("Failed to update advertising data");
+                        }
+
+                        // The session of a removed controller ends once it has read the response
+                        if let (Some(response), Some(session)) = (&mut response, &self.session) {
+                            if self
+                                .store
+                                .find_pairing(&session.controller().identifier)
+                                .is_none()
+                            {
+                                response.end_session = true;
+                            }
+                        }
+
+                        if let Some(response) = response {
+                            self.queue_response(response);
+                        }
                     }
                 }
                 Stm32Wb5xEvent::AttReadPermitRequest(AttReadPermitRequest {
                     conn_handle,
-                    attribute_handle: _,
-                    offset: _,
+                    attribute_handle,
+                    offset,
                 }) => {
+                    // Serve the next fragment of the response, if the controller reads it.
+                    // Reads with an offset continue a long read of the current fragment.
+                    let served = match &mut self.pending_response {
+                        Some(response)
+                            if *offset == 0 && response.is_read_by(*attribute_handle) =>
+                        {
+                            Some(response.serve_next_fragment(self.session.as_mut()))
+                        }
+                        _ => None,
+                    };
+
+                    let response_complete = match served {
+                        Some(Ok(complete)) => complete,
+                        Some(Err(())) => {
+                            rprintln!("Failed to serve HAP response, dropping it");
+
+                            self.pending_response = None;
+                            false
+                        }
+                        None => false,
+                    };
+
+                    let (end_session, verified) = match self.pending_response.take() {
+                        Some(response) if !response_complete => {
+                            self.pending_response = Some(response);
+                            (false, None)
+                        }
+                        Some(response) => (response.end_session, response.verified),
+                        None => (false, None),
+                    };
+
                     // TODO: Check if allowed
                     perform_command(|rc| rc.allow_read(*conn_handle))
                         .expect("Failed to allow read");
+
+                    // The response to M3 is not encrypted yet, the session starts after it
+                    if let Some(verified) = verified {
+                        self.start_session(verified);
+                    }
+
+                    if end_session {
+                        rprintln!("Controller has been removed, closing session");
+
+                        self.session = None;
+
+                        // The disconnection is reported with a command status event
+                        let _ = perform_command(|rc| {
+                            rc.disconnect(*conn_handle, Status::RemoteTerminationByUser)
+                                .map_err(|_| nb::Error::Other(()))
+                        });
+                    }
                 }
                 // Ignore other events
                 _ => {}
-            }
+            },
+            // Ignore other events
+            _ => {}
         }
     }
 }
 
+/// A HAP response, which is read by the controller
+/// using one or more GATT reads.
+///
+/// The response to a HAP request written to a characteristic is served
+/// when the controller reads that characteristic.
+struct PendingResponse {
+    /// Characteristic used to serve the response
+    characteristic: Characteristic,
+
+    tid: u8,
+
+    status: HapStatus,
+
+    body: heapless::Vec<u8, HapResponseBodyLen>,
+
+    /// Maximum length of a fragment, before encryption
+    max_fragment_len: usize,
+
+    /// Index of the next fragment which is served
+    next_fragment: usize,
+
+    /// Close the session once the response has been read
+    end_session: bool,
+
+    /// Start a session once the response has been read
+    verified: Option<VerifiedSession>,
+}
+
+impl PendingResponse {
+    fn new(
+        characteristic: &Characteristic,
+        tid: u8,
+        status: HapStatus,
+        body: &[u8],
+    ) -> Result<Self, ()> {
+        let mut response_body = heapless::Vec::new();
+        response_body.extend_from_slice(body)?;
+
+        Ok(PendingResponse {
+            characteristic: characteristic.clone(),
+            tid,
+            status,
+            body: response_body,
+            max_fragment_len: MIN_RESPONSE_FRAGMENT_LEN,
+            next_fragment: 0,
+            end_session: false,
+            verified: None,
+        })
+    }
+
+    /// Check if a read of the given handle is a read of this response.
+    fn is_read_by(&self, handle: AttributeHandle) -> bool {
+        // The value handle directly follows the characteristic declaration
+        handle.0 == self.characteristic.characteristic.0 + 1
+    }
+
+    /// Set the characteristic value to the next fragment of the response.
+    ///
+    /// The fragment is encrypted if a secure session is active.
+    /// Returns `true` when the last fragment has been served.
+    fn serve_next_fragment(&mut self, session: Option<&mut SecureSession>) -> Result<bool, ()> {
+        let mut buffer = [0u8; HAP_FRAGMENT_BUFFER_LEN];
+
+        let response = HapResponse::new(self.tid, self.status, &self.body);
+
+        let mut fragments = response
+            .fragments(self.max_fragment_len)
+            .map_err(|e| rprintln!("Failed to fragment HAP response: {:?}", e))?;
+
+        let fragment = fragments.nth(self.next_fragment).ok_or(())?;
+
+        let mut len = fragment.write_into(&mut buffer).map_err(|_| ())?;
+
+        if let Some(session) = session {
+            len = session
+                .encrypt(&mut buffer, len)
+                .map_err(|e| rprintln!("Failed to encrypt HAP response: {:?}", e))?;
+        }
+
+        self.characteristic.set_value(&buffer[..len])?;
+
+        self.next_fragment += 1;
+
+        Ok(fragments.next().is_none())
+    }
+}
+
 fn perform_command(
     command: impl Fn(&mut RadioCopro) -> nb::Result<(), ()>,
 ) -> Result<ReturnParameters<Stm32Wb5xEvent>, ()> {
@@ -450,6 +905,7 @@ impl Service {
     }
 }
 
+#[derive(Clone)]
 struct Characteristic {
     service: ServiceHandle,
     characteristic: CharacteristicHandle,
@@ -528,7 +984,7 @@ struct HapService {
     /// UUID of the Homekit Service
     uuid: [u8; 16],
 
-    instance_id: u16,
+    instance_id: InstanceId,
 
     instance_id_characteristic: Characteristic,
 }
@@ -554,7 +1010,7 @@ impl HapService {
         Ok(HapService {
             service,
             uuid,
-            instance_id,
+            instance_id: instance_id.into(),
             instance_id_characteristic,
         })
     }
@@ -571,7 +1027,7 @@ struct HapCharacteristic {
 
     uuid: [u8; 16],
 
-    instance_id: u16,
+    instance_id: InstanceId,
 
     /// Characteristic properties,
     /// see section 7.4.4.6.1
@@ -630,6 +1086,21 @@ enum GattFormat {
     Data = 0x1B,
 }
 
+/// Body of the response to a characteristic signature read
+#[derive(TlvEncode)]
+struct CharacteristicSignature {
+    #[tlv(type = ParamType::CharacteristicType)]
+    characteristic_type: [u8; 16],
+    #[tlv(type = ParamType::ServiceInstanceId)]
+    service_id: InstanceId,
+    #[tlv(type = ParamType::ServiceType)]
+    service_type: [u8; 16],
+    #[tlv(type = ParamType::CharacteristicProperties)]
+    properties: u16,
+    #[tlv(type = ParamType::GattPresentationFormat)]
+    presentation_format: [u8; 7],
+}
+
 impl HapCharacteristic {
     fn build(
         service: &HapService,
@@ -673,7 +1144,7 @@ impl HapCharacteristic {
         Ok(HapCharacteristic {
             characteristic,
             uuid,
-            instance_id,
+            instance_id: instance_id.into(),
             properties: hap_properties,
             characteristic_id: descriptor_handle,
             format,
@@ -681,6 +1152,35 @@ impl HapCharacteristic {
         })
     }
 
+    /// Build the body of the response to a characteristic signature read.
+    fn signature(
+        &self,
+        service: &HapService,
+    ) -> Result<TlvVec<HapResponseBodyLen>, homekit_ble::Error> {
+        let mut gatt_format = [0u8; 7];
+
+        // Formatj
+        gatt_format[0] = self.format as u8;
+
+        gatt_format[2..4].copy_from_slice(&(self.unit as u16).to_le_bytes());
+
+        // namespace
+        gatt_format[4] = 1;
+
+        let signature = CharacteristicSignature {
+            characteristic_type: self.uuid,
+            service_id: service.instance_id,
+            service_type: service.uuid,
+            properties: self.properties.bits(),
+            presentation_format: gatt_format,
+        };
+
+        let mut data = TlvVec::new();
+        signature.encode(&mut data)?;
+
+        Ok(data)
+    }
+
     fn set_value(&self, value: &[u8]) -> Result<(), ()> {
         rprintln!(
             "{:?}: value={:x?}",
@@ -691,7 +1191,15 @@ impl HapCharacteristic {
     }
 }
 
-fn init_gap_and_gatt() -> Result<HapAccessory, ()> {
+fn init_gap_and_gatt(
+    request_buffer: &mut [u8],
+    store: FlashStore,
+    identity: Identity,
+    rng: HardwareRng,
+    manufacturing_data: ManufacturingData<'static>,
+) -> Result<HapAccessory<'_>, ()> {
+    let name = manufacturing_data.name.as_bytes();
+
     let response = perform_command(|rc: &mut RadioCopro| {
         rc.write_config_data(&ConfigData::public_address(get_bd_addr()).build())
     })?;
@@ -713,7 +1221,7 @@ fn init_gap_and_gatt() -> Result<HapAccessory, ()> {
     let mut ble_context = BleContext::default();
 
     let return_params =
-        perform_command(|rc| rc.init_gap(Role::PERIPHERAL, false, BLE_GAP_DEVICE_NAME_LENGTH))?;
+        perform_command(|rc| rc.init_gap(Role::PERIPHERAL, false, name.len() as u8))?;
 
     if let ReturnParameters::Vendor(stm32wb55::event::command::ReturnParameters::GapInit(
         stm32wb55::event::command::GapInit {
@@ -737,7 +1245,7 @@ fn init_gap_and_gatt() -> Result<HapAccessory, ()> {
             service_handle: ble_context.service_handle.unwrap(),
             characteristic_handle: ble_context.dev_name_handle.unwrap(),
             offset: 0,
-            value: BT_NAME,
+            value: name,
         })
         .map_err(|_| nb::Error::Other(()))
     })?;
@@ -790,9 +1298,10 @@ fn init_gap_and_gatt() -> Result<HapAccessory, ()> {
         CharacteristicProperty::READ | CharacteristicProperty::WRITE,
         HapProperties::SECURE_READ,
         GattFormat::String,
-        64,
+        MAX_MANUFACTURER_LEN,
     )?;
-    information_manufacturer_characteristic.set_value(b"Dominik Corp.\0")?;
+    information_manufacturer_characteristic
+        .set_value(manufacturing_data.manufacturer.as_bytes())?;
 
     let information_model_characteristic = HapCharacteristic::build(
         &accessory_service,
@@ -801,9 +1310,9 @@ fn init_gap_and_gatt() -> Result<HapAccessory, ()> {
         CharacteristicProperty::READ | CharacteristicProperty::WRITE,
         HapProperties::SECURE_READ,
         GattFormat::String,
-        10,
+        MAX_MODEL_LEN,
     )?;
-    information_model_characteristic.set_value(b"M001\0")?;
+    information_model_characteristic.set_value(manufacturing_data.model.as_bytes())?;
 
     let information_name_characteristic = HapCharacteristic::build(
         &accessory_service,
@@ -812,9 +1321,9 @@ fn init_gap_and_gatt() -> Result<HapAccessory, ()> {
         CharacteristicProperty::READ | CharacteristicProperty::WRITE,
         HapProperties::SECURE_READ,
         GattFormat::String,
-        10,
+        MAX_NAME_LEN,
     )?;
-    information_name_characteristic.set_value(BT_NAME)?;
+    information_name_characteristic.set_value(name)?;
 
     let information_serial_number_characteristic = HapCharacteristic::build(
         &accessory_service,
@@ -823,9 +1332,10 @@ fn init_gap_and_gatt() -> Result<HapAccessory, ()> {
         CharacteristicProperty::READ | CharacteristicProperty::WRITE,
         HapProperties::SECURE_READ,
         GattFormat::String,
-        15,
+        MAX_SERIAL_NUMBER_LEN,
     )?;
-    information_serial_number_characteristic.set_value(b"S12345\0")?;
+    information_serial_number_characteristic
+        .set_value(manufacturing_data.serial_number.as_bytes())?;
 
     let information_firmware_revision_characteristic = HapCharacteristic::build(
         &accessory_service,
@@ -836,7 +1346,7 @@ fn init_gap_and_gatt() -> Result<HapAccessory, ()> {
         GattFormat::String,
         10,
     )?;
-    information_firmware_revision_characteristic.set_value(b"1.0.0\0")?;
+    information_firmware_revision_characteristic.set_value(env!("CARGO_PKG_VERSION").as_bytes())?;
 
     let information_hardware_revision_characteristic = HapCharacteristic::build(
         &accessory_service,
@@ -845,58 +1355,28 @@ fn init_gap_and_gatt() -> Result<HapAccessory, ()> {
         CharacteristicProperty::READ | CharacteristicProperty::WRITE,
         HapProperties::SECURE_READ,
         GattFormat::String,
-        10,
+        MAX_HARDWARE_REVISION_LEN,
     )?;
-    information_hardware_revision_characteristic.set_value(b"1.0.0\0")?;
+    information_hardware_revision_characteristic
+        .set_value(manufacturing_data.hardware_revision.as_bytes())?;
 
     let protocol_service = ProtocolService::create_ble()?;
 
-    // Add Pairing service
-    rprintln!("Pairing service");
-    let pairing_service = HapService::new(UUID_PAIRING_SERVICE, 20, 0x20)?;
-
-    // TODO: not hardcoded value here
-    let maximum_handle = pairing_service.service.handle.0 + 20;
-
-    let pair_setup = HapCharacteristic::build(
-        &pairing_service,
-        0x22,
-        UUID_PAIRING_SETUP,
-        CharacteristicProperty::READ | CharacteristicProperty::WRITE,
-        HapProperties::SECURE_READ,
-        GattFormat::Data,
-        1,
-    )?;
-
-    let pair_verify = HapCharacteristic::build(
-        &pairing_service,
-        0x23,
-        UUID_PAIRING_VERIFY,
-        CharacteristicProperty::READ | CharacteristicProperty::WRITE,
-        HapProperties::READ | HapProperties::WRITE,
-        GattFormat::Data,
-        1,
-    )?;
-    let pairing_features = HapCharacteristic::build(
-        &pairing_service,
-        0x24,
-        UUID_PAIRING_FEATURES,
-        CharacteristicProperty::READ | CharacteristicProperty::WRITE,
-        HapProperties::READ | HapProperties::WRITE,
-        GattFormat::Uint8,
-        1,
-    )?;
-    let pairing_pairings = HapCharacteristic::build(
-        &pairing_service,
-        0x25,
-        UUID_PAIRING_PAIRINGS,
-        CharacteristicProperty::READ | CharacteristicProperty::WRITE,
-        HapProperties::READ | HapProperties::WRITE,
-        GattFormat::Data,
-        1,
-    )?;
-
-    Ok(HapAccessory { protocol_service })
+    let pairing_service = PairingService::create_ble(manufacturing_data.setup.verifier)?;
+
+    Ok(HapAccessory {
+        protocol_service,
+        pairing_service,
+        store,
+        identity,
+        rng,
+        setup_id: manufacturing_data.setup.setup_id,
+        category: manufacturing_data.category,
+        reassembler: RequestReassembler::new(request_buffer),
+        pending_response: None,
+        att_mtu: DEFAULT_ATT_MTU,
+        session: None,
+    })
 }
 
 struct ProtocolService {
@@ -955,118 +1435,342 @@ impl ProtocolService {
     }
 
     /// Handle a BLE event for this service
-    fn handle_attribute_modified(&self, modified: &GattAttributeModified) -> Result<(), ()> {
-        // Try to parse a HAP PDU
-        if let Ok(HapPdu::Request(pdu)) = HapPdu::parse(modified.data()) {
-            rprintln!("PDU: {:?}", pdu);
+    fn handle_attribute_modified(
+        &self,
+        data: &[u8],
+        reassembler: &mut RequestReassembler,
+    ) -> Result<Option<PendingResponse>, ()> {
+        // Try to parse a HAP PDU, which might be split over multiple writes
+        let pdu = match reassembler.push(data) {
+            Ok(Some(pdu)) => pdu,
+            Ok(None) => {
+                rprintln!("Waiting for further fragments of HAP PDU.");
+                return Ok(None);
+            }
+            Err(e) => {
+                rprintln!("Failed to parse HAP PDU: {:?}", e);
+                return Ok(None);
+            }
+        };
+
+        rprintln!("PDU: {:?}", pdu);
 
-            match pdu.op_code {
-                OpCode::ServiceSignatureRead => {
-                    // Handle read of Protocol Service Signature
-                    if pdu.char_id == self.service.instance_id {
-                        // We don't link to any services, so the LinkedSvc TLV is not used
+        match pdu.op_code {
+            OpCode::ServiceSignatureRead => {
+                // Handle read of Protocol Service Signature
+                if pdu.char_id == self.service.instance_id {
+                    let mut response_data = TlvVec::<HapResponseBodyLen>::new();
 
+                    response_data
                         // The properties of this service are that it support configuration
                         // -> 0x0004
+                        .push(Tlv::new(ParamType::ServiceProperties, 0x0004u16))
+                        // We don't link to any services, so the list of linked services is empty
+                        .and_then(|data| {
+                            data.push(Tlv::new(ParamType::LinkedServices, Value::Empty))
+                        })
+                        .map_err(|e| rprintln!("Error creating HAP response PDU: {:?}", e))?;
+
+                    PendingResponse::new(
+                        &self.signature.characteristic,
+                        pdu.tid,
+                        HapStatus::Success,
+                        &response_data,
+                    )
+                    .map(Some)
+                } else {
+                    // Not sure
+                    Ok(None)
+                }
+            }
+            OpCode::CharacteristicSignatureRead => {
+                // Signature for Protocol Service Signature Characteristic
+                let characteristic = if pdu.char_id == self.signature.instance_id {
+                    &self.signature
+                } else if pdu.char_id == self.version.instance_id {
+                    &self.version
+                } else {
+                    // Unsupported characteristic ID
+                    rprintln!(
+                        "Characteristic with ID {} is not part of this service.",
+                        pdu.char_id
+                    );
+                    return Err(());
+                };
+
+                let response_data = characteristic
+                    .signature(&self.service)
+                    .map_err(|e| rprintln!("Error creating HAP response PDU: {:?}", e))?;
+
+                PendingResponse::new(
+                    &characteristic.characteristic,
+                    pdu.tid,
+                    HapStatus::Success,
+                    &response_data,
+                )
+                .map(Some)
+            }
+            // Ignore other op codes
+            _ => Ok(None),
+        }
+    }
+}
 
-                        let response_data = [0x0f, 0x02, 0x04, 0x00, 0x10, 0x00];
-                        let response =
-                            HapResponse::new(pdu.tid, HapStatus::Success, &response_data);
+struct PairingService {
+    service: HapService,
 
-                        // we now have to write the property with the response
+    pair_setup: HapCharacteristic,
 
-                        let mut resp_buff = [0u8; 50];
+    pair_verify: HapCharacteristic,
 
-                        response
-                            .write_into(&mut resp_buff)
-                            .expect("Failed to HAP Response");
+    features: HapCharacteristic,
 
-                        // This meas we have to send a xxx event
-                        self.signature
-                            .set_value(&resp_buff[..response.size()])
-                            .expect("Failed to set value for ServiceSignatureRead");
-                    } else {
-                        // Not sure
-                    }
-                }
-                OpCode::CharacteristicSignatureRead => {
-                    // Signature for Protocol Service Signature Characteristic
-                    let characteristic = if pdu.char_id == self.signature.instance_id {
-                        &self.signature
-                    } else if pdu.char_id == self.version.instance_id {
-                        &self.version
-                    } else {
-                        // Unsupported characteristic ID
-                        rprintln!(
-                            "Characteristic with ID {} is not part of this service.",
-                            pdu.char_id
-                        );
-                        return Err(());
-                    };
+    pairings: HapCharacteristic,
+
+    /// State of the pair setup procedure
+    setup: PairSetup,
 
-                    let mut response_data = [0u8; 53];
-                    let characteristic_uuid = Tlv::new(0x04, &characteristic.uuid[..]);
-                    let service_uuid = Tlv::new(0x06, &self.service.uuid[..]);
+    /// State of the pair verify procedure
+    verify: PairVerify,
+}
+
+impl PairingService {
+    /// Create the necessary GATT services
+    /// and characteristics for this service.
+    fn create_ble(verifier: Verifier) -> Result<Self, ()> {
+        // Add Pairing service
+        rprintln!("Pairing service");
+        let pairing_service = HapService::new(UUID_PAIRING_SERVICE, 20, 0x20)?;
+
+        let pair_setup = HapCharacteristic::build(
+            &pairing_service,
+            0x22,
+            UUID_PAIRING_SETUP,
+            CharacteristicProperty::READ | CharacteristicProperty::WRITE,
+            HapProperties::SECURE_READ,
+            GattFormat::Data,
+            PAIRING_CHARACTERISTIC_LEN,
+        )?;
 
-                    let mut offset = 0;
+        let pair_verify = HapCharacteristic::build(
+            &pairing_service,
+            0x23,
+            UUID_PAIRING_VERIFY,
+            CharacteristicProperty::READ | CharacteristicProperty::WRITE,
+            HapProperties::READ | HapProperties::WRITE,
+            GattFormat::Data,
+            PAIRING_CHARACTERISTIC_LEN,
+        )?;
+        let pairing_features = HapCharacteristic::build(
+            &pairing_service,
+            0x24,
+            UUID_PAIRING_FEATURES,
+            CharacteristicProperty::READ | CharacteristicProperty::WRITE,
+            HapProperties::READ | HapProperties::WRITE,
+            GattFormat::Uint8,
+            1,
+        )?;
+        let pairing_pairings = HapCharacteristic::build(
+            &pairing_service,
+            0x25,
+            UUID_PAIRING_PAIRINGS,
+            CharacteristicProperty::READ | CharacteristicProperty::WRITE,
+            HapProperties::SECURE_READ | HapProperties::SECURE_WRITE,
+            GattFormat::Data,
+            PAIRING_CHARACTERISTIC_LEN,
+        )?;
 
-                    // characteristic type
-                    offset += characteristic_uuid.write_into(&mut response_data);
+        Ok(Self {
+            service: pairing_service,
+            pair_setup,
+            pair_verify,
+            features: pairing_features,
+            pairings: pairing_pairings,
+            setup: PairSetup::new(verifier),
+            verify: PairVerify::new(),
+        })
+    }
 
-                    // service id
-                    offset += Tlv::new(0x07, self.service.instance_id)
-                        .write_into(&mut response_data[offset..]);
+    /// Check if a BLE attribute handle is part of this service
+    fn contains_handle(&self, handle: AttributeHandle) -> bool {
+        self.service.contains_handle(handle)
+    }
 
-                    // service type
-                    offset += service_uuid.write_into(&mut response_data[offset..]);
+    /// Find the characteristic with the given instance ID
+    fn characteristic(&self, instance_id: InstanceId) -> Option<&HapCharacteristic> {
+        [
+            &self.pair_setup,
+            &self.pair_verify,
+            &self.features,
+            &self.pairings,
+        ]
+        .iter()
+        .find(|characteristic| characteristic.instance_id == instance_id)
+        .copied()
+    }
 
-                    // properties
-                    offset += Tlv::new(0x0a, characteristic.properties.bits())
-                        .write_into(&mut response_data[offset..]);
+    /// Handle a BLE event for this service
+    #[allow(clippy::too_many_arguments)]
+    fn handle_attribute_modified(
+        &mut self,
+        data: &[u8],
+        conn_handle: ConnectionHandle,
+        reassembler: &mut RequestReassembler,
+        session: Option<&SecureSession>,
+        store: &mut impl PairingStore,
+        identity: &Identity,
+        rng: &mut HardwareRng,
+    ) -> Result<Option<PendingResponse>, ()> {
+        // Try to parse a HAP PDU, which might be split over multiple writes
+        let pdu = match reassembler.push(data) {
+            Ok(Some(pdu)) => pdu,
+            Ok(None) => {
+                rprintln!("Waiting for further fragments of HAP PDU.");
+                return Ok(None);
+            }
+            Err(e) => {
+                rprintln!("Failed to parse HAP PDU: {:?}", e);
+                return Ok(None);
+            }
+        };
 
-                    let mut gatt_format = [0u8; 7];
+        rprintln!("PDU: {:?}", pdu);
 
-                    // Formatj
-                    gatt_format[0] = characteristic.format as u8;
+        let characteristic = match self.characteristic(pdu.char_id) {
+            Some(characteristic) => characteristic,
+            None => {
+                rprintln!(
+                    "Characteristic with ID {} is not part of this service.",
+                    pdu.char_id
+                );
+                return Err(());
+            }
+        };
 
-                    gatt_format[2..4].copy_from_slice(&(characteristic.unit as u16).to_le_bytes());
+        match pdu.op_code {
+            OpCode::CharacteristicSignatureRead => {
+                let response_data = characteristic
+                    .signature(&self.service)
+                    .map_err(|e| rprintln!("Error creating HAP response PDU: {:?}", e))?;
+
+                PendingResponse::new(
+                    &characteristic.characteristic,
+                    pdu.tid,
+                    HapStatus::Success,
+                    &response_data,
+                )
+                .map(Some)
+            }
+            OpCode::CharacteristicWrite if pdu.char_id == self.pair_setup.instance_id => {
+                // Copied, as the pair setup state is modified below
+                let gatt_characteristic = characteristic.characteristic.clone();
+
+                let (status, response_data) =
+                    handle_pairing_write(&pdu, "pair setup", |message, response| {
+                        let request = SetupRequest {
+                            message,
+                            connection: conn_handle.0,
+                            time: clock::now(),
+                        };
+
+                        let pairing = self.setup.handle(
+                            request,
+                            &identity.accessory(),
+                            store,
+                            rng,
+                            response,
+                        )?;
+
+                        if let Some(pairing) = pairing {
+                            store.save_pairing(pairing)?;
+                            rprintln!("Paired with a controller");
+                        }
+
+                        Ok(())
+                    });
+
+                PendingResponse::new(&gatt_characteristic, pdu.tid, status, &response_data)
+                    .map(Some)
+            }
+            OpCode::CharacteristicWrite if pdu.char_id == self.pair_verify.instance_id => {
+                // Copied, as the pair verify state is modified below
+                let gatt_characteristic = characteristic.characteristic.clone();
 
-                    // namespace
-                    gatt_format[4] = 1;
+                let mut verified = None;
 
-                    // GATT Format
-                    offset +=
-                        Tlv::new(0x0C, &gatt_format[..]).write_into(&mut response_data[offset..]);
+                let (status, response_data) =
+                    handle_pairing_write(&pdu, "pair verify", |request, response| {
+                        verified = self.verify.handle(
+                            request,
+                            &identity.accessory(),
+                            |identifier| store.find_pairing(identifier).cloned(),
+                            rng,
+                            response,
+                        )?;
 
-                    assert_eq!(
-                        offset,
-                        response_data.len(),
-                        "Error creating HAP response PDU"
-                    );
+                        Ok(())
+                    });
 
-                    let response = HapResponse::new(pdu.tid, HapStatus::Success, &response_data);
+                let mut response =
+                    PendingResponse::new(&gatt_characteristic, pdu.tid, status, &response_data)?;
 
-                    // we now have to write the property with the response
+                response.verified = verified;
 
-                    let mut resp_buff = [0u8; 70];
+                Ok(Some(response))
+            }
+            OpCode::CharacteristicWrite if pdu.char_id == self.pairings.instance_id => {
+                // Pairings can only be managed by a verified controller
+                let session = match session {
+                    Some(session) => session,
+                    None => {
+                        return PendingResponse::new(
+                            &characteristic.characteristic,
+                            pdu.tid,
+                            HapStatus::InsufficientAuthentication,
+                            &[],
+                        )
+                        .map(Some)
+                    }
+                };
 
-                    response
-                        .write_into(&mut resp_buff)
-                        .expect("Failed to build HAP Response");
+                let (status, response_data) =
+                    handle_pairing_write(&pdu, "pairings", |request, response| {
+                        handle_pairings(store, request, &session.controller().identifier, response)
+                    });
 
-                    // This meas we have to send a xxx event
-                    self.signature
-                        .set_value(&resp_buff[..response.size()])
-                        .expect("Failed to set value for CharacteristicSignatureRead");
-                }
-                // Ignore other op codes
-                _ => {}
+                PendingResponse::new(
+                    &characteristic.characteristic,
+                    pdu.tid,
+                    status,
+                    &response_data,
+                )
+                .map(Some)
             }
-        } else {
-            rprintln!("Failed to parse HAP PDU.");
+            // Ignore other op codes
+            _ => Ok(None),
         }
+    }
+}
 
-        Ok(())
+/// Handle a HAP request written to one of the pairing characteristics,
+/// returning the status and the body of the response.
+///
+/// If the request can't be handled, the response body is empty.
+fn handle_pairing_write(
+    pdu: &HapRequest,
+    procedure: &str,
+    handle: impl FnOnce(&[u8], &mut TlvWriter) -> Result<(), homekit_ble::Error>,
+) -> (HapStatus, TlvVec<HapResponseBodyLen>) {
+    let mut response_data = TlvVec::<HapResponseBodyLen>::new();
+
+    match handle_write(pdu.body().unwrap_or(&[]), &mut response_data, handle) {
+        Ok(()) => (HapStatus::Success, response_data),
+        Err(e) => {
+            rprintln!("Failed to handle {} request: {:?}", procedure, e);
+
+            response_data.clear();
+            (HapStatus::InvalidRequest, response_data)
+        }
     }
 }
 
@@ -1099,14 +1803,14 @@ fn get_erk() -> EncryptionKey {
     EncryptionKey(BLE_CFG_ERK)
 }
 
-fn init_homekit() -> Result<(), ()> {
+fn init_homekit(accessory: &HapAccessory, name: &str) -> Result<(), ()> {
     // Disable scan response
     perform_command(|rc: &mut RadioCopro| {
         rc.le_set_scan_response_data(&[])
             .map_err(|_| nb::Error::Other(()))
     })?;
 
-    // Put the device in a non-connectable mode
+    // Advertise as connectable, so that controllers can pair with the accessory
     perform_command(|rc| {
         let params = DiscoverableParameters {
             advertising_type: AdvertisingType::ConnectableUndirected,
@@ -1116,8 +1820,8 @@ fn init_homekit() -> Result<(), ()> {
             )),
             address_type: OwnAddressType::Public,
             filter_policy: AdvertisingFilterPolicy::AllowConnectionAndScan,
-            // Local name should be empty for the device to be recognized as an Eddystone beacon
-            local_name: Some(LocalName::Complete(BT_NAME)),
+            // The name is shown to the user when adding the accessory
+            local_name: Some(LocalName::Complete(name.as_bytes())),
             advertising_data: &[],
             conn_interval: (None, None),
         };
@@ -1126,25 +1830,7 @@ fn init_homekit() -> Result<(), ()> {
             .map_err(|_| nb::Error::Other(()))
     })?;
 
-    perform_command(|rc| {
-        let advertising_data = [
-            0x12, // Length
-            0xff, // Manufacturer Data
-            0x4c, 0x00, // Apple ID
-            0x06, // Type
-            0x2D, // STL
-            0x01, // SF
-            0x44, 0x55, 0x66, 0x44, 0x55, 0x66, // Device ID
-            0x00, 0x0A, // ACID G
-            0x00, 0x01, // GSN
-            0x2,  // Configuration number
-            0x2,  // CV
-                  //0x00, 0x00, 0x00, 0x00, // Secure Hash,
-        ];
-
-        rc.update_advertising_data(&advertising_data[..])
-            .map_err(|_| nb::Error::Other(()))
-    })?;
+    accessory.update_advertising_data()?;
 
     perform_command(|rc| {
         let mut service_uuid_list = [0u8; 16 * 1 + 2];
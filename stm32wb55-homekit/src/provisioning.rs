@@ -0,0 +1,123 @@
+//! Manufacturing data in the internal flash
+//!
+//! The record is written by `homekit-provision` during manufacturing,
+//! to the page reserved in `memory.x`. The record only contains the verifier
+//! of the setup code, so the setup code and QR code of a provisioned accessory
+//! are only available on its label, which is printed by `homekit-provision`.
+
+use core::slice;
+
+use homekit_ble::{
+    provisioning::{ManufacturingData, MAX_RECORD_LEN},
+    qr::{EccLevel, QrCode},
+    setup_code::{setup_payload, SetupCode, SetupFlags},
+    Error,
+};
+use rtt_target::rprintln;
+
+#[cfg(debug_assertions)]
+use homekit_ble::{
+    identity::DeviceId, pairing::srp::SALT_LEN, provisioning::SetupMaterial, setup_code::SetupId,
+};
+
+/// Address of the record, which has to match the `PROVISIONING` region in `memory.x`
+const MANUFACTURING_DATA_ADDRESS: usize = 0x0803_4000;
+
+/// Setup code of development builds, which is printed at boot until the accessory is paired
+#[cfg(debug_assertions)]
+const DEVELOPMENT_SETUP_CODE: &[u8] = b"518-08-582";
+
+#[cfg(debug_assertions)]
+const DEVELOPMENT_SETUP_ID: &[u8] = b"1QJ8";
+
+/// Like the setup code and the Setup ID, the Device ID and the salt are fixed,
+/// so that the compiled-in record is the same on every boot, and doesn't need
+/// the RNG. The Device ID is only used when the identity is created on first
+/// boot, and the salt only within a single Pair Setup.
+#[cfg(debug_assertions)]
+const DEVELOPMENT_DEVICE_ID: DeviceId = DeviceId([0xc8, 0xd8, 0x3d, 0x0a, 0xb0, 0x4b]);
+
+#[cfg(debug_assertions)]
+const DEVELOPMENT_SALT: [u8; SALT_LEN] = [
+    0x5a, 0x1e, 0x0c, 0x93, 0x27, 0xd4, 0x61, 0xb8, 0x0f, 0x72, 0xe6, 0x3d, 0xa9, 0x14, 0x8b, 0x50,
+];
+
+/// Read the manufacturing data, which fails if the accessory hasn't been provisioned.
+///
+/// Development builds fall back to compiled-in data instead, so that they
+/// can be used without provisioning the accessory. The setup code is
+/// only returned for the compiled-in data.
+pub fn read_manufacturing_data() -> Result<(ManufacturingData<'static>, Option<SetupCode>), Error> {
+    // The page is reserved in `memory.x`, and never written by the firmware
+    let data =
+        unsafe { slice::from_raw_parts(MANUFACTURING_DATA_ADDRESS as *const u8, MAX_RECORD_LEN) };
+
+    match ManufacturingData::decode(data) {
+        Ok(record) => Ok((record, None)),
+        Err(e) => {
+            rprintln!("No valid manufacturing data: {:?}", e);
+
+            fallback()
+                .map(|(record, setup_code)| (record, Some(setup_code)))
+                .ok_or(e)
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+fn fallback() -> Option<(ManufacturingData<'static>, SetupCode)> {
+    None
+}
+
+#[cfg(debug_assertions)]
+fn fallback() -> Option<(ManufacturingData<'static>, SetupCode)> {
+    rprintln!("Using development manufacturing data");
+
+    let setup_code = SetupCode::parse(DEVELOPMENT_SETUP_CODE).expect("Invalid setup code");
+    let setup_id = SetupId::new(DEVELOPMENT_SETUP_ID).expect("Invalid Setup ID");
+
+    let record = ManufacturingData {
+        manufacturer: "Dominik Corp.",
+        model: "M001",
+        serial_number: "S12345",
+        hardware_revision: "1.0.0",
+        name: "hokt",
+        category: 10,
+        // Calculating the verifier takes a while, which only happens in development builds
+        setup: SetupMaterial::with_salt(
+            &setup_code,
+            DEVELOPMENT_SALT,
+            setup_id,
+            DEVELOPMENT_DEVICE_ID,
+        ),
+    };
+
+    Some((record, setup_code))
+}
+
+/// Print the setup code, and the setup payload as QR code.
+pub fn print_setup_payload(setup_code: &SetupCode, record: &ManufacturingData) {
+    let payload = setup_payload(
+        setup_code,
+        &record.setup.setup_id,
+        record.category,
+        SetupFlags {
+            ble: true,
+            ..SetupFlags::default()
+        },
+    );
+
+    rprintln!(
+        "Setup code: {}",
+        core::str::from_utf8(&setup_code.to_bytes()).unwrap_or("")
+    );
+    rprintln!(
+        "Setup payload: {}",
+        core::str::from_utf8(&payload).unwrap_or("")
+    );
+
+    match QrCode::encode(&payload, EccLevel::Medium) {
+        Ok(code) => code.render_ascii(|line| rprintln!("{}", line)),
+        Err(e) => rprintln!("Failed to encode setup payload: {:?}", e),
+    }
+}
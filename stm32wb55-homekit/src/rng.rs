@@ -0,0 +1,67 @@
+//! Driver for the true random number generator
+//!
+//! The RNG is clocked by the HSI48 oscillator, which is selected as
+//! RNG clock after reset.
+
+use hal::device::{RCC, RNG};
+use homekit_ble::rand_core::{impls, CryptoRng, Error, RngCore};
+
+pub struct HardwareRng {
+    rng: RNG,
+}
+
+impl HardwareRng {
+    pub fn new(rng: RNG) -> Self {
+        // The HAL doesn't support the RNG, so the clocks are enabled directly
+        let rcc = unsafe { &*RCC::ptr() };
+
+        rcc.crrcr.modify(|_, w| w.hsi48on().set_bit());
+        while rcc.crrcr.read().hsi48rdy().bit_is_clear() {}
+
+        rcc.ahb3enr.modify(|_, w| w.rngen().set_bit());
+
+        rng.cr.modify(|_, w| w.rngen().set_bit());
+
+        HardwareRng { rng }
+    }
+
+    /// Read the next random word, waiting until it is available.
+    fn read(&mut self) -> u32 {
+        loop {
+            let status = self.rng.sr.read();
+
+            // After a seed error, the RNG has to be restarted
+            if status.seis().bit_is_set() {
+                self.rng.sr.modify(|_, w| w.seis().clear_bit());
+                self.rng.cr.modify(|_, w| w.rngen().clear_bit());
+                self.rng.cr.modify(|_, w| w.rngen().set_bit());
+                continue;
+            }
+
+            if status.drdy().bit_is_set() {
+                return self.rng.dr.read().bits();
+            }
+        }
+    }
+}
+
+impl RngCore for HardwareRng {
+    fn next_u32(&mut self) -> u32 {
+        self.read()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for HardwareRng {}
@@ -0,0 +1,220 @@
+//! Pairing store in the internal flash
+//!
+//! The state is kept in RAM, and written to a key-value store in the flash
+//! pages reserved in `memory.x` whenever it is modified. Modifications are
+//! written before the response to the controller is sent, so that they
+//! survive a reset during the connection. Flash accesses are coordinated with
+//! CPU2, see `cpu2`.
+
+use core::slice;
+
+use hal::flash::{FlashPage, Parts, WriteErase};
+use homekit_ble::{
+    identity::Identity,
+    kv::{Flash, KvStore, WRITE_SIZE},
+    pairing::{Pairing, Pairings},
+    store::{MemoryStore, PairingStore, MAX_ENCODED_LEN},
+    tlv::TlvWriter,
+    Error,
+};
+use rtt_target::rprintln;
+
+use crate::cpu2;
+
+/// Start of the flash
+const FLASH_START: usize = 0x0800_0000;
+
+/// Size of a flash page
+const PAGE_SIZE: usize = 4096;
+
+/// First page used for the store, which has to match the `STORE` region in `memory.x`
+const FIRST_PAGE: usize = 48;
+
+/// Number of pages in the `STORE` region
+const PAGE_COUNT: usize = 4;
+
+/// Key of the encoded `MemoryStore`
+const STATE_KEY: u16 = 1;
+
+/// Flash pages reserved for the store
+struct StoreFlash {
+    flash: Parts,
+}
+
+impl StoreFlash {
+    fn address(page: usize, offset: usize) -> usize {
+        FLASH_START + (FIRST_PAGE + page) * PAGE_SIZE + offset
+    }
+
+    fn unlock(&mut self) -> Result<hal::flash::UnlockedFlash<'_>, Error> {
+        self.flash
+            .keyr
+            .unlock_flash(&mut self.flash.sr, &mut self.flash.cr)
+            .map_err(|e| {
+                rprintln!("Failed to unlock flash: {:?}", e);
+                Error::Storage
+            })
+    }
+}
+
+impl Flash for StoreFlash {
+    const PAGE_SIZE: usize = PAGE_SIZE;
+    const PAGE_COUNT: usize = PAGE_COUNT;
+
+    fn read(&self, page: usize, offset: usize, buffer: &mut [u8]) -> Result<(), Error> {
+        // The pages are reserved in `memory.x`, and only written by the store
+        let data = unsafe {
+            slice::from_raw_parts(Self::address(page, offset) as *const u8, buffer.len())
+        };
+
+        buffer.copy_from_slice(data);
+
+        Ok(())
+    }
+
+    fn write(&mut self, page: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+        // Every double word is programmed separately, so that CPU2 is only
+        // stalled briefly, and can block the flash in between
+        for (i, chunk) in data.chunks(WRITE_SIZE).enumerate() {
+            let address = Self::address(page, offset + i * WRITE_SIZE);
+
+            cpu2::flash_operation(|| {
+                self.unlock()?.write(address, chunk).map_err(|e| {
+                    rprintln!("Failed to write flash: {:?}", e);
+                    Error::Storage
+                })
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), Error> {
+        cpu2::set_erase_activity(true)?;
+
+        let result = cpu2::flash_operation(|| {
+            self.unlock()?
+                .erase_page(FlashPage(FIRST_PAGE + page))
+                .map_err(|e| {
+                    rprintln!("Failed to erase flash page: {:?}", e);
+                    Error::Storage
+                })
+        });
+
+        cpu2::set_erase_activity(false)?;
+
+        result
+    }
+}
+
+/// Pairing store, which is written to flash
+pub struct FlashStore {
+    kv: KvStore<StoreFlash>,
+
+    state: MemoryStore,
+}
+
+impl FlashStore {
+    /// Load the stored state from flash.
+    ///
+    /// Fails if the stored state can't be read, instead of starting
+    /// without pairings and overwriting it with the next modification.
+    pub fn new(flash: Parts) -> Result<Self, Error> {
+        let kv = KvStore::new(StoreFlash { flash })?;
+
+        let mut buffer = [0u8; MAX_ENCODED_LEN];
+
+        let state = match kv.get(STATE_KEY, &mut buffer)? {
+            Some(data) => MemoryStore::decode(data)?,
+            None => {
+                rprintln!("Pairing store is empty");
+                MemoryStore::new()
+            }
+        };
+
+        Ok(FlashStore { kv, state })
+    }
+
+    /// Open the store without loading the stored state, which is
+    /// replaced by the next modification.
+    pub fn empty(flash: Parts) -> Result<Self, Error> {
+        let kv = KvStore::new(StoreFlash { flash })?;
+
+        Ok(FlashStore {
+            kv,
+            state: MemoryStore::new(),
+        })
+    }
+
+    /// Write the current state to flash.
+    fn write(&mut self) -> Result<(), Error> {
+        let mut buffer = [0u8; MAX_ENCODED_LEN];
+
+        let mut writer = TlvWriter::new(&mut buffer);
+        self.state.encode(&mut writer)?;
+
+        self.kv.set(STATE_KEY, writer.finish())
+    }
+}
+
+impl PairingStore for FlashStore {
+    fn pairings(&self) -> &Pairings {
+        self.state.pairings()
+    }
+
+    fn save_pairing(&mut self, pairing: Pairing) -> Result<(), Error> {
+        self.state.save_pairing(pairing)?;
+        self.write()
+    }
+
+    fn remove_pairing(&mut self, identifier: &[u8]) -> Result<Option<Pairing>, Error> {
+        let pairing = self.state.remove_pairing(identifier)?;
+
+        if pairing.is_some() {
+            self.write()?;
+        }
+
+        Ok(pairing)
+    }
+
+    fn remove_all_pairings(&mut self) -> Result<(), Error> {
+        self.state.remove_all_pairings()?;
+        self.write()
+    }
+
+    fn identity(&self) -> Option<&Identity> {
+        self.state.identity()
+    }
+
+    fn set_identity(&mut self, identity: &Identity) -> Result<(), Error> {
+        self.state.set_identity(identity)?;
+        self.write()
+    }
+
+    fn config_number(&self) -> u8 {
+        self.state.config_number()
+    }
+
+    fn set_config_number(&mut self, config_number: u8) -> Result<(), Error> {
+        self.state.set_config_number(config_number)?;
+        self.write()
+    }
+
+    fn global_state_number(&self) -> u16 {
+        self.state.global_state_number()
+    }
+
+    fn set_global_state_number(&mut self, gsn: u16) -> Result<(), Error> {
+        self.state.set_global_state_number(gsn)?;
+        self.write()
+    }
+
+    fn failed_setup_attempts(&self) -> u8 {
+        self.state.failed_setup_attempts()
+    }
+
+    fn set_failed_setup_attempts(&mut self, attempts: u8) -> Result<(), Error> {
+        self.state.set_failed_setup_attempts(attempts)?;
+        self.write()
+    }
+}